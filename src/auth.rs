@@ -0,0 +1,184 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::fs;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::time::Duration;
+
+use crate::error;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Environment variable holding the pre-shared key
+pub const PSK_ENV_VAR: &str = "PERFY_PSK";
+
+/// Size of the nonce issued by the Server in bytes
+const NONCE_SIZE: usize = 32;
+
+/// Size of the HMAC-SHA256 tag sent by the Client in bytes
+const TAG_SIZE: usize = 32;
+
+/// How long the Server waits for a Client to authenticate
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Secret shared by the Client and Server
+pub struct Psk {
+    key: Vec<u8>,
+}
+
+impl Psk {
+    pub fn new(key: &[u8]) -> error::Result<Psk> {
+        if key.is_empty() {
+            return Err(error::Error::new("Pre-shared key is empty"));
+        }
+        Ok(Psk { key: key.to_vec() })
+    }
+
+    /// Read the key from a file, ignoring trailing whitespace
+    pub fn from_file(path: &Path) -> error::Result<Psk> {
+        let key = fs::read(path).map_err(|e| {
+            error::Error::new(&format!(
+                "Failed reading pre-shared key from {}: {}",
+                path.display(),
+                e
+            ))
+        })?;
+        Psk::new(key.trim_ascii_end())
+    }
+
+    /// Read the key from `PERFY_PSK` if it is set
+    pub fn from_env() -> error::Result<Option<Psk>> {
+        match std::env::var_os(PSK_ENV_VAR) {
+            Some(key) => Psk::new(key.as_encoded_bytes()).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Key from `path` if given, otherwise from the environment
+    pub fn load(path: Option<&Path>) -> error::Result<Option<Psk>> {
+        match path {
+            Some(path) => Psk::from_file(path).map(Some),
+            None => Psk::from_env(),
+        }
+    }
+
+    fn mac(&self, nonce: &[u8]) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.key).expect("HMAC accepts any key size");
+        mac.update(nonce);
+        mac
+    }
+
+    fn sign(&self, nonce: &[u8]) -> [u8; TAG_SIZE] {
+        self.mac(nonce).finalize().into_bytes().into()
+    }
+
+    fn verify(&self, nonce: &[u8], tag: &[u8]) -> bool {
+        self.mac(nonce).verify_slice(tag).is_ok()
+    }
+}
+
+/// Server side of the control channel handshake.
+///
+/// The Server sends a one byte flag saying whether authentication is
+/// required followed by a random nonce. When it is, the Client must answer
+/// with HMAC-SHA256(psk, nonce) and is rejected otherwise.
+pub fn server_handshake<S>(stream: &mut S, psk: Option<&Psk>) -> error::Result<()>
+where
+    S: Read + Write,
+{
+    let nonce: [u8; NONCE_SIZE] = rand::random();
+    let mut hello = [0; 1 + NONCE_SIZE];
+    hello[0] = psk.is_some() as u8;
+    hello[1..].copy_from_slice(&nonce);
+    stream.write_all(&hello)?;
+
+    let Some(psk) = psk else {
+        return Ok(());
+    };
+    let mut tag = [0; TAG_SIZE];
+    stream.read_exact(&mut tag)?;
+    if psk.verify(&nonce, &tag) {
+        stream.write_all("OK".as_bytes())?;
+        Ok(())
+    } else {
+        stream.write_all("NO".as_bytes())?;
+        Err(error::Error::new("Client failed authentication"))
+    }
+}
+
+/// Client side of the control channel handshake, see [`server_handshake`]
+pub fn client_handshake<S>(stream: &mut S, psk: Option<&Psk>) -> error::Result<()>
+where
+    S: Read + Write,
+{
+    let mut hello = [0; 1 + NONCE_SIZE];
+    stream.read_exact(&mut hello)?;
+    match hello[0] {
+        0 => Ok(()),
+        1 => {
+            let Some(psk) = psk else {
+                return Err(error::Error::new(&format!(
+                    "Server requires authentication, set a pre-shared key with --psk-file or {}",
+                    PSK_ENV_VAR
+                )));
+            };
+            stream.write_all(&psk.sign(&hello[1..]))?;
+            let mut buf = [0; 2];
+            stream.read_exact(&mut buf)?;
+            if buf == "OK".as_bytes() {
+                Ok(())
+            } else {
+                Err(error::Error::new("Server rejected pre-shared key"))
+            }
+        }
+        _ => Err(error::Error::new("Received invalid handshake from server")),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::os::unix::net::UnixStream;
+    use std::thread;
+
+    use super::*;
+
+    fn handshake(server_psk: Option<&'static str>, client_psk: Option<&'static str>) -> bool {
+        let (mut server, mut client) = UnixStream::pair().unwrap();
+        let server_thread = thread::spawn(move || {
+            let psk = server_psk.map(|k| Psk::new(k.as_bytes()).unwrap());
+            server_handshake(&mut server, psk.as_ref()).is_ok()
+        });
+        let psk = client_psk.map(|k| Psk::new(k.as_bytes()).unwrap());
+        let client_ok = client_handshake(&mut client, psk.as_ref()).is_ok();
+        // close our end so the server never blocks on a missing tag
+        drop(client);
+        let server_ok = server_thread.join().unwrap();
+        client_ok && server_ok
+    }
+
+    #[test]
+    fn test_handshake_without_psk() {
+        assert!(handshake(None, None));
+        assert!(handshake(None, Some("secret")));
+    }
+
+    #[test]
+    fn test_handshake_with_matching_psk() {
+        assert!(handshake(Some("secret"), Some("secret")));
+    }
+
+    #[test]
+    fn test_handshake_with_wrong_psk() {
+        assert!(!handshake(Some("secret"), Some("guess")));
+    }
+
+    #[test]
+    fn test_handshake_with_missing_psk() {
+        assert!(!handshake(Some("secret"), None));
+    }
+
+    #[test]
+    fn test_empty_psk() {
+        assert!(Psk::new(b"").is_err());
+    }
+}
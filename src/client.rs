@@ -1,69 +1,278 @@
-use std::io::{Read, Write};
-use std::net::TcpStream;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::path::PathBuf;
 use std::sync::mpsc;
 use std::thread;
+use std::time::Duration;
 
+use crate::auth::{self, Psk};
+use crate::compare::{self, Thresholds};
+use crate::csv::CsvWriter;
 use crate::error;
-use crate::netexp::{NetExp, NetExpParams, Side};
+use crate::netexp::{Marking, NetExp, NetExpParams, RunOptions, Side, Stats, Summary};
+use crate::units::Format;
+
+#[derive(Default)]
+pub struct ClientConfig {
+    /// Key used to authenticate with Servers that require one
+    pub psk: Option<Psk>,
+    pub run_options: RunOptions,
+    /// Also write interval and summary results to this CSV file
+    pub csv: Option<PathBuf>,
+    /// Also save the results as JSON, for `perfy compare`
+    pub json: Option<PathBuf>,
+    /// Results saved with `json` to compare this run against
+    pub baseline: Option<PathBuf>,
+    /// How far from `baseline` results may be before they count as regressed
+    pub thresholds: Thresholds,
+    /// Time to wait between repeated runs
+    pub pause: Duration,
+    /// Core the Server pins its side of the NetExp to
+    pub server_cpu: Option<u16>,
+}
+
+/// Results of one run of a NetExp
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RunResult {
+    pub sender: Stats,
+    pub receiver: Stats,
+}
 
 /// The Client connects to the Server, sends the NetExp to run,
 /// and runs the NetExp when both the Client and Server are ready.
-pub fn run(net_exp: NetExp) -> error::Result<()> {
-    let client_params = match &net_exp {
-        NetExp::Tcp(params) => params,
-        NetExp::Udp(params) => params,
-    };
+/// The Client opens every data connection, in reverse tests too, except
+/// for Unix sockets and multicast where the receiving side binds.
+/// Repeated runs reuse the control connection, the Client starts each
+/// one by sending "GO".
+pub fn run(net_exp: NetExp, config: &ClientConfig) -> error::Result<Vec<RunResult>> {
+    let client_params = net_exp.params().clone();
     let server_params = NetExpParams {
-        host: client_params.host,
-        port: client_params.port,
         side: match client_params.side {
             Side::Rx => Side::Tx,
             Side::Tx => Side::Rx,
         },
-        parallel: client_params.parallel,
-        duration: client_params.duration,
-    };
-    let server_net_exp = match net_exp {
-        NetExp::Tcp(_) => NetExp::Tcp(server_params),
-        NetExp::Udp(_) => NetExp::Udp(server_params),
+        cpu: config.server_cpu,
+        ..client_params.clone()
     };
+    let server_net_exp = net_exp.with_params(server_params);
 
-    let mut stream = TcpStream::connect(format!("{}:{}", client_params.host, client_params.port))?;
+    let mut stream = config.run_options.local.tcp_connect(
+        SocketAddr::new(client_params.host, client_params.port),
+        &Marking::default(),
+    )?;
+    auth::client_handshake(&mut stream, config.psk.as_ref())?;
 
-    let mut buf = [0; 2];
-    match client_params.side {
-        Side::Tx => {
-            // Send NetExp to Server then wait for Server to say that it's ready
-            stream.write_all(&server_net_exp.serialize())?;
-            stream.read_exact(&mut buf)?;
-            if buf == "OK".as_bytes() {
-                net_exp.run(|| {});
-                Ok(())
+    let format = &config.run_options.format;
+    let mut results = Vec::new();
+    for run in 0..client_params.repeat {
+        if run > 0 {
+            thread::sleep(config.pause);
+            println!("Run {} of {}", run + 1, client_params.repeat);
+        }
+        // the NetExp itself starts the first run
+        let start_run = |stream: &mut TcpStream| {
+            if run == 0 {
+                server_net_exp.write_to(stream)
             } else {
-                Err(error::Error::new("Received invalid response from server"))
+                Ok(stream.write_all("GO".as_bytes())?)
             }
-        }
-        Side::Rx => {
-            // Set up listener before sending NetExp to Server
+        };
+
+        let local = if net_exp.accepts(&config.run_options) {
+            // Set up listener before starting the Server
             let (ready_tx, ready_rx) = mpsc::channel::<()>();
+            let run_options = config.run_options.clone();
+            let net_exp = net_exp.clone();
             let exp_thread = thread::spawn(move || {
-                net_exp.run(|| {
+                net_exp.run(&run_options, || {
                     ready_tx.send(()).unwrap();
                 })
             });
-            let Ok(_) = ready_rx.recv_timeout(std::time::Duration::new(5, 0)) else {
-                return Err(error::Error::new("Timed out initializing test"));
-            };
-            // Listener is ready, send NetExp to Server
-            stream.write_all(&server_net_exp.serialize())?;
-            stream.read_exact(&mut buf)?;
-            if buf != "OK".as_bytes() {
-                return Err(error::Error::new("Received invalid response from server"));
+            match ready_rx.recv_timeout(Duration::new(5, 0)) {
+                Ok(_) => {}
+                // the NetExp failed before it was ready
+                Err(mpsc::RecvTimeoutError::Disconnected) => {
+                    return match exp_thread.join() {
+                        Ok(Err(e)) => Err(e),
+                        _ => Err(error::Error::new("Failed joining thread")),
+                    };
+                }
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    return Err(error::Error::new("Timed out initializing test"));
+                }
             }
+            // Listener is ready, start the Server
+            start_run(&mut stream)?;
+            wait_for_ok(&mut stream)?;
             match exp_thread.join() {
-                Err(_) => Err(error::Error::new("Failed joining thread")),
-                Ok(_) => Ok(()),
+                Err(_) => return Err(error::Error::new("Failed joining thread")),
+                Ok(stats) => stats?,
             }
+        } else {
+            // Start the Server then wait for it to listen, whichever side
+            // sends the data
+            start_run(&mut stream)?;
+            wait_for_ok(&mut stream)?;
+            net_exp.run(&config.run_options, || {})?
+        };
+
+        let remote = Stats::read_from(&mut stream)
+            .map_err(|e| error::Error::new(&format!("Server did not send results: {}", e)))?;
+        let (sender, mut receiver) = match client_params.side {
+            Side::Tx => (local, remote),
+            Side::Rx => (remote, local),
+        };
+        // only the Client knows how much the other side sent
+        if let (Some(integrity), Some((sent, _)), Some((received, _))) =
+            (&mut receiver.integrity, sender.transfer, receiver.transfer)
+            && integrity.missing_datagrams.is_none()
+        {
+            integrity.missing_bytes = Some(sent.saturating_sub(received));
         }
+        println!("Sender:\n{}", sender.format(format));
+        println!("Receiver:\n{}", receiver.format(format));
+        if let Some(warning) = check_marking(client_params.marking.tos, &receiver) {
+            println!("{}", warning);
+        }
+        results.push(RunResult { sender, receiver });
+    }
+
+    if results.len() > 1 {
+        println!("{}", format_runs(&results, format));
+    }
+    if let Some(path) = &config.csv {
+        let mut csv = CsvWriter::new(BufWriter::new(File::create(path)?))?;
+        for (i, result) in results.iter().enumerate() {
+            let run = i as u16 + 1;
+            csv.write(run, "sender", &result.sender)?;
+            csv.write(run, "receiver", &result.receiver)?;
+        }
+    }
+    if let Some(path) = &config.json {
+        compare::save(path, &results)?;
+    }
+    Ok(results)
+}
+
+/// Wait for the Server to say its side of the run is ready
+fn wait_for_ok(stream: &mut TcpStream) -> error::Result<()> {
+    let mut buf = [0; 2];
+    // the Server closes the connection if its side fails to start
+    stream
+        .read_exact(&mut buf)
+        .map_err(|_| error::Error::new("Server failed to start the test"))?;
+    if buf != "OK".as_bytes() {
+        return Err(error::Error::new("Received invalid response from server"));
+    }
+    Ok(())
+}
+
+/// Join the multicast group of a UDP NetExp and report what arrives from
+/// a sender run by another Client, without talking to a Server
+pub fn join(net_exp: NetExp, config: &ClientConfig) -> error::Result<Stats> {
+    let stats = net_exp.run(&config.run_options, || {})?;
+    println!("Receiver:\n{}", stats.format(&config.run_options.format));
+    if let Some(path) = &config.csv {
+        let mut csv = CsvWriter::new(BufWriter::new(File::create(path)?))?;
+        csv.write(1, "receiver", &stats)?;
+    }
+    Ok(stats)
+}
+
+/// Warn if datagrams arrived with a DSCP other than the one they were
+/// sent with, the ECN bits may change on the way and are not compared
+fn check_marking(tos: u8, receiver: &Stats) -> Option<String> {
+    let received_tos = receiver.received_tos.as_ref()?;
+    if tos == 0 {
+        return None;
+    }
+    let total: u64 = received_tos.values().sum();
+    let remarked: u64 = received_tos
+        .iter()
+        .filter(|(received, _)| *received >> 2 != tos >> 2)
+        .map(|(_, n)| n)
+        .sum();
+    (remarked > 0).then(|| {
+        format!(
+            "Warning: {} of {} datagrams arrived without DSCP {}",
+            remarked,
+            total,
+            tos >> 2
+        )
+    })
+}
+
+/// Mean packet loss of datagram runs, or the total retransmits of stream
+/// runs
+pub fn format_loss(runs: &[RunResult]) -> Option<String> {
+    let loss: Vec<f64> = runs.iter().filter_map(|r| r.receiver.packet_loss).collect();
+    if let Some(loss) = Summary::of(&loss) {
+        return Some(format!("{:.3}%", loss.mean));
+    }
+    let retransmits: Option<u64> = runs.iter().map(|r| r.sender.retransmits).sum();
+    retransmits.map(|retransmits| format!("{} retransmits", retransmits))
+}
+
+/// Mean jitter of datagram runs, or the mean round trip time of runs that
+/// measure it
+pub fn format_latency(runs: &[RunResult]) -> Option<String> {
+    let mean = |f: fn(&RunResult) -> Option<Duration>| {
+        let samples: Vec<f64> = runs.iter().filter_map(f).map(|d| d.as_secs_f64()).collect();
+        Summary::of(&samples).map(|summary| summary.mean * 1_000f64)
+    };
+    if let Some(jitter) = mean(|r| r.receiver.jitter) {
+        return Some(format!("{:.3} ms jitter", jitter));
+    }
+    mean(|r| r.sender.rtt).map(|rtt| format!("{:.3} ms RTT", rtt))
+}
+
+/// Receiver bandwidth of each run, then its mean and 95% confidence interval
+fn format_runs(results: &[RunResult], format: &Format) -> String {
+    let rates: Vec<f64> = results.iter().filter_map(|r| r.receiver.rate()).collect();
+    let mut lines = vec![format!("Summary of {} runs:", results.len())];
+    for (i, result) in results.iter().enumerate() {
+        lines.push(format!(
+            "Run {:>3}: {}",
+            i + 1,
+            result
+                .receiver
+                .rate()
+                .map(|rate| format.rate(rate))
+                .unwrap_or("n/a".to_string())
+        ));
+    }
+    if let Some(summary) = Summary::of(&rates) {
+        lines.push(match summary.ci95() {
+            Some(ci) => format!(
+                "Bandwidth: mean {} ± {} (95% CI)",
+                format.rate(summary.mean),
+                format.rate(ci)
+            ),
+            None => format!("Bandwidth: mean {}", format.rate(summary.mean)),
+        });
+    }
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use super::*;
+
+    #[test]
+    fn test_check_marking() {
+        let receiver = Stats {
+            received_tos: Some(BTreeMap::from([(0xb8, 90), (0xb9, 5), (0x00, 5)])),
+            ..Default::default()
+        };
+        assert_eq!(
+            check_marking(0xb8, &receiver).as_deref(),
+            Some("Warning: 5 of 100 datagrams arrived without DSCP 46")
+        );
+        assert_eq!(check_marking(0, &receiver), None);
+        assert_eq!(check_marking(0xb8, &Stats::default()), None);
     }
 }
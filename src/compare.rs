@@ -0,0 +1,239 @@
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::Path;
+
+use crate::client::RunResult;
+use crate::error;
+use crate::units::Format;
+
+/// Results as saved with `--json`
+#[derive(Serialize, Deserialize)]
+struct Saved {
+    runs: Vec<RunResult>,
+}
+
+/// Save the results of every run to `path` as JSON
+pub fn save(path: &Path, runs: &[RunResult]) -> error::Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    serde_json::to_writer_pretty(
+        &mut writer,
+        &Saved {
+            runs: runs.to_vec(),
+        },
+    )?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Load results saved with [`save`]
+pub fn load(path: &Path) -> error::Result<Vec<RunResult>> {
+    let file = File::open(path)
+        .map_err(|e| error::Error::new(&format!("Failed opening {}: {}", path.display(), e)))?;
+    let saved: Saved = serde_json::from_reader(BufReader::new(file))?;
+    Ok(saved.runs)
+}
+
+/// How much worse than the baseline a result may be before it counts as a
+/// regression
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Thresholds {
+    /// Percent drop in receiver bandwidth
+    pub throughput_drop: f64,
+    /// Percentage point rise in packet loss
+    pub loss_increase: f64,
+    /// Percent rise in jitter or round trip time
+    pub latency_increase: f64,
+}
+
+impl Default for Thresholds {
+    fn default() -> Self {
+        Self {
+            throughput_drop: 5f64,
+            loss_increase: 1f64,
+            latency_increase: 10f64,
+        }
+    }
+}
+
+/// What a metric measures, which decides how it is printed and judged
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Kind {
+    /// Bytes per second, higher is better
+    Throughput,
+    /// Percent, lower is better
+    Loss,
+    /// Seconds, lower is better
+    Latency,
+    /// A count, informational only
+    Count,
+}
+
+/// One metric of the baseline and current results
+#[derive(Clone, Debug, PartialEq)]
+pub struct Delta {
+    pub name: &'static str,
+    kind: Kind,
+    pub baseline: f64,
+    pub current: f64,
+    pub regressed: bool,
+}
+
+impl Delta {
+    fn new(
+        name: &'static str,
+        kind: Kind,
+        baseline: f64,
+        current: f64,
+        thresholds: &Thresholds,
+    ) -> Delta {
+        let percent = percent_change(baseline, current);
+        let regressed = match kind {
+            Kind::Throughput => percent.is_some_and(|p| p < -thresholds.throughput_drop),
+            Kind::Loss => current - baseline > thresholds.loss_increase,
+            Kind::Latency => percent.is_some_and(|p| p > thresholds.latency_increase),
+            Kind::Count => false,
+        };
+        Delta {
+            name,
+            kind,
+            baseline,
+            current,
+            regressed,
+        }
+    }
+
+    pub fn format(&self, format: &Format) -> String {
+        let value = |v: f64| match self.kind {
+            Kind::Throughput => format.rate(v),
+            Kind::Loss => format!("{:.3}%", v),
+            Kind::Latency => format!("{:.3} ms", v * 1_000f64),
+            Kind::Count => format!("{:.0}", v),
+        };
+        let change = match (self.kind, percent_change(self.baseline, self.current)) {
+            (Kind::Loss, _) => format!("{:+.3} pts", self.current - self.baseline),
+            (_, Some(percent)) => format!("{:+.2}%", percent),
+            (_, None) => "n/a".to_string(),
+        };
+        format!(
+            "{:<12} {:>16} -> {:>16}  {:>12}{}",
+            self.name,
+            value(self.baseline),
+            value(self.current),
+            change,
+            if self.regressed { "  REGRESSION" } else { "" },
+        )
+    }
+}
+
+fn percent_change(baseline: f64, current: f64) -> Option<f64> {
+    (baseline != 0f64).then(|| (current - baseline) * 100f64 / baseline)
+}
+
+/// Reads one metric from a run, if the run has it
+type Metric = fn(&RunResult) -> Option<f64>;
+
+/// Mean of a metric over every run that reports it
+fn mean(runs: &[RunResult], f: Metric) -> Option<f64> {
+    let values: Vec<f64> = runs.iter().filter_map(f).collect();
+    (!values.is_empty()).then(|| values.iter().sum::<f64>() / values.len() as f64)
+}
+
+/// Compare the mean of each metric over the baseline and current runs,
+/// metrics missing from either are left out
+pub fn compare(
+    baseline: &[RunResult],
+    current: &[RunResult],
+    thresholds: &Thresholds,
+) -> Vec<Delta> {
+    let metrics: [(&'static str, Kind, Metric); 5] = [
+        ("Bandwidth", Kind::Throughput, |r| r.receiver.rate()),
+        ("Packet loss", Kind::Loss, |r| r.receiver.packet_loss),
+        ("Jitter", Kind::Latency, |r| {
+            r.receiver.jitter.map(|d| d.as_secs_f64())
+        }),
+        ("RTT", Kind::Latency, |r| {
+            r.sender.rtt.map(|d| d.as_secs_f64())
+        }),
+        ("Retransmits", Kind::Count, |r| {
+            r.sender.retransmits.map(|n| n as f64)
+        }),
+    ];
+    metrics
+        .into_iter()
+        .filter_map(|(name, kind, f)| {
+            let baseline = mean(baseline, f)?;
+            let current = mean(current, f)?;
+            Some(Delta::new(name, kind, baseline, current, thresholds))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::netexp::Stats;
+
+    fn result(bytes: u64, loss: Option<f64>, rtt_ms: u64) -> RunResult {
+        RunResult {
+            sender: Stats {
+                rtt: Some(Duration::from_millis(rtt_ms)),
+                ..Default::default()
+            },
+            receiver: Stats {
+                transfer: Some((bytes, Duration::from_secs(1))),
+                packet_loss: loss,
+                ..Default::default()
+            },
+        }
+    }
+
+    #[test]
+    fn test_no_regression_within_thresholds() {
+        let baseline = [result(1_000, Some(0.5), 10), result(1_200, Some(0.5), 10)];
+        let current = [result(1_080, Some(1.2), 11)];
+        let deltas = compare(&baseline, &current, &Thresholds::default());
+        let names: Vec<&str> = deltas.iter().map(|d| d.name).collect();
+        assert_eq!(names, ["Bandwidth", "Packet loss", "RTT"]);
+        assert_eq!(deltas[0].baseline, 1_100f64);
+        assert!(deltas.iter().all(|d| !d.regressed));
+    }
+
+    #[test]
+    fn test_regressions_beyond_thresholds() {
+        let baseline = [result(1_000, Some(0.0), 10)];
+        let current = [result(900, Some(2.0), 12)];
+        let deltas = compare(&baseline, &current, &Thresholds::default());
+        assert!(deltas.iter().all(|d| d.regressed));
+
+        let lenient = Thresholds {
+            throughput_drop: 20f64,
+            loss_increase: 5f64,
+            latency_increase: 50f64,
+        };
+        let deltas = compare(&baseline, &current, &lenient);
+        assert!(deltas.iter().all(|d| !d.regressed));
+    }
+
+    #[test]
+    fn test_metrics_missing_from_either_side_are_skipped() {
+        let baseline = [result(1_000, None, 10)];
+        let current = [result(1_000, Some(1.0), 10)];
+        let deltas = compare(&baseline, &current, &Thresholds::default());
+        assert!(deltas.iter().all(|d| d.name != "Packet loss"));
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let path = std::env::temp_dir().join(format!("perfy-compare-{}.json", std::process::id()));
+        let runs = vec![result(1_000, Some(0.5), 10)];
+        save(&path, &runs).unwrap();
+        let loaded = load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(loaded[0].receiver.transfer, runs[0].receiver.transfer);
+        assert_eq!(loaded[0].sender.rtt, runs[0].sender.rtt);
+    }
+}
@@ -0,0 +1,204 @@
+use std::io::Write;
+use std::time::{Duration, UNIX_EPOCH};
+
+use crate::error;
+use crate::netexp::{Interval, Stats};
+
+/// Column names, written once at the top of the file
+pub const HEADER: &str = "timestamp,run,side,stream,start,end,bytes,bits_per_second,packets,lost,loss_percent,jitter_ms,retransmits";
+
+/// Writes [`Stats`] as CSV, one row per interval per stream followed by a
+/// summary row whose stream column is "sum"
+pub struct CsvWriter<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> CsvWriter<W> {
+    pub fn new(mut writer: W) -> error::Result<CsvWriter<W>> {
+        writeln!(writer, "{}", HEADER)?;
+        Ok(CsvWriter { writer })
+    }
+
+    /// Write the rows for one side of a run, `side` is "sender" or "receiver"
+    pub fn write(&mut self, run: u16, side: &str, stats: &Stats) -> error::Result<()> {
+        for interval in &stats.intervals {
+            let row = Row {
+                end: stats.started.map(|started| started + interval.end),
+                ..Row::from(interval)
+            };
+            self.write_row(run, side, &row)?;
+        }
+
+        let (bytes, duration) = stats.transfer.unwrap_or_default();
+        let sum = |f: fn(&Interval) -> Option<u64>| {
+            stats.intervals.iter().filter_map(f).reduce(|a, b| a + b)
+        };
+        // intervals left out with --omit do not count towards the summary
+        let start = stats
+            .intervals
+            .iter()
+            .map(|i| i.start)
+            .min()
+            .unwrap_or_default();
+        let summary = Row {
+            end: stats.started.map(|started| started + start + duration),
+            stream: "sum".to_string(),
+            start,
+            duration,
+            bytes,
+            rate: stats.rate(),
+            packets: sum(|i| i.packets),
+            lost: sum(|i| i.lost),
+            loss: stats.packet_loss,
+            jitter: stats.jitter,
+            retransmits: stats.retransmits,
+        };
+        self.write_row(run, side, &summary)?;
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    fn write_row(&mut self, run: u16, side: &str, row: &Row) -> error::Result<()> {
+        let timestamp = row
+            .end
+            .and_then(|end| end.duration_since(UNIX_EPOCH).ok())
+            .map(|t| format!("{:.3}", t.as_secs_f64()));
+        writeln!(
+            self.writer,
+            "{},{},{},{},{:.3},{:.3},{},{},{},{},{},{},{}",
+            timestamp.unwrap_or_default(),
+            run,
+            side,
+            row.stream,
+            row.start.as_secs_f64(),
+            (row.start + row.duration).as_secs_f64(),
+            row.bytes,
+            field(row.rate.map(|rate| format!("{:.0}", rate * 8f64))),
+            field(row.packets),
+            field(row.lost),
+            field(row.loss.map(|loss| format!("{:.3}", loss))),
+            field(
+                row.jitter
+                    .map(|jitter| format!("{:.3}", jitter.as_secs_f64() * 1_000f64))
+            ),
+            field(row.retransmits),
+        )?;
+        Ok(())
+    }
+}
+
+/// Values of one CSV line
+struct Row {
+    /// Wall clock time at the end of the row's period
+    end: Option<std::time::SystemTime>,
+    stream: String,
+    start: Duration,
+    duration: Duration,
+    bytes: u64,
+    /// Bytes per second
+    rate: Option<f64>,
+    packets: Option<u64>,
+    lost: Option<u64>,
+    loss: Option<f64>,
+    jitter: Option<Duration>,
+    retransmits: Option<u64>,
+}
+
+impl From<&Interval> for Row {
+    fn from(interval: &Interval) -> Row {
+        Row {
+            end: None,
+            stream: interval.stream.to_string(),
+            start: interval.start,
+            duration: interval.end - interval.start,
+            bytes: interval.bytes,
+            rate: interval.rate(),
+            packets: interval.packets,
+            lost: interval.lost,
+            loss: interval.loss(),
+            jitter: interval.jitter,
+            retransmits: interval.retransmits,
+        }
+    }
+}
+
+/// Missing values are left empty so spreadsheets treat them as blank
+fn field<T: ToString>(value: Option<T>) -> String {
+    value.map(|v| v.to_string()).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_interval_and_summary_rows() {
+        let stats = Stats {
+            started: Some(UNIX_EPOCH + Duration::from_secs(100)),
+            transfer: Some((3_000, Duration::from_secs(2))),
+            packet_loss: Some(25f64),
+            jitter: Some(Duration::from_micros(1_500)),
+            intervals: vec![
+                Interval {
+                    stream: 0,
+                    end: Duration::from_secs(1),
+                    bytes: 1_000,
+                    packets: Some(1),
+                    lost: Some(1),
+                    jitter: Some(Duration::from_millis(1)),
+                    ..Default::default()
+                },
+                Interval {
+                    stream: 0,
+                    start: Duration::from_secs(1),
+                    end: Duration::from_secs(2),
+                    bytes: 2_000,
+                    packets: Some(2),
+                    lost: Some(0),
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        };
+
+        let mut buf = Vec::new();
+        CsvWriter::new(&mut buf)
+            .unwrap()
+            .write(1, "receiver", &stats)
+            .unwrap();
+        let csv = String::from_utf8(buf).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines[0], HEADER);
+        assert_eq!(
+            lines[1],
+            "101.000,1,receiver,0,0.000,1.000,1000,8000,1,1,50.000,1.000,"
+        );
+        assert_eq!(
+            lines[2],
+            "102.000,1,receiver,0,1.000,2.000,2000,16000,2,0,0.000,,"
+        );
+        assert_eq!(
+            lines[3],
+            "102.000,1,receiver,sum,0.000,2.000,3000,12000,3,1,25.000,1.500,"
+        );
+        assert_eq!(lines.len(), 4);
+    }
+
+    #[test]
+    fn test_missing_start_time_leaves_timestamp_empty() {
+        let stats = Stats {
+            transfer: Some((0, Duration::ZERO)),
+            ..Default::default()
+        };
+        let mut buf = Vec::new();
+        CsvWriter::new(&mut buf)
+            .unwrap()
+            .write(2, "sender", &stats)
+            .unwrap();
+        let csv = String::from_utf8(buf).unwrap();
+        assert_eq!(
+            csv.lines().nth(1),
+            Some(",2,sender,sum,0.000,0.000,0,,,,,,")
+        );
+    }
+}
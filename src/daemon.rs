@@ -0,0 +1,239 @@
+use std::env;
+use std::ffi::OsStr;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::error;
+
+/// Set by SIGTERM, the Server stops once the current session is over
+static STOP: AtomicBool = AtomicBool::new(false);
+/// Set by SIGHUP, the Server reloads its configuration between sessions
+static RELOAD: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_signal(signal: libc::c_int) {
+    match signal {
+        libc::SIGTERM => STOP.store(true, Ordering::SeqCst),
+        libc::SIGHUP => RELOAD.store(true, Ordering::SeqCst),
+        _ => {}
+    }
+}
+
+/// Have SIGTERM stop the Server and SIGHUP reload it, in place of killing
+/// the process
+pub fn handle_signals() -> error::Result<()> {
+    for signal in [libc::SIGTERM, libc::SIGHUP] {
+        // SAFETY: sigaction is plain data, all zeroes is a valid value
+        let mut action: libc::sigaction = unsafe { std::mem::zeroed() };
+        action.sa_sigaction = on_signal as *const () as libc::sighandler_t;
+        action.sa_flags = libc::SA_RESTART;
+        // SAFETY: the handler only stores to atomics, which is async-signal-safe
+        if unsafe { libc::sigaction(signal, &action, std::ptr::null_mut()) } != 0 {
+            return Err(error::Error::new(&format!(
+                "Failed installing signal handler: {}",
+                io::Error::last_os_error()
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Whether SIGTERM has asked the Server to stop
+pub fn stopping() -> bool {
+    STOP.load(Ordering::SeqCst)
+}
+
+/// Whether SIGHUP has asked the Server to reload since the last call
+pub fn take_reload() -> bool {
+    RELOAD.swap(false, Ordering::SeqCst)
+}
+
+/// Tell systemd about a change of state such as "READY=1", if it started
+/// this process as a Type=notify service
+pub fn notify(state: &str) {
+    let Some(path) = env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    let sent = UnixDatagram::unbound().and_then(|socket| send_notify(&socket, &path, state));
+    if let Err(e) = sent {
+        eprintln!("Failed notifying systemd: {}", e);
+    }
+}
+
+/// Send `state` to the socket at `path`, names starting with '@' are in the
+/// abstract namespace
+#[cfg(target_os = "linux")]
+fn send_notify(socket: &UnixDatagram, path: &OsStr, state: &str) -> io::Result<usize> {
+    use std::os::linux::net::SocketAddrExt;
+    match path.as_encoded_bytes().strip_prefix(b"@") {
+        Some(name) => socket.send_to_addr(state.as_bytes(), &SocketAddr::from_abstract_name(name)?),
+        None => socket.send_to(state.as_bytes(), path),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn send_notify(socket: &UnixDatagram, path: &OsStr, state: &str) -> io::Result<usize> {
+    socket.send_to(state.as_bytes(), path)
+}
+
+/// The process started by the user, which waits for the daemon to be
+/// set up before exiting so that its exit status means something
+pub struct Detached {
+    ready: OwnedFd,
+}
+
+impl Detached {
+    /// Let the original process exit successfully
+    pub fn ready(self) {
+        let mut pipe = File::from(self.ready);
+        let _ = pipe.write_all(&[1]);
+    }
+}
+
+/// Carry on in the background, detached from the terminal, with stdout and
+/// stderr appended to `log` or discarded. Must be called before any
+/// threads are started.
+pub fn daemonize(log: Option<&Path>) -> error::Result<Detached> {
+    let failed =
+        |what: &str| error::Error::new(&format!("Failed {}: {}", what, io::Error::last_os_error()));
+    let mut fds = [0; 2];
+    // SAFETY: fds has room for both ends of the pipe
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+        return Err(failed("creating pipe"));
+    }
+    // SAFETY: pipe returned two open descriptors that nothing else owns
+    let (read, write) = unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+
+    // SAFETY: the process is single threaded
+    match unsafe { libc::fork() } {
+        -1 => return Err(failed("forking")),
+        0 => {}
+        _ => {
+            // exit once the daemon is ready, or fail if it exits first
+            drop(write);
+            let mut buf = [0; 1];
+            let ready = File::from(read).read(&mut buf).unwrap_or(0);
+            process::exit(if ready == 1 { 0 } else { 1 });
+        }
+    }
+    drop(read);
+    // SAFETY: the child is not a process group leader
+    if unsafe { libc::setsid() } == -1 {
+        return Err(failed("starting session"));
+    }
+    // fork again so the daemon can never acquire a controlling terminal
+    // SAFETY: the process is still single threaded
+    match unsafe { libc::fork() } {
+        -1 => return Err(failed("forking")),
+        0 => {}
+        _ => process::exit(0),
+    }
+
+    let null = File::open("/dev/null")?;
+    // SAFETY: both descriptors are open
+    unsafe { libc::dup2(null.as_raw_fd(), libc::STDIN_FILENO) };
+    redirect_output(log)?;
+    Ok(Detached { ready: write })
+}
+
+/// Point stdout and stderr at `log`, or at /dev/null, reopening the file so
+/// that it can be rotated
+pub fn redirect_output(log: Option<&Path>) -> error::Result<()> {
+    let file = match log {
+        Some(log) => OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(log)
+            .map_err(|e| error::Error::new(&format!("Failed opening {}: {}", log.display(), e)))?,
+        None => OpenOptions::new().write(true).open("/dev/null")?,
+    };
+    for fd in [libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+        // SAFETY: both descriptors are open
+        if unsafe { libc::dup2(file.as_raw_fd(), fd) } == -1 {
+            return Err(error::Error::new(&format!(
+                "Failed redirecting output: {}",
+                io::Error::last_os_error()
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// A file holding the process ID, removed when dropped
+pub struct Pidfile {
+    path: PathBuf,
+}
+
+impl Pidfile {
+    /// Write the process ID to `path`, unless it names a process that is
+    /// still running
+    pub fn create(path: &Path) -> error::Result<Pidfile> {
+        if let Some(pid) = fs::read_to_string(path)
+            .ok()
+            .and_then(|s| s.trim().parse::<libc::pid_t>().ok())
+            .filter(|pid| *pid > 0)
+            // SAFETY: signal 0 only checks the process exists
+            && unsafe { libc::kill(pid, 0) } == 0
+        {
+            return Err(error::Error::new(&format!(
+                "{} says perfy is already running as process {}",
+                path.display(),
+                pid
+            )));
+        }
+        fs::write(path, format!("{}\n", process::id()))
+            .map_err(|e| error::Error::new(&format!("Failed writing {}: {}", path.display(), e)))?;
+        Ok(Pidfile {
+            path: path.to_path_buf(),
+        })
+    }
+}
+
+impl Drop for Pidfile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_pidfile() {
+        let path = env::temp_dir().join(format!("perfy-test-{}.pid", process::id()));
+        let pidfile = Pidfile::create(&path).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            format!("{}\n", process::id())
+        );
+        // this process is still running
+        assert!(Pidfile::create(&path).is_err());
+        drop(pidfile);
+        assert!(!path.exists());
+
+        // left behind by a process that has exited
+        fs::write(&path, "0x\n").unwrap();
+        let pidfile = Pidfile::create(&path).unwrap();
+        drop(pidfile);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_notify() {
+        let dir = env::temp_dir().join(format!("perfy-notify-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("notify");
+        let socket = UnixDatagram::bind(&path).unwrap();
+        let client = UnixDatagram::unbound().unwrap();
+        send_notify(&client, path.as_os_str(), "READY=1").unwrap();
+        let mut buf = [0; 16];
+        let n = socket.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"READY=1");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
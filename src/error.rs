@@ -26,4 +26,28 @@ impl From<io::Error> for Error {
     }
 }
 
+impl From<bytes::TryGetError> for Error {
+    fn from(e: bytes::TryGetError) -> Error {
+        Error::new(&e.to_string())
+    }
+}
+
+impl From<rustls::Error> for Error {
+    fn from(e: rustls::Error) -> Error {
+        Error::new(&e.to_string())
+    }
+}
+
+impl From<rcgen::Error> for Error {
+    fn from(e: rcgen::Error) -> Error {
+        Error::new(&e.to_string())
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Error {
+        Error::new(&e.to_string())
+    }
+}
+
 pub type Result<T> = std::result::Result<T, Error>;
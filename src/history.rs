@@ -0,0 +1,245 @@
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::error;
+use crate::netexp::{NetExp, Side, Stats};
+use crate::units::Format;
+
+/// Rotate the history file once it grows past this many bytes
+pub const DEFAULT_MAX_SIZE: u64 = 10 * 1024 * 1024;
+
+/// Number of rotated history files kept besides the current one
+pub const DEFAULT_KEEP: usize = 3;
+
+/// What the Client asked the Server to run
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SessionParams {
+    pub protocol: String,
+    /// The Server's side of the test, "rx" or "tx"
+    pub side: String,
+    pub parallel: u16,
+    pub duration: u16,
+    pub bitrate: u64,
+    pub length: u32,
+    pub repeat: u16,
+    pub omit: u16,
+}
+
+impl From<&NetExp> for SessionParams {
+    fn from(net_exp: &NetExp) -> SessionParams {
+        let params = net_exp.params();
+        SessionParams {
+            protocol: net_exp.name().to_string(),
+            side: match params.side {
+                Side::Rx => "rx".to_string(),
+                Side::Tx => "tx".to_string(),
+            },
+            parallel: params.parallel,
+            duration: params.duration,
+            bitrate: params.bitrate,
+            length: params.length,
+            repeat: params.repeat,
+            omit: params.omit,
+        }
+    }
+}
+
+/// One line of the history file, describing one Client session
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Record {
+    /// When the session started, as UTC in RFC 3339 format
+    pub time: String,
+    pub peer: IpAddr,
+    /// Missing if the session failed before the Client sent a NetExp
+    pub params: Option<SessionParams>,
+    /// The Server's results of each run that completed
+    pub results: Vec<Stats>,
+    pub error: Option<String>,
+}
+
+impl Record {
+    pub fn new(peer: IpAddr) -> Record {
+        Record {
+            time: format_utc(SystemTime::now()),
+            peer,
+            params: None,
+            results: Vec::new(),
+            error: None,
+        }
+    }
+
+    pub fn format(&self, format: &Format) -> String {
+        let mut line = format!("{} {}", self.time, self.peer);
+        if let Some(params) = &self.params {
+            line += &format!(
+                " {} {} x{} {}s",
+                params.protocol, params.side, params.parallel, params.duration
+            );
+        }
+        let rates: Vec<String> = self
+            .results
+            .iter()
+            .filter_map(Stats::rate)
+            .map(|rate| format.rate(rate))
+            .collect();
+        if !rates.is_empty() {
+            line += &format!(" {}", rates.join(", "));
+        }
+        if let Some(error) = &self.error {
+            line += &format!(" error: {}", error);
+        }
+        line
+    }
+}
+
+/// Appends session records to a JSON-lines file, rotating it to `path.1`,
+/// `path.2` and so on when it grows too large
+pub struct History {
+    path: PathBuf,
+    max_size: u64,
+    keep: usize,
+}
+
+impl History {
+    pub fn new(path: PathBuf, max_size: u64, keep: usize) -> History {
+        History {
+            path,
+            max_size,
+            keep,
+        }
+    }
+
+    pub fn append(&self, record: &Record) -> error::Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        let size = fs::metadata(&self.path).map(|m| m.len()).unwrap_or(0);
+        if size > 0 && size + line.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        file.write_all(&line)?;
+        Ok(())
+    }
+
+    fn rotate(&self) -> io::Result<()> {
+        if self.keep == 0 {
+            return fs::remove_file(&self.path);
+        }
+        let _ = fs::remove_file(rotated(&self.path, self.keep));
+        for i in (1..self.keep).rev() {
+            let from = rotated(&self.path, i);
+            if from.exists() {
+                fs::rename(from, rotated(&self.path, i + 1))?;
+            }
+        }
+        fs::rename(&self.path, rotated(&self.path, 1))
+    }
+}
+
+fn rotated(path: &Path, i: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", i));
+    PathBuf::from(name)
+}
+
+/// Read every record in the history at `path` and its rotated files,
+/// oldest first. Lines that fail to parse are skipped.
+pub fn read(path: &Path) -> error::Result<Vec<Record>> {
+    let mut files: Vec<PathBuf> = (1..)
+        .map(|i| rotated(path, i))
+        .take_while(|p| p.exists())
+        .collect();
+    files.reverse();
+    files.push(path.to_path_buf());
+
+    let mut records = Vec::new();
+    for file in files {
+        let file = match File::open(&file) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        };
+        for line in BufReader::new(file).lines() {
+            if let Ok(record) = serde_json::from_str(&line?) {
+                records.push(record);
+            }
+        }
+    }
+    Ok(records)
+}
+
+/// Format a time as UTC in RFC 3339 format, such as 2026-10-18T09:30:00Z
+fn format_utc(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let (days, rem) = (secs / 86_400, secs % 86_400);
+    // civil date from days since the epoch, see
+    // https://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        rem / 3_600,
+        rem % 3_600 / 60,
+        rem % 60
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::Ipv4Addr;
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_format_utc() {
+        assert_eq!(format_utc(UNIX_EPOCH), "1970-01-01T00:00:00Z");
+        let time = UNIX_EPOCH + Duration::from_secs(951_782_400 + 3_661);
+        assert_eq!(format_utc(time), "2000-02-29T01:01:01Z");
+        let time = UNIX_EPOCH + Duration::from_secs(1_792_314_000);
+        assert_eq!(format_utc(time), "2026-10-18T09:00:00Z");
+    }
+
+    #[test]
+    fn test_append_rotates_and_read_returns_oldest_first() {
+        let dir = std::env::temp_dir().join(format!("perfy-history-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("history.jsonl");
+
+        let record = |i: u8| Record {
+            error: Some(format!("session {}", i)),
+            ..Record::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, i)))
+        };
+        let line_len = serde_json::to_vec(&record(0)).unwrap().len() as u64 + 1;
+        // two records per file, keeping one rotated file
+        let history = History::new(path.clone(), line_len * 2, 1);
+        for i in 0..5 {
+            history.append(&record(i)).unwrap();
+        }
+
+        let records = read(&path).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        let errors: Vec<&str> = records.iter().filter_map(|r| r.error.as_deref()).collect();
+        assert_eq!(errors, ["session 2", "session 3", "session 4"]);
+    }
+}
@@ -1,4 +1,14 @@
+pub mod auth;
 pub mod client;
+pub mod compare;
+pub mod csv;
+pub mod daemon;
 pub mod error;
+pub mod history;
+pub mod metrics;
 pub mod netexp;
+pub mod plan;
+pub mod selftest;
 pub mod server;
+pub mod sweep;
+pub mod units;
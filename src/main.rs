@@ -1,8 +1,14 @@
 use clap::{Args, Parser, Subcommand};
-use std::net::IpAddr;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::{Arc, mpsc};
+use std::thread;
+use std::time::Duration;
 
+use perfy::auth::Psk;
 use perfy::netexp;
-use perfy::{client, server};
+use perfy::units::{Format, parse_bitrate, parse_size, parse_u32};
+use perfy::{client, compare, daemon, history, plan, selftest, server, sweep};
 
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
@@ -14,16 +20,127 @@ struct Cli {
 #[derive(Subcommand)]
 enum Commands {
     /// run perfy server
-    Server {
-        /// interface to bind to
-        #[arg(short = 'B', long = "bind")]
-        host: String,
-        /// port to bind to
-        #[arg(short = 'p', long = "port")]
-        port: u16,
-    },
+    Server(ServerArgs),
     /// run perfy client
     Client(ClientArgs),
+    /// compare results saved with --json against a baseline, exits with
+    /// status 2 if any metric regressed
+    Compare {
+        /// results to compare against
+        baseline: PathBuf,
+        /// results to check
+        current: PathBuf,
+        #[command(flatten)]
+        thresholds: ThresholdArgs,
+        /// units for results: bits, bytes, bits-iec or bytes-iec
+        #[arg(short = 'f', long = "format", default_value = "bits")]
+        format: Format,
+    },
+    /// run TCP, UDP and QUIC tests against a server started in-process on
+    /// a loopback port, reporting what the host's network stack can do,
+    /// exits with status 1 if any failed
+    Selftest {
+        /// number of seconds to run each test for
+        #[arg(short = 't', long = "time", default_value_t = 3)]
+        duration: u16,
+        /// units for results: bits, bytes, bits-iec or bytes-iec
+        #[arg(short = 'f', long = "format", default_value = "bits")]
+        format: Format,
+    },
+}
+
+#[derive(Args)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+struct ServerArgs {
+    #[command(subcommand)]
+    command: Option<ServerCommands>,
+    /// interface to bind to
+    #[arg(short = 'B', long = "bind", required = true)]
+    host: Option<String>,
+    /// pin data sockets to this network interface (Linux only)
+    #[arg(long = "bind-dev")]
+    bind_dev: Option<String>,
+    /// send and receive multicast tests on this network interface
+    #[arg(long = "multicast-if")]
+    multicast_if: Option<String>,
+    /// port to bind to
+    #[arg(short = 'p', long = "port", required = true)]
+    port: Option<u16>,
+    /// require clients to authenticate with the key in this file
+    /// (defaults to the PERFY_PSK environment variable)
+    #[arg(long = "psk-file")]
+    psk_file: Option<PathBuf>,
+    #[command(flatten)]
+    tls: TlsArgs,
+    /// units for results: bits, bytes, bits-iec or bytes-iec
+    #[arg(short = 'f', long = "format", default_value = "bits")]
+    format: Format,
+    /// serve Prometheus metrics at http://ADDR:PORT/metrics
+    #[arg(long = "metrics")]
+    metrics: Option<SocketAddr>,
+    /// append a JSON line describing every session to this file
+    #[arg(long = "history")]
+    history: Option<PathBuf>,
+    /// rotate the history file once it grows past this many bytes
+    #[arg(long = "history-max-size", default_value_t = history::DEFAULT_MAX_SIZE)]
+    history_max_size: u64,
+    /// number of rotated history files to keep
+    #[arg(long = "history-keep", default_value_t = history::DEFAULT_KEEP)]
+    history_keep: usize,
+    /// run in the background, exiting once the server is listening
+    #[arg(long = "daemon")]
+    daemon: bool,
+    /// write the process ID to this file while running
+    #[arg(long = "pidfile")]
+    pidfile: Option<PathBuf>,
+    /// append output to this file when running with --daemon, reopened on
+    /// SIGHUP (defaults to discarding it)
+    #[arg(long = "log", requires = "daemon")]
+    log: Option<PathBuf>,
+}
+
+#[derive(Subcommand)]
+enum ServerCommands {
+    /// show sessions recorded with --history, oldest first
+    History {
+        /// history file written by the server
+        file: PathBuf,
+        /// only show sessions with this peer
+        #[arg(long = "peer")]
+        peer: Option<IpAddr>,
+        /// only show the most recent sessions
+        #[arg(short = 'n', long = "last")]
+        last: Option<usize>,
+        /// print the raw JSON records
+        #[arg(long = "json", default_value_t = false)]
+        json: bool,
+        /// units for results: bits, bytes, bits-iec or bytes-iec
+        #[arg(short = 'f', long = "format", default_value = "bits")]
+        format: Format,
+    },
+}
+
+#[derive(Args)]
+struct ThresholdArgs {
+    /// percent drop in bandwidth that counts as a regression
+    #[arg(long = "max-throughput-drop", default_value_t = 5.0)]
+    max_throughput_drop: f64,
+    /// percentage point rise in packet loss that counts as a regression
+    #[arg(long = "max-loss-increase", default_value_t = 1.0)]
+    max_loss_increase: f64,
+    /// percent rise in jitter or RTT that counts as a regression
+    #[arg(long = "max-latency-increase", default_value_t = 10.0)]
+    max_latency_increase: f64,
+}
+
+impl ThresholdArgs {
+    fn thresholds(&self) -> compare::Thresholds {
+        compare::Thresholds {
+            throughput_drop: self.max_throughput_drop,
+            loss_increase: self.max_loss_increase,
+            latency_increase: self.max_latency_increase,
+        }
+    }
 }
 
 #[derive(Args)]
@@ -40,8 +157,20 @@ struct CommonClientArgs {
     /// server port to connect to
     #[arg(short = 'p', long = "port")]
     port: u16,
+    /// only use IPv4
+    #[arg(short = '4', long = "ipv4", conflicts_with = "ipv6")]
+    ipv4: bool,
+    /// only use IPv6
+    #[arg(short = '6', long = "ipv6")]
+    ipv6: bool,
+    /// local address to send from and listen on
+    #[arg(short = 'B', long = "bind")]
+    bind: Option<String>,
+    /// pin sockets to this network interface (Linux only)
+    #[arg(long = "bind-dev")]
+    bind_dev: Option<String>,
     /// number of parallel streams
-    #[arg(short = 'P', long = "parallel", default_value_t = 1)]
+    #[arg(short = 'P', long = "parallel", default_value_t = 1, value_parser = clap::value_parser!(u16).range(1..=netexp::MAX_PARALLEL as i64))]
     parallel: u16,
     /// number of seconds to run for
     #[arg(short = 't', long = "time", default_value_t = 10)]
@@ -49,6 +178,208 @@ struct CommonClientArgs {
     /// send data from server to client instead of client to server
     #[arg(short = 'R', long = "reverse", default_value_t = false)]
     reverse: bool,
+    /// number of times to run the test over the same control connection
+    #[arg(long = "repeat", default_value_t = 1, value_parser = clap::value_parser!(u16).range(1..))]
+    repeat: u16,
+    /// seconds to wait between repeated runs
+    #[arg(long = "pause", default_value_t = 1)]
+    pause: u16,
+    /// seconds at the start of each run to leave out of the results, such as
+    /// TCP slow start
+    #[arg(short = 'O', long = "omit", default_value_t = 0)]
+    omit: u16,
+    /// bytes in each write or datagram (defaults to 128 KiB for TCP and
+    /// 1400 for UDP)
+    #[arg(short = 'l', long = "length", value_parser = clap::value_parser!(u32).range(..=netexp::MAX_LENGTH as i64))]
+    length: Option<u32>,
+    /// target bitrate of each TCP or UDP stream in bits per second, accepts
+    /// k, M and G suffixes, 0 for unlimited (defaults to unlimited for TCP
+    /// and 1M for UDP)
+    #[arg(short = 'b', long = "bitrate", value_parser = parse_bitrate)]
+    bitrate: Option<u64>,
+    /// bytes a stream paced with --bitrate may send back to back
+    /// (defaults to 10 ms worth, and is at least one write)
+    #[arg(long = "burst", requires = "bitrate")]
+    burst: Option<u32>,
+    /// have the kernel pace TCP streams with SO_MAX_PACING_RATE instead of
+    /// pacing writes in perfy (Linux only)
+    #[arg(long = "kernel-pacing", requires = "bitrate", conflicts_with = "burst")]
+    kernel_pacing: bool,
+    /// what TCP and UDP senders fill their writes with: zeros, random (a
+    /// seeded block, repeated), incompressible (seeded, never repeats) or
+    /// file:PATH (repeated, only when the client sends)
+    #[arg(long = "payload", default_value = "zeros", value_parser = parse_payload)]
+    payload: PayloadArg,
+    /// seed of the random and incompressible payloads (defaults to a random
+    /// one)
+    #[arg(long = "seed")]
+    seed: Option<u64>,
+    /// have the TCP or UDP receiver check every byte against the payload
+    /// and report corrupted, missing and misordered data
+    #[arg(long = "verify")]
+    verify: bool,
+    /// IPv4 TOS or IPv6 traffic class byte of TCP, TLS and UDP data on both
+    /// sides, such as 0xb8, the UDP receiver reports what arrives
+    #[arg(long = "tos", value_parser = parse_tos)]
+    tos: Option<u8>,
+    /// DSCP to mark data with, the upper six bits of --tos
+    #[arg(long = "dscp", conflicts_with = "tos", value_parser = clap::value_parser!(u8).range(..64))]
+    dscp: Option<u8>,
+    /// IPv6 flow label of the streams the sending side opens (Linux only)
+    #[arg(long = "flow-label", value_parser = clap::value_parser!(u32).range(1..1 << 20))]
+    flow_label: Option<u32>,
+    /// SO_PRIORITY of data sockets on both sides (Linux only)
+    #[arg(long = "priority")]
+    priority: Option<u32>,
+    /// SO_MARK of data sockets on both sides, needs CAP_NET_ADMIN
+    /// (Linux only)
+    #[arg(long = "mark", value_parser = parse_u32)]
+    mark: Option<u32>,
+    /// SO_SNDBUF and SO_RCVBUF of data sockets on both sides, accepts K, M
+    /// and G suffixes
+    #[arg(short = 'w', long = "window", value_parser = parse_size)]
+    window: Option<u32>,
+    /// pin the client's side of the test to core CPU, and the server's to
+    /// SERVER_CPU, as CPU[,SERVER_CPU] (Linux only)
+    #[arg(short = 'A', long = "affinity", value_parser = parse_affinity)]
+    affinity: Option<Affinity>,
+    /// how the TCP and UDP data loops do their I/O on both sides: std, or
+    /// io_uring with registered buffers and multishot receives (Linux
+    /// builds with the io-uring feature, each side falls back to std)
+    #[arg(long = "engine", default_value = "std", value_parser = parse_engine)]
+    engine: netexp::Engine,
+    /// units for results: bits, bytes, bits-iec or bytes-iec
+    #[arg(short = 'f', long = "format", default_value = "bits")]
+    format: Format,
+    /// authenticate with the key in this file
+    /// (defaults to the PERFY_PSK environment variable)
+    #[arg(long = "psk-file")]
+    psk_file: Option<PathBuf>,
+    /// also write per-interval and summary results to this CSV file
+    #[arg(long = "csv")]
+    csv: Option<PathBuf>,
+    /// also save the results to this JSON file, for `perfy compare`
+    #[arg(long = "json")]
+    json: Option<PathBuf>,
+    /// compare the results against this file saved with --json, exits with
+    /// status 2 if any metric regressed
+    #[arg(long = "baseline")]
+    baseline: Option<PathBuf>,
+    #[command(flatten)]
+    thresholds: ThresholdArgs,
+    /// run the test once for each value of length, parallel, bitrate or
+    /// window and print a table of the results, as PARAM=A,B,C or a range
+    /// PARAM=START..END that doubles each step (END*N multiplies, END+N
+    /// adds)
+    #[arg(long = "sweep", conflicts_with_all = ["csv", "json", "baseline"])]
+    sweep: Option<sweep::Sweep>,
+}
+
+#[derive(Clone)]
+enum PayloadArg {
+    Pattern(netexp::Pattern),
+    File(PathBuf),
+}
+
+/// Cores given with --affinity
+#[derive(Clone, Copy)]
+struct Affinity {
+    client: u16,
+    server: Option<u16>,
+}
+
+#[derive(Args)]
+struct TlsArgs {
+    /// PEM certificate chain presented when accepting TLS or QUIC connections
+    /// (defaults to a generated self-signed certificate)
+    #[arg(long = "tls-cert", requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+    /// PEM private key for --tls-cert
+    #[arg(long = "tls-key", requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+}
+
+#[derive(Args)]
+struct UdpClientArgs {
+    #[command(flatten)]
+    common: CommonClientArgs,
+    /// send datagrams to this multicast group instead of the server, the
+    /// receiving side joins it
+    #[arg(long = "multicast", value_parser = parse_multicast)]
+    multicast: Option<IpAddr>,
+    /// hops multicast datagrams may travel
+    #[arg(long = "ttl", default_value_t = 1, requires = "multicast")]
+    ttl: u8,
+    /// send and receive multicast on this network interface
+    #[arg(long = "multicast-if", requires = "multicast")]
+    multicast_if: Option<String>,
+    /// datagrams each side moves per system call with sendmmsg and recvmmsg
+    /// (Linux only)
+    #[arg(long = "batch", default_value_t = 1, value_parser = clap::value_parser!(u16).range(1..=netexp::MAX_BATCH as i64))]
+    batch: u16,
+    /// have the sender hand the kernel --batch datagrams as one buffer to
+    /// split with UDP GSO, at most 64 and 64 KiB at a time (Linux only)
+    #[arg(long = "gso")]
+    gso: bool,
+    /// have the receiver take datagrams the kernel coalesced with UDP GRO
+    /// (Linux only)
+    #[arg(long = "gro")]
+    gro: bool,
+}
+
+#[derive(Args)]
+struct JoinArgs {
+    /// multicast group to join
+    #[arg(short = 'g', long = "group", value_parser = parse_multicast)]
+    group: IpAddr,
+    /// port the sender sends to
+    #[arg(short = 'p', long = "port")]
+    port: u16,
+    /// number of seconds the sender runs for, gives up if nothing arrives
+    /// within that plus a few seconds
+    #[arg(short = 't', long = "time", default_value_t = 10)]
+    duration: u16,
+    /// number of parallel streams the sender runs
+    #[arg(short = 'P', long = "parallel", default_value_t = 1, value_parser = clap::value_parser!(u16).range(1..=netexp::MAX_PARALLEL as i64))]
+    parallel: u16,
+    /// receive on this network interface
+    #[arg(long = "multicast-if")]
+    multicast_if: Option<String>,
+    /// units for results: bits, bytes, bits-iec or bytes-iec
+    #[arg(short = 'f', long = "format", default_value = "bits")]
+    format: Format,
+    /// also write per-interval and summary results to this CSV file
+    #[arg(long = "csv")]
+    csv: Option<PathBuf>,
+}
+
+#[derive(Args)]
+struct RunArgs {
+    /// TOML file listing the steps to run
+    plan: PathBuf,
+    /// authenticate with the key in this file
+    /// (defaults to the PERFY_PSK environment variable)
+    #[arg(long = "psk-file")]
+    psk_file: Option<PathBuf>,
+    /// units for results: bits, bytes, bits-iec or bytes-iec
+    #[arg(short = 'f', long = "format", default_value = "bits")]
+    format: Format,
+    /// also save the results of every step to this JSON file
+    #[arg(long = "json")]
+    json: Option<PathBuf>,
+}
+
+#[derive(Args)]
+struct UnixClientArgs {
+    #[command(flatten)]
+    common: CommonClientArgs,
+    /// socket path shared by client and server, prefix with '@' for an
+    /// abstract socket
+    #[arg(long = "path")]
+    path: String,
+    /// use datagram sockets instead of stream sockets
+    #[arg(long = "datagram", default_value_t = false)]
+    datagram: bool,
 }
 
 #[derive(Subcommand)]
@@ -57,51 +388,499 @@ enum ClientCommands {
     Tcp(CommonClientArgs),
 
     /// test using UDP
-    Udp(CommonClientArgs),
+    Udp(UdpClientArgs),
+
+    /// test using TCP encrypted with TLS
+    Tls(CommonClientArgs),
+
+    /// test using QUIC, with one stream per --parallel
+    Quic(CommonClientArgs),
+
+    /// test using a Unix domain socket on the same host
+    Unix(UnixClientArgs),
+
+    /// join a multicast group and report what arrives from a `client udp
+    /// --multicast` sender, without a server
+    Join(JoinArgs),
+
+    /// run the steps of a TOML test plan, one after another or all at
+    /// once, then report them together, exits with status 1 if any failed
+    Run(RunArgs),
+}
+
+impl ClientCommands {
+    /// Options shared by the subcommands that run a test against a server
+    fn common(&self) -> Option<&CommonClientArgs> {
+        match self {
+            ClientCommands::Tcp(args) => Some(args),
+            ClientCommands::Udp(args) => Some(&args.common),
+            ClientCommands::Tls(args) | ClientCommands::Quic(args) => Some(args),
+            ClientCommands::Unix(args) => Some(&args.common),
+            ClientCommands::Join(_) | ClientCommands::Run(_) => None,
+        }
+    }
 }
 
 fn main() {
     let cli = Cli::parse();
     match cli.command {
-        Commands::Server { host, port } => {
-            let host: IpAddr = host.parse().expect("Invalid host");
-            let config = server::ServerConfig { host, port };
-            server::run(config).unwrap_or_else(|e| print_error_and_exit(&e.message))
+        Commands::Server(ServerArgs {
+            command:
+                Some(ServerCommands::History {
+                    file,
+                    peer,
+                    last,
+                    json,
+                    format,
+                }),
+            ..
+        }) => {
+            let mut records =
+                history::read(&file).unwrap_or_else(|e| print_error_and_exit(&e.message));
+            if let Some(peer) = peer {
+                records.retain(|r| r.peer == peer);
+            }
+            if let Some(last) = last {
+                records.drain(..records.len().saturating_sub(last));
+            }
+            for record in records {
+                if json {
+                    let line = serde_json::to_string(&record)
+                        .unwrap_or_else(|e| print_error_and_exit(&e.to_string()));
+                    println!("{}", line);
+                } else {
+                    println!("{}", record.format(&format));
+                }
+            }
+        }
+        Commands::Server(args) => {
+            let (Some(host), Some(port)) = (args.host, args.port) else {
+                print_error_and_exit("--bind and --port are required");
+            };
+            let host = netexp::resolve(&host, netexp::Family::Any)
+                .unwrap_or_else(|e| print_error_and_exit(&e.message));
+            let psk = Psk::load(args.psk_file.as_deref())
+                .unwrap_or_else(|e| print_error_and_exit(&e.message));
+            let run_options = netexp::RunOptions {
+                tls_identity: tls_identity(&args.tls),
+                local: netexp::LocalBind {
+                    addr: Some(host),
+                    device: args.bind_dev,
+                    multicast_if: args.multicast_if,
+                },
+                format: args.format,
+                ..Default::default()
+            };
+            let reload = server_reload(&args.psk_file, &args.tls, &args.log);
+            // fork before any threads are started
+            let detached = args.daemon.then(|| {
+                daemon::daemonize(args.log.as_deref())
+                    .unwrap_or_else(|e| print_error_and_exit(&e.message))
+            });
+            let pidfile = args.pidfile.as_ref().map(|path| {
+                daemon::Pidfile::create(path).unwrap_or_else(|e| print_error_and_exit(&e.message))
+            });
+            daemon::handle_signals().unwrap_or_else(|e| print_error_and_exit(&e.message));
+            let (ready_tx, ready_rx) = mpsc::channel();
+            // the user's process exits once the server is listening
+            if let Some(detached) = detached {
+                thread::spawn(move || {
+                    if ready_rx.recv().is_ok() {
+                        detached.ready();
+                    }
+                });
+            }
+            let config = server::ServerConfig {
+                host,
+                port,
+                psk,
+                run_options,
+                metrics: args.metrics,
+                history: args.history.map(|path| {
+                    history::History::new(path, args.history_max_size, args.history_keep)
+                }),
+                ready: args.daemon.then_some(ready_tx),
+                reload: Some(reload),
+            };
+            let result = server::run(config);
+            drop(pidfile);
+            result.unwrap_or_else(|e| print_error_and_exit(&e.message))
         }
-        Commands::Client(client_args) => match client_args.command {
-            ClientCommands::Tcp(args) => {
-                let host: IpAddr = args.host.parse().expect("Invalid host");
-                let params = netexp::NetExpParams {
-                    host,
-                    port: args.port,
-                    side: if args.reverse {
-                        netexp::Side::Rx
-                    } else {
-                        netexp::Side::Tx
-                    },
-                    parallel: args.parallel,
-                    duration: args.duration,
-                };
-                let net_exp = netexp::NetExp::Tcp(params);
-                client::run(net_exp).unwrap_or_else(|e| print_error_and_exit(&e.message))
+        Commands::Client(client_args) => {
+            let sweep = client_args.command.common().and_then(|c| c.sweep.clone());
+            let (net_exp, config) = match client_args.command {
+                ClientCommands::Tcp(args) => (
+                    netexp::NetExp::Tcp(net_exp_params(&args)),
+                    client_config(&args),
+                ),
+                ClientCommands::Udp(args) => {
+                    if args.common.engine == netexp::Engine::IoUring && (args.gso || args.gro) {
+                        print_error_and_exit("--gso and --gro need --engine std");
+                    }
+                    let params = netexp::NetExpParams {
+                        bitrate: args.common.bitrate.unwrap_or(netexp::DEFAULT_UDP_BITRATE),
+                        multicast: args.multicast,
+                        multicast_ttl: args.ttl,
+                        batch: args.batch,
+                        gso: args.gso,
+                        gro: args.gro,
+                        ..net_exp_params(&args.common)
+                    };
+                    let mut config = client_config(&args.common);
+                    config.run_options.local.multicast_if = args.multicast_if;
+                    (netexp::NetExp::Udp(params), config)
+                }
+                ClientCommands::Tls(args) => (
+                    netexp::NetExp::Tls(net_exp_params(&args)),
+                    client_config(&args),
+                ),
+                ClientCommands::Quic(args) => (
+                    netexp::NetExp::Quic(net_exp_params(&args)),
+                    client_config(&args),
+                ),
+                ClientCommands::Join(args) => {
+                    let params = netexp::NetExpParams {
+                        port: args.port,
+                        side: netexp::Side::Rx,
+                        parallel: args.parallel,
+                        duration: args.duration,
+                        multicast: Some(args.group),
+                        ..Default::default()
+                    };
+                    let config = client::ClientConfig {
+                        run_options: netexp::RunOptions {
+                            local: netexp::LocalBind {
+                                multicast_if: args.multicast_if,
+                                ..Default::default()
+                            },
+                            format: args.format,
+                            ..Default::default()
+                        },
+                        csv: args.csv,
+                        ..Default::default()
+                    };
+                    client::join(netexp::NetExp::Udp(params), &config)
+                        .unwrap_or_else(|e| print_error_and_exit(&e.message));
+                    return;
+                }
+                ClientCommands::Run(args) => {
+                    run_plan(args);
+                    return;
+                }
+                ClientCommands::Unix(args) => {
+                    let unix = netexp::UnixParams {
+                        path: args.path,
+                        mode: if args.datagram {
+                            netexp::UnixMode::Datagram
+                        } else {
+                            netexp::UnixMode::Stream
+                        },
+                    };
+                    (
+                        netexp::NetExp::Unix(net_exp_params(&args.common), unix),
+                        client_config(&args.common),
+                    )
+                }
+            };
+            if let Some(sweep) = sweep {
+                let points = sweep.run(&net_exp, &config);
+                println!("Sweep results:");
+                println!(
+                    "{}",
+                    sweep.format_points(&points, &config.run_options.format)
+                );
+                if points.iter().any(|p| p.error.is_some()) {
+                    std::process::exit(1);
+                }
+                return;
+            }
+            let baseline = config.baseline.as_ref().map(|path| {
+                compare::load(path).unwrap_or_else(|e| print_error_and_exit(&e.message))
+            });
+            let results =
+                client::run(net_exp, &config).unwrap_or_else(|e| print_error_and_exit(&e.message));
+            if let Some(baseline) = baseline {
+                compare_and_exit(
+                    &baseline,
+                    &results,
+                    &config.thresholds,
+                    &config.run_options.format,
+                );
             }
-            ClientCommands::Udp(args) => {
-                let host: IpAddr = args.host.parse().expect("Invalid host");
-                let params = netexp::NetExpParams {
-                    host,
-                    port: args.port,
-                    side: if args.reverse {
-                        netexp::Side::Rx
-                    } else {
-                        netexp::Side::Tx
-                    },
-                    parallel: args.parallel,
-                    duration: args.duration,
-                };
-                let net_exp = netexp::NetExp::Udp(params);
-                client::run(net_exp).unwrap_or_else(|e| print_error_and_exit(&e.message))
+        }
+        Commands::Compare {
+            baseline,
+            current,
+            thresholds,
+            format,
+        } => {
+            let baseline =
+                compare::load(&baseline).unwrap_or_else(|e| print_error_and_exit(&e.message));
+            let current =
+                compare::load(&current).unwrap_or_else(|e| print_error_and_exit(&e.message));
+            compare_and_exit(&baseline, &current, &thresholds.thresholds(), &format);
+        }
+        Commands::Selftest { duration, format } => {
+            let config = client::ClientConfig {
+                run_options: netexp::RunOptions {
+                    format,
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+            let reports = selftest::run(duration, &config)
+                .unwrap_or_else(|e| print_error_and_exit(&e.message));
+            println!("Selftest results:");
+            println!("{}", plan::format_reports(&reports, &format));
+            if reports.iter().any(|r| r.error.is_some()) {
+                std::process::exit(1);
             }
+        }
+    }
+}
+
+fn run_plan(args: RunArgs) {
+    let plan = plan::Plan::load(&args.plan).unwrap_or_else(|e| print_error_and_exit(&e.message));
+    let psk =
+        Psk::load(args.psk_file.as_deref()).unwrap_or_else(|e| print_error_and_exit(&e.message));
+    let config = client::ClientConfig {
+        psk,
+        run_options: netexp::RunOptions {
+            format: args.format,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let reports = plan.run(&config);
+    println!("Plan results:");
+    println!("{}", plan::format_reports(&reports, &args.format));
+    let failed = reports.iter().any(|r| r.error.is_some());
+    if let Some(path) = &args.json {
+        plan::save(path, reports).unwrap_or_else(|e| print_error_and_exit(&e.message));
+    }
+    if failed {
+        std::process::exit(1);
+    }
+}
+
+/// Address family requested with -4/-6, or that of the --bind address
+fn family(args: &CommonClientArgs) -> netexp::Family {
+    if args.ipv4 {
+        netexp::Family::V4
+    } else if args.ipv6 {
+        netexp::Family::V6
+    } else {
+        netexp::Family::Any
+    }
+}
+
+fn local_bind(args: &CommonClientArgs) -> netexp::LocalBind {
+    let addr = args.bind.as_ref().map(|bind| {
+        netexp::resolve(bind, family(args)).unwrap_or_else(|e| print_error_and_exit(&e.message))
+    });
+    netexp::LocalBind {
+        addr,
+        device: args.bind_dev.clone(),
+        multicast_if: None,
+    }
+}
+
+fn net_exp_params(args: &CommonClientArgs) -> netexp::NetExpParams {
+    let family = match local_bind(args).addr {
+        Some(IpAddr::V4(_)) => netexp::Family::V4,
+        Some(IpAddr::V6(_)) => netexp::Family::V6,
+        None => family(args),
+    };
+    let host =
+        netexp::resolve(&args.host, family).unwrap_or_else(|e| print_error_and_exit(&e.message));
+    if args.flow_label.is_some() && host.is_ipv4() {
+        print_error_and_exit("--flow-label needs an IPv6 server address");
+    }
+    let payload = match &args.payload {
+        PayloadArg::Pattern(pattern) => *pattern,
+        PayloadArg::File(_) if args.reverse => {
+            print_error_and_exit("--payload file: needs the client to send, not --reverse")
+        }
+        PayloadArg::File(_) if args.verify => {
+            print_error_and_exit("--verify needs a generated payload, not a file")
+        }
+        PayloadArg::File(_) => netexp::Pattern::Zeros,
+    };
+    let seed = match payload {
+        netexp::Pattern::Zeros => 0,
+        _ => args.seed.unwrap_or_else(rand::random),
+    };
+    netexp::NetExpParams {
+        host,
+        port: args.port,
+        side: if args.reverse {
+            netexp::Side::Rx
+        } else {
+            netexp::Side::Tx
+        },
+        parallel: args.parallel,
+        duration: args.duration,
+        length: args.length.unwrap_or(0),
+        bitrate: args.bitrate.unwrap_or(0),
+        burst: args.burst.unwrap_or(0),
+        kernel_pacing: args.kernel_pacing,
+        marking: netexp::Marking {
+            tos: args.tos.or(args.dscp.map(|dscp| dscp << 2)).unwrap_or(0),
+            flow_label: args.flow_label.unwrap_or(0),
+            priority: args.priority.unwrap_or(0),
+            mark: args.mark.unwrap_or(0),
+            buffer: args.window.unwrap_or(0),
         },
+        payload,
+        seed,
+        verify: args.verify,
+        repeat: args.repeat,
+        omit: args.omit,
+        cpu: args.affinity.map(|affinity| affinity.client),
+        engine: args.engine,
+        ..Default::default()
+    }
+}
+
+fn parse_multicast(s: &str) -> Result<IpAddr, String> {
+    let group: IpAddr = s
+        .parse()
+        .map_err(|_| format!("invalid IP address '{}'", s))?;
+    if !group.is_multicast() {
+        return Err(format!("{} is not a multicast address", group));
+    }
+    Ok(group)
+}
+
+fn parse_payload(s: &str) -> Result<PayloadArg, String> {
+    match s {
+        "zeros" => Ok(PayloadArg::Pattern(netexp::Pattern::Zeros)),
+        "random" => Ok(PayloadArg::Pattern(netexp::Pattern::Random)),
+        "incompressible" => Ok(PayloadArg::Pattern(netexp::Pattern::Incompressible)),
+        _ => match s.strip_prefix("file:") {
+            Some(path) if !path.is_empty() => Ok(PayloadArg::File(path.into())),
+            _ => Err(format!(
+                "invalid payload '{}', expected zeros, random, incompressible or file:PATH",
+                s
+            )),
+        },
+    }
+}
+
+fn parse_engine(s: &str) -> Result<netexp::Engine, String> {
+    match s {
+        "std" => Ok(netexp::Engine::Std),
+        "io_uring" => Ok(netexp::Engine::IoUring),
+        _ => Err(format!("invalid engine '{}', expected std or io_uring", s)),
+    }
+}
+
+fn parse_affinity(s: &str) -> Result<Affinity, String> {
+    let core = |s: &str| {
+        s.parse()
+            .map_err(|_| format!("invalid CPU '{}', expected CPU[,SERVER_CPU]", s))
+    };
+    match s.split_once(',') {
+        Some((client, server)) => Ok(Affinity {
+            client: core(client)?,
+            server: Some(core(server)?),
+        }),
+        None => Ok(Affinity {
+            client: core(s)?,
+            server: None,
+        }),
+    }
+}
+
+fn parse_tos(s: &str) -> Result<u8, String> {
+    let tos = parse_u32(s)?;
+    u8::try_from(tos).map_err(|_| format!("TOS {} is more than one byte", s))
+}
+
+fn client_config(args: &CommonClientArgs) -> client::ClientConfig {
+    let psk =
+        Psk::load(args.psk_file.as_deref()).unwrap_or_else(|e| print_error_and_exit(&e.message));
+    client::ClientConfig {
+        psk,
+        run_options: netexp::RunOptions {
+            local: local_bind(args),
+            format: args.format,
+            payload_data: payload_data(args),
+            ..Default::default()
+        },
+        csv: args.csv.clone(),
+        json: args.json.clone(),
+        baseline: args.baseline.clone(),
+        thresholds: args.thresholds.thresholds(),
+        pause: Duration::from_secs(args.pause.into()),
+        server_cpu: args.affinity.and_then(|affinity| affinity.server),
+    }
+}
+
+/// Contents of the --payload file, if one was given
+fn payload_data(args: &CommonClientArgs) -> Option<Arc<[u8]>> {
+    let PayloadArg::File(path) = &args.payload else {
+        return None;
+    };
+    let data = std::fs::read(path).unwrap_or_else(|e| {
+        print_error_and_exit(&format!("Failed reading {}: {}", path.display(), e))
+    });
+    if data.is_empty() {
+        print_error_and_exit(&format!("{} is empty", path.display()));
+    }
+    Some(data.into())
+}
+
+fn tls_identity(args: &TlsArgs) -> Option<Arc<netexp::TlsIdentity>> {
+    let (Some(cert), Some(key)) = (&args.tls_cert, &args.tls_key) else {
+        return None;
+    };
+    let identity = netexp::TlsIdentity::from_pem_files(cert, key)
+        .unwrap_or_else(|e| print_error_and_exit(&e.message));
+    Some(Arc::new(identity))
+}
+
+/// Re-read the PSK and TLS identity on SIGHUP, and reopen the log
+fn server_reload(
+    psk_file: &Option<PathBuf>,
+    tls: &TlsArgs,
+    log: &Option<PathBuf>,
+) -> server::Reload {
+    let psk_file = psk_file.clone();
+    let (tls_cert, tls_key) = (tls.tls_cert.clone(), tls.tls_key.clone());
+    let log = log.clone();
+    Box::new(move || {
+        if let Some(log) = &log {
+            daemon::redirect_output(Some(log))?;
+        }
+        let tls_identity = match (&tls_cert, &tls_key) {
+            (Some(cert), Some(key)) => {
+                Some(Arc::new(netexp::TlsIdentity::from_pem_files(cert, key)?))
+            }
+            _ => None,
+        };
+        Ok(server::Reloaded {
+            psk: Psk::load(psk_file.as_deref())?,
+            tls_identity,
+        })
+    })
+}
+
+/// Print how `current` differs from `baseline`, exiting with status 2 if
+/// anything regressed
+fn compare_and_exit(
+    baseline: &[client::RunResult],
+    current: &[client::RunResult],
+    thresholds: &compare::Thresholds,
+    format: &Format,
+) {
+    let deltas = compare::compare(baseline, current, thresholds);
+    println!("Compared to baseline:");
+    for delta in &deltas {
+        println!("{}", delta.format(format));
+    }
+    if deltas.iter().any(|d| d.regressed) {
+        std::process::exit(2);
     }
 }
 
@@ -0,0 +1,205 @@
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{IpAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::netexp::{Side, Stats};
+
+/// How long a scraper has to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+/// Most of a request read before answering it
+const MAX_REQUEST_SIZE: u64 = 8192;
+
+/// Counters and gauges describing what a Server has done since it started
+#[derive(Default)]
+pub struct Metrics {
+    sessions_started: AtomicU64,
+    sessions_failed: AtomicU64,
+    active_sessions: AtomicU64,
+    bytes_received: AtomicU64,
+    bytes_sent: AtomicU64,
+    /// Bits per second of the most recent run with each peer
+    last_throughput: Mutex<HashMap<IpAddr, f64>>,
+}
+
+impl Metrics {
+    pub fn session_started(&self) {
+        self.sessions_started.fetch_add(1, Ordering::Relaxed);
+        self.active_sessions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn session_finished(&self, ok: bool) {
+        if !ok {
+            self.sessions_failed.fetch_add(1, Ordering::Relaxed);
+        }
+        self.active_sessions.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Count the data moved by one run on `side` of a NetExp with `peer`
+    pub fn record_run(&self, peer: IpAddr, side: &Side, stats: &Stats) {
+        if let Some((bytes, _)) = stats.transfer {
+            let counter = match side {
+                Side::Rx => &self.bytes_received,
+                Side::Tx => &self.bytes_sent,
+            };
+            counter.fetch_add(bytes, Ordering::Relaxed);
+        }
+        if let Some(rate) = stats.rate() {
+            self.last_throughput
+                .lock()
+                .unwrap()
+                .insert(peer, rate * 8f64);
+        }
+    }
+
+    /// Render in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, value: u64| {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            let _ = writeln!(out, "{} {}", name, value);
+        };
+        metric(
+            "perfy_sessions_started_total",
+            "counter",
+            "Client sessions accepted",
+            self.sessions_started.load(Ordering::Relaxed),
+        );
+        metric(
+            "perfy_sessions_failed_total",
+            "counter",
+            "Client sessions that ended with an error",
+            self.sessions_failed.load(Ordering::Relaxed),
+        );
+        metric(
+            "perfy_active_sessions",
+            "gauge",
+            "Client sessions in progress",
+            self.active_sessions.load(Ordering::Relaxed),
+        );
+        metric(
+            "perfy_bytes_received_total",
+            "counter",
+            "Test data received from clients",
+            self.bytes_received.load(Ordering::Relaxed),
+        );
+        metric(
+            "perfy_bytes_sent_total",
+            "counter",
+            "Test data sent to clients",
+            self.bytes_sent.load(Ordering::Relaxed),
+        );
+
+        let name = "perfy_last_throughput_bits_per_second";
+        let _ = writeln!(
+            out,
+            "# HELP {} Throughput of the most recent run with each peer",
+            name
+        );
+        let _ = writeln!(out, "# TYPE {} gauge", name);
+        let last_throughput = self.last_throughput.lock().unwrap();
+        let mut peers: Vec<_> = last_throughput.iter().collect();
+        peers.sort_by_key(|(peer, _)| **peer);
+        for (peer, rate) in peers {
+            let _ = writeln!(out, "{}{{peer=\"{}\"}} {}", name, peer, rate);
+        }
+        out
+    }
+
+    /// Answer HTTP requests for /metrics on `listener` from a background
+    /// thread
+    pub fn serve(self: &Arc<Self>, listener: TcpListener) {
+        let metrics = self.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                if let Err(e) = metrics.respond(stream) {
+                    eprintln!("Error serving metrics: {}", e);
+                }
+            }
+        });
+    }
+
+    fn respond(&self, mut stream: TcpStream) -> std::io::Result<()> {
+        stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+        stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+        // the one thread serving scrapers only waits on each for so long
+        // and reads so much before answering
+        let mut reader = BufReader::new(stream.try_clone()?.take(MAX_REQUEST_SIZE));
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line)?;
+        // skip the headers, nothing in them changes the response
+        let mut line = String::new();
+        while reader.read_line(&mut line)? > 2 {
+            line.clear();
+        }
+
+        let mut parts = request_line.split_whitespace();
+        let (status, body) = match (parts.next(), parts.next()) {
+            (Some("GET"), Some("/metrics")) => ("200 OK", self.render()),
+            (Some("GET"), _) => ("404 Not Found", "Not Found\n".to_string()),
+            _ => ("405 Method Not Allowed", "Method Not Allowed\n".to_string()),
+        };
+        write!(
+            stream,
+            "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            body.len(),
+            body
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::default();
+        metrics.session_started();
+        metrics.session_finished(false);
+        metrics.session_started();
+        let stats = Stats::new().with_transfer(1_000, Duration::from_secs(1));
+        let peer = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        metrics.record_run(peer, &Side::Rx, &stats);
+        metrics.record_run(peer, &Side::Tx, &stats);
+
+        let text = metrics.render();
+        assert!(text.contains("perfy_sessions_started_total 2\n"));
+        assert!(text.contains("perfy_sessions_failed_total 1\n"));
+        assert!(text.contains("perfy_active_sessions 1\n"));
+        assert!(text.contains("perfy_bytes_received_total 1000\n"));
+        assert!(text.contains("perfy_bytes_sent_total 1000\n"));
+        assert!(text.contains("perfy_last_throughput_bits_per_second{peer=\"127.0.0.1\"} 8000\n"));
+        assert!(text.contains("# TYPE perfy_active_sessions gauge\n"));
+    }
+
+    fn get(addr: std::net::SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\nHost: perfy\r\n\r\n", path).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn test_serve() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let metrics = Arc::new(Metrics::default());
+        metrics.session_started();
+        metrics.serve(listener);
+
+        let response = get(addr, "/metrics");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("perfy_sessions_started_total 1\n"));
+        assert!(get(addr, "/").starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+}
@@ -1,69 +1,85 @@
+mod cpu;
+mod engine;
+mod mmsg;
+mod pacer;
+mod payload;
+mod quic;
+mod sock;
+mod stats;
 mod tcp;
+mod tls;
 mod udp;
+mod unix;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+mod uring;
 
 use bytes::{Buf, BufMut, Bytes, BytesMut};
 use std::{
-    fmt::Display,
+    io::{Read, Write},
     net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::Arc,
+    time::Duration,
 };
 
-use crate::error;
-
-struct Stats {
-    /// Bandwidth in KB per second
-    bandwidth: Option<u128>,
-    // Packet loss as percentage
-    packet_loss: Option<f64>,
-}
+pub use engine::Engine;
+pub use payload::Pattern;
+pub use sock::{Family, LocalBind, Marking, resolve};
+pub use stats::{Cpu, DatagramIo, INTERVAL, Integrity, Interval, Stats, Summary};
+pub use tls::Identity as TlsIdentity;
 
-impl Stats {
-    fn new() -> Self {
-        Self {
-            bandwidth: None,
-            packet_loss: None,
-        }
-    }
-
-    fn with_bandwidth(self, bandwidth: u128) -> Self {
-        Self {
-            bandwidth: Some(bandwidth),
-            ..self
-        }
-    }
-
-    fn with_packet_loss(self, packet_loss: f64) -> Self {
-        Self {
-            packet_loss: Some(packet_loss),
-            ..self
-        }
-    }
-}
-
-impl Display for Stats {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        if let Some(bw) = self.bandwidth {
-            // convert from bytes to bits
-            let bw = bw * 8;
-            let msg = match bw {
-                0..1_000 => format!("Bandwidth: {} kbps", bw),
-                1_000..1_000_000 => format!("Bandwidth: {} mbps", bw as f64 / 1_000f64),
-                _ => format!("Bandwidth: {} gbps", bw as f64 / 1_000_000f64),
-            };
-            f.write_str(&msg)?
-        }
-        if let Some(pl) = self.packet_loss {
-            f.write_str(&format!("Packet loss: {}%\n", pl))?
-        }
+use crate::error;
+use crate::units::Format;
 
-        Ok(())
-    }
-}
+/// Option tag for [`NetExpParams::bitrate`]
+const OPT_BITRATE: u8 = 1;
+/// Option tag for [`NetExpParams::length`]
+const OPT_LENGTH: u8 = 2;
+/// Option tag for [`NetExpParams::repeat`]
+const OPT_REPEAT: u8 = 3;
+/// Option tag for [`NetExpParams::omit`]
+const OPT_OMIT: u8 = 4;
+/// Option tag for [`NetExpParams::burst`]
+const OPT_BURST: u8 = 5;
+/// Option tag for [`NetExpParams::kernel_pacing`]
+const OPT_KERNEL_PACING: u8 = 6;
+/// Option tag for [`Marking::tos`]
+const OPT_TOS: u8 = 7;
+/// Option tag for [`Marking::flow_label`]
+const OPT_FLOW_LABEL: u8 = 8;
+/// Option tag for [`Marking::priority`]
+const OPT_PRIORITY: u8 = 9;
+/// Option tag for [`Marking::mark`]
+const OPT_MARK: u8 = 10;
+/// Option tag for [`NetExpParams::multicast`]
+const OPT_MULTICAST: u8 = 11;
+/// Option tag for [`NetExpParams::multicast_ttl`]
+const OPT_MULTICAST_TTL: u8 = 12;
+/// Option tag for [`NetExpParams::payload`] and [`NetExpParams::seed`]
+const OPT_PAYLOAD: u8 = 13;
+/// Option tag for [`NetExpParams::verify`]
+const OPT_VERIFY: u8 = 14;
+/// Option tag for [`NetExpParams::cpu`]
+const OPT_CPU: u8 = 15;
+/// Option tag for [`Marking::buffer`]
+const OPT_BUFFER: u8 = 16;
+/// Option tag for [`NetExpParams::batch`]
+const OPT_BATCH: u8 = 17;
+/// Option tag for [`NetExpParams::gso`]
+const OPT_GSO: u8 = 18;
+/// Option tag for [`NetExpParams::gro`]
+const OPT_GRO: u8 = 19;
+/// Option tag for [`NetExpParams::engine`]
+const OPT_ENGINE: u8 = 20;
 
-/// Megabytes (base 10)
-const MB: usize = 1_000_000;
+/// UDP streams are paced to 1 Mbit/s unless --bitrate says otherwise
+pub const DEFAULT_UDP_BITRATE: u64 = 1_000_000;
 
-/// Size of data to send in bytes
-const BUF_SIZE: usize = 10 * MB;
+/// Most streams a NetExp may run, each is a thread and a socket
+pub const MAX_PARALLEL: u16 = 1024;
+/// Largest write or datagram, each stream allocates a few of them
+pub const MAX_LENGTH: u32 = 1024 * 1024;
+/// Most datagrams moved per system call, each has a buffer of its own
+pub const MAX_BATCH: u16 = 1024;
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct NetExpParams {
@@ -72,12 +88,120 @@ pub struct NetExpParams {
     pub side: Side,
     pub parallel: u16,
     pub duration: u16,
+    /// Target rate of each stream in bits per second, 0 for unlimited
+    pub bitrate: u64,
+    /// Size of each write or datagram in bytes, 0 for the protocol default
+    pub length: u32,
+    /// Number of runs made back to back over one control connection
+    pub repeat: u16,
+    /// Seconds at the start of each run left out of the results, the run
+    /// is extended by as much to make up for them
+    pub omit: u16,
+    /// Bytes a paced sender may send back to back, 0 for one write
+    pub burst: u32,
+    /// Have the kernel pace TCP with SO_MAX_PACING_RATE instead of pacing
+    /// writes in perfy (Linux only)
+    pub kernel_pacing: bool,
+    /// QoS markings of the TCP, TLS and UDP data sockets on both sides
+    pub marking: Marking,
+    /// Multicast group UDP datagrams are sent to instead of `host`, the
+    /// receiving side joins it
+    pub multicast: Option<IpAddr>,
+    /// Hops multicast datagrams may travel
+    pub multicast_ttl: u8,
+    /// What TCP and UDP senders fill their writes with
+    pub payload: Pattern,
+    /// Seed of the pseudo-random payload patterns
+    pub seed: u64,
+    /// Have TCP and UDP receivers check the data against the payload
+    pub verify: bool,
+    /// Core the side running these params pins its threads to (Linux only),
+    /// the Client sends the Server its own
+    pub cpu: Option<u16>,
+    /// Datagrams UDP moves per system call with sendmmsg and recvmmsg, or
+    /// the sender hands the kernel at once with `gso` (Linux only)
+    pub batch: u16,
+    /// Have the UDP sender pass `batch` datagrams as one buffer for the
+    /// kernel to split with UDP GSO (Linux only)
+    pub gso: bool,
+    /// Have the UDP receiver take datagrams coalesced by UDP GRO (Linux only)
+    pub gro: bool,
+    /// How the TCP and UDP data loops do their I/O, each side falls back to
+    /// std on its own if it cannot use io_uring
+    pub engine: Engine,
+}
+
+impl Default for NetExpParams {
+    fn default() -> Self {
+        Self {
+            host: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            port: 0,
+            side: Side::Tx,
+            parallel: 1,
+            duration: 10,
+            bitrate: 0,
+            length: 0,
+            repeat: 1,
+            omit: 0,
+            burst: 0,
+            kernel_pacing: false,
+            marking: Marking::default(),
+            multicast: None,
+            multicast_ttl: 1,
+            payload: Pattern::Zeros,
+            seed: 0,
+            verify: false,
+            cpu: None,
+            batch: 1,
+            gso: false,
+            gro: false,
+            engine: Engine::Std,
+        }
+    }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum NetExp {
     Tcp(NetExpParams),
     Udp(NetExpParams),
+    /// TCP with the data encrypted by TLS
+    Tls(NetExpParams),
+    /// One QUIC connection carrying `parallel` streams
+    Quic(NetExpParams),
+    /// Same-host Unix domain socket, `host` and `port` are unused
+    Unix(NetExpParams, UnixParams),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct UnixParams {
+    /// Filesystem path of the socket, or an abstract name prefixed with '@'
+    pub path: String,
+    pub mode: UnixMode,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum UnixMode {
+    Stream,
+    Datagram,
+}
+
+/// Settings for one side of a NetExp that are not sent to the peer
+#[derive(Clone, Default)]
+pub struct RunOptions {
+    /// Certificate presented when this side accepts TLS or QUIC connections,
+    /// a self-signed one is generated when unset
+    pub tls_identity: Option<Arc<TlsIdentity>>,
+    /// Local address and interface for this side's sockets
+    pub local: LocalBind,
+    /// Units used when printing results
+    pub format: Format,
+    /// Data a TCP or UDP sender repeats in place of the payload pattern,
+    /// such as the contents of a file
+    pub payload_data: Option<Arc<[u8]>>,
+    /// Wait for the peer to open the data connections in either direction,
+    /// as the Server does so that Clients behind NAT or a firewall can run
+    /// reverse tests
+    pub accept: bool,
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -87,51 +211,258 @@ pub enum Side {
 }
 
 impl NetExp {
-    pub fn run<F>(&self, ready_cb: F)
+    /// Short protocol name, as used by the client subcommands
+    pub fn name(&self) -> &'static str {
+        match self {
+            NetExp::Tcp(_) => "tcp",
+            NetExp::Udp(_) => "udp",
+            NetExp::Tls(_) => "tls",
+            NetExp::Quic(_) => "quic",
+            NetExp::Unix(..) => "unix",
+        }
+    }
+
+    pub fn params(&self) -> &NetExpParams {
+        match self {
+            NetExp::Tcp(params) => params,
+            NetExp::Udp(params) => params,
+            NetExp::Tls(params) => params,
+            NetExp::Quic(params) => params,
+            NetExp::Unix(params, _) => params,
+        }
+    }
+
+    pub fn params_mut(&mut self) -> &mut NetExpParams {
+        match self {
+            NetExp::Tcp(params) => params,
+            NetExp::Udp(params) => params,
+            NetExp::Tls(params) => params,
+            NetExp::Quic(params) => params,
+            NetExp::Unix(params, _) => params,
+        }
+    }
+
+    /// The same kind of NetExp with different params
+    pub fn with_params(&self, params: NetExpParams) -> NetExp {
+        match self {
+            NetExp::Tcp(_) => NetExp::Tcp(params),
+            NetExp::Udp(_) => NetExp::Udp(params),
+            NetExp::Tls(_) => NetExp::Tls(params),
+            NetExp::Quic(_) => NetExp::Quic(params),
+            NetExp::Unix(_, unix) => NetExp::Unix(params, unix.clone()),
+        }
+    }
+
+    /// Whether this side waits for the peer to open the data connections,
+    /// and so must be ready before the peer starts. Unix sockets and
+    /// multicast are always bound by the receiving side.
+    pub fn accepts(&self, options: &RunOptions) -> bool {
+        let params = self.params();
+        match self {
+            NetExp::Unix(..) => params.side == Side::Rx,
+            NetExp::Udp(_) if params.multicast.is_some() => params.side == Side::Rx,
+            _ => options.accept,
+        }
+    }
+
+    /// Run this side of the NetExp once, calling `ready_cb` once the peer
+    /// can start its side
+    pub fn run<F>(&self, options: &RunOptions, ready_cb: F) -> error::Result<Stats>
     where
         F: FnOnce(),
     {
+        let params = self.params();
+        if let Some(core) = params.cpu {
+            cpu::pin(core)?;
+            println!("Pinned to CPU {}", core);
+        }
+        // only the TCP and UDP data loops have engines, GSO and GRO only run
+        // on std, and each side falls back to std on its own
+        let engine = match self {
+            NetExp::Udp(params) if params.gso || params.gro => Engine::Std,
+            NetExp::Tcp(_) | NetExp::Udp(_) => params.engine.available(),
+            _ => Engine::Std,
+        };
+        let extended = self.with_params(NetExpParams {
+            duration: params.duration.saturating_add(params.omit),
+            engine,
+            ..params.clone()
+        });
+        let mut stats = extended.run_once(options, ready_cb)?;
+        // the totals of a side without intervals cannot be trimmed, so they
+        // cover the omitted seconds too
+        if params.omit > 0 && !stats.intervals.is_empty() {
+            stats = stats.omit(Duration::from_secs(params.omit.into()));
+        }
+        if params.engine != Engine::Std {
+            stats = stats.with_engine(engine);
+        }
+        Ok(stats)
+    }
+
+    fn run_once<F>(&self, options: &RunOptions, ready_cb: F) -> error::Result<Stats>
+    where
+        F: FnOnce(),
+    {
+        let accept = self.accepts(options);
+        let omit = Duration::from_secs(self.params().omit.into());
         match self {
-            NetExp::Tcp(params) => match params.side {
-                Side::Rx => {
+            NetExp::Tcp(params) => match (&params.side, accept) {
+                (Side::Rx, true) => {
                     let rx = tcp::TcpRx::new(params.clone());
-                    let rx = rx.bind().unwrap();
+                    let rx = rx.bind(options)?;
                     ready_cb();
-                    let rx = rx.accept().unwrap();
-                    match rx.run() {
-                        Ok(stats) => println!("{stats}"),
-                        Err(e) => eprintln!("Error running TCP Rx: {}", e.message),
-                    }
+                    let rx = rx.accept()?;
+                    cpu::measure(omit, || rx.run(options))
+                }
+                (Side::Rx, false) => {
+                    let rx = tcp::TcpRx::new(params.clone());
+                    let rx = rx.connect(options)?;
+                    ready_cb();
+                    cpu::measure(omit, || rx.run(options))
                 }
-                Side::Tx => {
+                (Side::Tx, true) => {
                     let tx = tcp::TcpTx::new(params.clone());
-                    let tx = tx.init().unwrap();
+                    let tx = tx.bind(options)?;
                     ready_cb();
-                    match tx.run() {
-                        Ok(stats) => println!("{stats}"),
-                        Err(e) => eprintln!("Error running TCP Tx: {}", e.message),
-                    }
+                    let tx = tx.accept()?;
+                    cpu::measure(omit, || tx.run(options))
+                }
+                (Side::Tx, false) => {
+                    let tx = tcp::TcpTx::new(params.clone());
+                    let tx = tx.init(options)?;
+                    ready_cb();
+                    cpu::measure(omit, || tx.run(options))
                 }
             },
-            NetExp::Udp(params) => match params.side {
-                Side::Rx => {
+            NetExp::Udp(params) => match (&params.side, accept) {
+                (Side::Rx, true) => {
+                    let rx = udp::UdpRx::new(params.clone());
+                    let rx = rx.bind(options)?;
+                    ready_cb();
+                    cpu::measure(omit, || rx.run(options))
+                }
+                (Side::Rx, false) => {
                     let rx = udp::UdpRx::new(params.clone());
-                    let rx = rx.bind().unwrap();
+                    let rx = rx.connect(options)?;
+                    ready_cb();
+                    cpu::measure(omit, || rx.run(options))
+                }
+                (Side::Tx, true) => {
+                    let tx = udp::UdpTx::new(params.clone());
+                    let tx = tx.bind(options)?;
                     ready_cb();
-                    rx.run().unwrap();
+                    let tx = tx.accept()?;
+                    cpu::measure(omit, || tx.run(options))
                 }
-                Side::Tx => {
+                (Side::Tx, false) => {
                     let tx = udp::UdpTx::new(params.clone());
-                    let tx = tx.init().unwrap();
+                    let tx = tx.init(options)?;
+                    ready_cb();
+                    cpu::measure(omit, || tx.run(options))
+                }
+            },
+            NetExp::Tls(params) => match (&params.side, accept) {
+                (Side::Rx, true) => {
+                    let rx = tls::TlsRx::new(params.clone());
+                    let rx = rx.bind(options)?;
+                    ready_cb();
+                    let rx = rx.accept()?;
+                    cpu::measure(omit, || rx.run(options))
+                }
+                (Side::Rx, false) => {
+                    let rx = tls::TlsRx::new(params.clone());
+                    let rx = rx.connect(options)?;
+                    ready_cb();
+                    cpu::measure(omit, || rx.run(options))
+                }
+                (Side::Tx, true) => {
+                    let tx = tls::TlsTx::new(params.clone());
+                    let tx = tx.bind(options)?;
+                    ready_cb();
+                    let tx = tx.accept()?;
+                    cpu::measure(omit, || tx.run(options))
+                }
+                (Side::Tx, false) => {
+                    let tx = tls::TlsTx::new(params.clone());
+                    let tx = tx.init(options)?;
+                    ready_cb();
+                    cpu::measure(omit, || tx.run(options))
+                }
+            },
+            NetExp::Quic(params) => match (&params.side, accept) {
+                (Side::Rx, true) => {
+                    let rx = quic::QuicRx::new(params.clone());
+                    let rx = rx.bind(options)?;
+                    ready_cb();
+                    let rx = rx.accept()?;
+                    cpu::measure(omit, || rx.run(options))
+                }
+                (Side::Rx, false) => {
+                    let rx = quic::QuicRx::new(params.clone());
+                    let rx = rx.connect(options)?;
+                    ready_cb();
+                    cpu::measure(omit, || rx.run(options))
+                }
+                (Side::Tx, true) => {
+                    let tx = quic::QuicTx::new(params.clone());
+                    let tx = tx.bind(options)?;
                     ready_cb();
-                    tx.run().unwrap();
+                    let tx = tx.accept()?;
+                    cpu::measure(omit, || tx.run(options))
+                }
+                (Side::Tx, false) => {
+                    let tx = quic::QuicTx::new(params.clone());
+                    let tx = tx.init(options)?;
+                    ready_cb();
+                    cpu::measure(omit, || tx.run(options))
+                }
+            },
+            NetExp::Unix(params, unix) => match (&params.side, &unix.mode) {
+                (Side::Rx, UnixMode::Stream) => {
+                    let rx = unix::UnixStreamRx::new(params.clone(), unix.clone());
+                    let rx = rx.bind()?;
+                    ready_cb();
+                    let rx = rx.accept()?;
+                    cpu::measure(omit, || rx.run(options))
+                }
+                (Side::Tx, UnixMode::Stream) => {
+                    let tx = unix::UnixStreamTx::new(params.clone(), unix.clone());
+                    let tx = tx.init()?;
+                    ready_cb();
+                    cpu::measure(omit, || tx.run(options))
+                }
+                (Side::Rx, UnixMode::Datagram) => {
+                    let rx = unix::UnixDatagramRx::new(params.clone(), unix.clone());
+                    let rx = rx.bind()?;
+                    ready_cb();
+                    cpu::measure(omit, || rx.run(options))
+                }
+                (Side::Tx, UnixMode::Datagram) => {
+                    let tx = unix::UnixDatagramTx::new(params.clone(), unix.clone());
+                    let tx = tx.init()?;
+                    ready_cb();
+                    cpu::measure(omit, || tx.run(options))
                 }
             },
         }
     }
 
-    pub const fn serialized_size() -> usize {
-        25
+    /// Write the NetExp to `writer` prefixed with its length
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> error::Result<()> {
+        let bytes = self.serialize();
+        writer.write_all(&(bytes.len() as u16).to_be_bytes())?;
+        writer.write_all(&bytes)?;
+        Ok(())
+    }
+
+    /// Read a NetExp written by [`NetExp::write_to`]
+    pub fn read_from<R: Read>(reader: &mut R) -> error::Result<Self> {
+        let mut len = [0; 2];
+        reader.read_exact(&mut len)?;
+        let mut buf = vec![0; u16::from_be_bytes(len) as usize];
+        reader.read_exact(&mut buf)?;
+        Self::deserialize(&buf)
     }
 
     pub fn serialize(&self) -> Bytes {
@@ -142,7 +473,10 @@ impl NetExp {
         // 1 byte for side
         // 2 bytes for parallel
         // 2 bytes for duration
-        let mut bytes = BytesMut::with_capacity(Self::serialized_size());
+        // Unix only: 1 byte for mode, 2 bytes for path length, path
+        // Options that differ from their defaults, each as 1 byte tag,
+        // 2 bytes length, value
+        let mut bytes = BytesMut::with_capacity(25);
         let params = match self {
             NetExp::Tcp(params) => {
                 bytes.put_u8(0);
@@ -152,6 +486,18 @@ impl NetExp {
                 bytes.put_u8(1);
                 params
             }
+            NetExp::Tls(params) => {
+                bytes.put_u8(2);
+                params
+            }
+            NetExp::Quic(params) => {
+                bytes.put_u8(3);
+                params
+            }
+            NetExp::Unix(params, _) => {
+                bytes.put_u8(4);
+                params
+            }
         };
         match params.host {
             IpAddr::V4(ipv4addr) => {
@@ -175,43 +521,266 @@ impl NetExp {
         }
         bytes.put_u16(params.parallel);
         bytes.put_u16(params.duration);
+        if let NetExp::Unix(_, unix) = self {
+            match unix.mode {
+                UnixMode::Stream => bytes.put_u8(0),
+                UnixMode::Datagram => bytes.put_u8(1),
+            }
+            bytes.put_u16(unix.path.len() as u16);
+            bytes.put_slice(unix.path.as_bytes());
+        }
+        if params.bitrate != 0 {
+            bytes.put_u8(OPT_BITRATE);
+            bytes.put_u16(8);
+            bytes.put_u64(params.bitrate);
+        }
+        if params.length != 0 {
+            bytes.put_u8(OPT_LENGTH);
+            bytes.put_u16(4);
+            bytes.put_u32(params.length);
+        }
+        if params.repeat != 1 {
+            bytes.put_u8(OPT_REPEAT);
+            bytes.put_u16(2);
+            bytes.put_u16(params.repeat);
+        }
+        if params.omit != 0 {
+            bytes.put_u8(OPT_OMIT);
+            bytes.put_u16(2);
+            bytes.put_u16(params.omit);
+        }
+        if params.burst != 0 {
+            bytes.put_u8(OPT_BURST);
+            bytes.put_u16(4);
+            bytes.put_u32(params.burst);
+        }
+        if params.kernel_pacing {
+            bytes.put_u8(OPT_KERNEL_PACING);
+            bytes.put_u16(1);
+            bytes.put_u8(1);
+        }
+        if params.marking.tos != 0 {
+            bytes.put_u8(OPT_TOS);
+            bytes.put_u16(1);
+            bytes.put_u8(params.marking.tos);
+        }
+        if params.marking.flow_label != 0 {
+            bytes.put_u8(OPT_FLOW_LABEL);
+            bytes.put_u16(4);
+            bytes.put_u32(params.marking.flow_label);
+        }
+        if params.marking.priority != 0 {
+            bytes.put_u8(OPT_PRIORITY);
+            bytes.put_u16(4);
+            bytes.put_u32(params.marking.priority);
+        }
+        if params.marking.mark != 0 {
+            bytes.put_u8(OPT_MARK);
+            bytes.put_u16(4);
+            bytes.put_u32(params.marking.mark);
+        }
+        match params.multicast {
+            Some(IpAddr::V4(group)) => {
+                bytes.put_u8(OPT_MULTICAST);
+                bytes.put_u16(4);
+                bytes.put_slice(&group.octets());
+            }
+            Some(IpAddr::V6(group)) => {
+                bytes.put_u8(OPT_MULTICAST);
+                bytes.put_u16(16);
+                bytes.put_slice(&group.octets());
+            }
+            None => {}
+        }
+        if params.multicast_ttl != 1 {
+            bytes.put_u8(OPT_MULTICAST_TTL);
+            bytes.put_u16(1);
+            bytes.put_u8(params.multicast_ttl);
+        }
+        if params.payload != Pattern::Zeros {
+            bytes.put_u8(OPT_PAYLOAD);
+            bytes.put_u16(9);
+            bytes.put_u8(match params.payload {
+                Pattern::Zeros => 0,
+                Pattern::Random => 1,
+                Pattern::Incompressible => 2,
+            });
+            bytes.put_u64(params.seed);
+        }
+        if params.verify {
+            bytes.put_u8(OPT_VERIFY);
+            bytes.put_u16(1);
+            bytes.put_u8(1);
+        }
+        if params.marking.buffer != 0 {
+            bytes.put_u8(OPT_BUFFER);
+            bytes.put_u16(4);
+            bytes.put_u32(params.marking.buffer);
+        }
+        if let Some(cpu) = params.cpu {
+            bytes.put_u8(OPT_CPU);
+            bytes.put_u16(2);
+            bytes.put_u16(cpu);
+        }
+        if params.batch != 1 {
+            bytes.put_u8(OPT_BATCH);
+            bytes.put_u16(2);
+            bytes.put_u16(params.batch);
+        }
+        if params.gso {
+            bytes.put_u8(OPT_GSO);
+            bytes.put_u16(1);
+            bytes.put_u8(1);
+        }
+        if params.gro {
+            bytes.put_u8(OPT_GRO);
+            bytes.put_u16(1);
+            bytes.put_u8(1);
+        }
+        if params.engine != Engine::Std {
+            bytes.put_u8(OPT_ENGINE);
+            bytes.put_u16(1);
+            bytes.put_u8(match params.engine {
+                Engine::Std => 0,
+                Engine::IoUring => 1,
+            });
+        }
 
         bytes.freeze()
     }
 
     pub fn deserialize(mut bytes: &[u8]) -> error::Result<Self> {
         // first byte tells us which enum variant to use
-        let variant = bytes.get_u8();
-        let host = match bytes.get_u8() {
+        // the peer picks the length of the frame, so every field may be
+        // missing
+        let variant = bytes.try_get_u8()?;
+        let host = match bytes.try_get_u8()? {
             0 => {
-                bytes.advance(12);
-                let host = bytes.get_u32();
+                // the address follows 12 bytes of padding
+                let host = bytes.try_get_u128()? as u32;
                 IpAddr::V4(Ipv4Addr::from_bits(host))
             }
             1 => {
-                let host = bytes.get_u128();
+                let host = bytes.try_get_u128()?;
                 IpAddr::V6(Ipv6Addr::from_bits(host))
             }
             _ => return Err(error::Error::new("Invalid host")),
         };
-        let port = bytes.get_u16();
-        let side = match bytes.get_u8() {
+        let port = bytes.try_get_u16()?;
+        let side = match bytes.try_get_u8()? {
             0 => Side::Rx,
             1 => Side::Tx,
             _ => return Err(error::Error::new("Invalid side")),
         };
-        let parallel = bytes.get_u16();
-        let duration = bytes.get_u16();
-        let params = NetExpParams {
+        let parallel = bytes.try_get_u16()?;
+        let duration = bytes.try_get_u16()?;
+        let mut params = NetExpParams {
             host,
             port,
             side,
             parallel,
             duration,
+            ..Default::default()
+        };
+        let unix = match variant {
+            4 => {
+                let mode = match bytes.try_get_u8()? {
+                    0 => UnixMode::Stream,
+                    1 => UnixMode::Datagram,
+                    _ => return Err(error::Error::new("Invalid Unix socket mode")),
+                };
+                let len = bytes.try_get_u16()? as usize;
+                if bytes.remaining() < len {
+                    return Err(error::Error::new("Invalid Unix socket path"));
+                }
+                let Ok(path) = String::from_utf8(bytes[..len].to_vec()) else {
+                    return Err(error::Error::new("Invalid Unix socket path"));
+                };
+                bytes.advance(len);
+                Some(UnixParams { path, mode })
+            }
+            _ => None,
         };
-        match variant {
-            0 => Ok(NetExp::Tcp(params)),
-            1 => Ok(NetExp::Udp(params)),
+        while bytes.has_remaining() {
+            if bytes.remaining() < 3 {
+                return Err(error::Error::new("Invalid option"));
+            }
+            let tag = bytes.get_u8();
+            let len = bytes.get_u16() as usize;
+            if bytes.remaining() < len {
+                return Err(error::Error::new("Invalid option"));
+            }
+            let mut value = &bytes[..len];
+            match (tag, len) {
+                (OPT_BITRATE, 8) => params.bitrate = value.get_u64(),
+                (OPT_LENGTH, 4) => params.length = value.get_u32(),
+                (OPT_REPEAT, 2) => params.repeat = value.get_u16(),
+                (OPT_OMIT, 2) => params.omit = value.get_u16(),
+                (OPT_BURST, 4) => params.burst = value.get_u32(),
+                (OPT_KERNEL_PACING, 1) => params.kernel_pacing = value.get_u8() != 0,
+                (OPT_TOS, 1) => params.marking.tos = value.get_u8(),
+                (OPT_FLOW_LABEL, 4) => params.marking.flow_label = value.get_u32(),
+                (OPT_PRIORITY, 4) => params.marking.priority = value.get_u32(),
+                (OPT_MARK, 4) => params.marking.mark = value.get_u32(),
+                (OPT_MULTICAST, 4) => {
+                    params.multicast = Some(IpAddr::V4(Ipv4Addr::from_bits(value.get_u32())))
+                }
+                (OPT_MULTICAST, 16) => {
+                    params.multicast = Some(IpAddr::V6(Ipv6Addr::from_bits(value.get_u128())))
+                }
+                (OPT_MULTICAST_TTL, 1) => params.multicast_ttl = value.get_u8(),
+                (OPT_PAYLOAD, 9) => {
+                    params.payload = match value.get_u8() {
+                        0 => Pattern::Zeros,
+                        1 => Pattern::Random,
+                        2 => Pattern::Incompressible,
+                        _ => return Err(error::Error::new("Invalid payload pattern")),
+                    };
+                    params.seed = value.get_u64();
+                }
+                (OPT_VERIFY, 1) => params.verify = value.get_u8() != 0,
+                (OPT_CPU, 2) => params.cpu = Some(value.get_u16()),
+                (OPT_BUFFER, 4) => params.marking.buffer = value.get_u32(),
+                (OPT_BATCH, 2) => params.batch = value.get_u16(),
+                (OPT_GSO, 1) => params.gso = value.get_u8() != 0,
+                (OPT_GRO, 1) => params.gro = value.get_u8() != 0,
+                (OPT_ENGINE, 1) => {
+                    params.engine = match value.get_u8() {
+                        0 => Engine::Std,
+                        1 => Engine::IoUring,
+                        _ => return Err(error::Error::new("Invalid engine")),
+                    }
+                }
+                (
+                    OPT_BITRATE | OPT_LENGTH | OPT_REPEAT | OPT_OMIT | OPT_BURST
+                    | OPT_KERNEL_PACING | OPT_TOS | OPT_FLOW_LABEL | OPT_PRIORITY | OPT_MARK
+                    | OPT_MULTICAST | OPT_MULTICAST_TTL | OPT_PAYLOAD | OPT_VERIFY | OPT_CPU
+                    | OPT_BUFFER | OPT_BATCH | OPT_GSO | OPT_GRO | OPT_ENGINE,
+                    _,
+                ) => {
+                    return Err(error::Error::new("Invalid option"));
+                }
+                // options from newer peers are ignored
+                _ => {}
+            }
+            bytes.advance(len);
+        }
+        // the peer picks these and each side allocates by them
+        if !(1..=MAX_PARALLEL).contains(&params.parallel) {
+            return Err(error::Error::new("Invalid parallel"));
+        }
+        if params.length > MAX_LENGTH {
+            return Err(error::Error::new("Invalid length"));
+        }
+        if !(1..=MAX_BATCH).contains(&params.batch) {
+            return Err(error::Error::new("Invalid batch"));
+        }
+        match (variant, unix) {
+            (0, _) => Ok(NetExp::Tcp(params)),
+            (1, _) => Ok(NetExp::Udp(params)),
+            (2, _) => Ok(NetExp::Tls(params)),
+            (3, _) => Ok(NetExp::Quic(params)),
+            (4, Some(unix)) => Ok(NetExp::Unix(params, unix)),
             _ => Err(error::Error::new("Invalid NetExp")),
         }
     }
@@ -241,6 +810,7 @@ mod test {
             side: Side::Rx,
             parallel: 4,
             duration: 30,
+            ..Default::default()
         });
         let net_exp = NetExp::deserialize(&in_bytes).expect("Failed to deserialize NetExp");
         assert_eq!(net_exp, expected);
@@ -266,6 +836,7 @@ mod test {
             side: Side::Tx,
             parallel: 0x0104,
             duration: 30,
+            ..Default::default()
         });
         let net_exp = NetExp::deserialize(&in_bytes).expect("Failed to deserialize NetExp");
         assert_eq!(net_exp, expected);
@@ -273,6 +844,272 @@ mod test {
         assert_eq!(out_bytes, in_bytes);
     }
 
+    #[test]
+    fn test_serialize_and_deserialize_options() {
+        let in_bytes: Bytes = vec![
+            1, // UDP
+            0, // IPv4
+            0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 127, 0, 0, 1, // host + padding
+            0, 80, // port
+            1,  // Tx
+            0, 1, // parallel
+            0, 10, // duration
+            1, 0, 8, 0, 0, 0, 0, 5, 245, 225, 0, // bitrate 100 Mbit/s
+            2, 0, 4, 0, 0, 5, 220, // length 1500
+            3, 0, 2, 0, 5, // repeat 5
+            4, 0, 2, 0, 2, // omit 2
+            5, 0, 4, 0, 0, 250, 0, // burst 64000
+            6, 0, 1, 1, // kernel pacing
+            7, 0, 1, 184, // TOS, DSCP 46
+            8, 0, 4, 0, 1, 226, 64, // flow label 123456
+            9, 0, 4, 0, 0, 0, 6, // priority 6
+            10, 0, 4, 0, 0, 0, 42, // mark 42
+            11, 0, 4, 239, 1, 2, 3, // multicast group
+            12, 0, 1, 8, // multicast TTL
+            13, 0, 9, 2, 0, 0, 0, 0, 0, 0, 1, 0, // incompressible, seed 256
+            14, 0, 1, 1, // verify
+            16, 0, 4, 0, 4, 0, 0, // buffer 256 KiB
+            15, 0, 2, 0, 3, // CPU 3
+            17, 0, 2, 0, 32, // batch 32
+            18, 0, 1, 1, // GSO
+            19, 0, 1, 1, // GRO
+            20, 0, 1, 1, // io_uring engine
+        ]
+        .into();
+        let expected = NetExp::Udp(NetExpParams {
+            host: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port: 80,
+            bitrate: 100_000_000,
+            length: 1500,
+            repeat: 5,
+            omit: 2,
+            burst: 64_000,
+            kernel_pacing: true,
+            marking: Marking {
+                tos: 184,
+                flow_label: 123_456,
+                priority: 6,
+                mark: 42,
+                buffer: 256 * 1024,
+            },
+            multicast: Some(IpAddr::V4(Ipv4Addr::new(239, 1, 2, 3))),
+            multicast_ttl: 8,
+            payload: Pattern::Incompressible,
+            seed: 256,
+            verify: true,
+            cpu: Some(3),
+            batch: 32,
+            gso: true,
+            gro: true,
+            engine: Engine::IoUring,
+            ..Default::default()
+        });
+        let net_exp = NetExp::deserialize(&in_bytes).expect("Failed to deserialize NetExp");
+        assert_eq!(net_exp, expected);
+        let out_bytes = net_exp.serialize();
+        assert_eq!(out_bytes, in_bytes);
+    }
+
+    #[test]
+    fn test_deserialize_ignores_unknown_options() {
+        let in_bytes: Bytes = vec![
+            0, // TCP
+            0, // IPv4
+            0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 127, 0, 0, 1, // host + padding
+            0, 80, // port
+            1,  // Tx
+            0, 1, // parallel
+            0, 10, // duration
+            255, 0, 2, 1, 2, // unknown option
+        ]
+        .into();
+        let net_exp = NetExp::deserialize(&in_bytes).expect("Failed to deserialize NetExp");
+        assert_eq!(
+            net_exp,
+            NetExp::Tcp(NetExpParams {
+                host: IpAddr::V4(Ipv4Addr::LOCALHOST),
+                port: 80,
+                ..Default::default()
+            })
+        );
+    }
+
+    #[test]
+    fn test_serialize_and_deserialize_tls_ipv4_tx() {
+        let in_bytes: Bytes = vec![
+            2, // TLS
+            0, // IPv4
+            0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 10, 0, 0, 1, // host + padding
+            17, 81, // port 4433
+            1,  // Tx
+            0, 1, // parallel
+            0, 10, // duration
+        ]
+        .into();
+        let expected = NetExp::Tls(NetExpParams {
+            host: IpAddr::V4(Ipv4Addr::from_str("10.0.0.1").unwrap()),
+            port: 4433,
+            side: Side::Tx,
+            parallel: 1,
+            duration: 10,
+            ..Default::default()
+        });
+        let net_exp = NetExp::deserialize(&in_bytes).expect("Failed to deserialize NetExp");
+        assert_eq!(net_exp, expected);
+        let out_bytes = net_exp.serialize();
+        assert_eq!(out_bytes, in_bytes);
+    }
+
+    #[test]
+    fn test_serialize_and_deserialize_quic_ipv6_rx() {
+        let in_bytes: Bytes = vec![
+            3, // QUIC
+            1, // IPv6
+            0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, // host
+            1, 187, // port 443
+            0,   // Rx
+            0, 8, // parallel
+            0, 5, // duration
+        ]
+        .into();
+        let expected = NetExp::Quic(NetExpParams {
+            host: IpAddr::V6(Ipv6Addr::LOCALHOST),
+            port: 443,
+            side: Side::Rx,
+            parallel: 8,
+            duration: 5,
+            ..Default::default()
+        });
+        let net_exp = NetExp::deserialize(&in_bytes).expect("Failed to deserialize NetExp");
+        assert_eq!(net_exp, expected);
+        let out_bytes = net_exp.serialize();
+        assert_eq!(out_bytes, in_bytes);
+    }
+
+    #[test]
+    fn test_serialize_and_deserialize_unix_datagram() {
+        let in_bytes: Bytes = vec![
+            4, // Unix
+            0, // IPv4
+            0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 127, 0, 0, 1, // host + padding
+            0, 80, // port
+            1,  // Tx
+            0, 1, // parallel
+            0, 3, // duration
+            1, // Datagram
+            0, 6, // path length
+            b'@', b'p', b'e', b'r', b'f', b'y', // path
+        ]
+        .into();
+        let expected = NetExp::Unix(
+            NetExpParams {
+                host: IpAddr::V4(Ipv4Addr::LOCALHOST),
+                port: 80,
+                side: Side::Tx,
+                parallel: 1,
+                duration: 3,
+                ..Default::default()
+            },
+            UnixParams {
+                path: "@perfy".to_string(),
+                mode: UnixMode::Datagram,
+            },
+        );
+        let net_exp = NetExp::deserialize(&in_bytes).expect("Failed to deserialize NetExp");
+        assert_eq!(net_exp, expected);
+        let out_bytes = net_exp.serialize();
+        assert_eq!(out_bytes, in_bytes);
+    }
+
+    #[test]
+    fn test_write_to_and_read_from() {
+        let net_exp = NetExp::Tcp(NetExpParams {
+            host: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port: 5201,
+            side: Side::Rx,
+            parallel: 2,
+            duration: 10,
+            ..Default::default()
+        });
+        let mut buf = Vec::new();
+        net_exp.write_to(&mut buf).unwrap();
+        assert_eq!(&buf[..2], &[0, 25]);
+        let read = NetExp::read_from(&mut buf.as_slice()).unwrap();
+        assert_eq!(read, net_exp);
+    }
+
+    #[test]
+    fn test_bad_deserialize_unix_path() {
+        let in_bytes: Bytes = vec![
+            4, // Unix
+            0, // IPv4
+            0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 127, 0, 0, 1, // host + padding
+            0, 80, // port
+            1,  // Tx
+            0, 1, // parallel
+            0, 3, // duration
+            0, // Stream
+            0, 10, // path length
+            b'/', b't', b'm', b'p', // NOT ENOUGH BYTES
+        ]
+        .into();
+        assert!(NetExp::deserialize(&in_bytes).is_err());
+    }
+
+    #[test]
+    fn test_deserialize_rejects_sizes_out_of_range() {
+        let header = [
+            0, // TCP
+            0, // IPv4
+            0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 127, 0, 0, 1, // host + padding
+            0, 80, // port
+            1,  // Tx
+            0, 1, // parallel
+            0, 10, // duration
+        ];
+        let with = |option: &[u8]| [&header[..], option].concat();
+        assert!(NetExp::deserialize(&with(&[17, 0, 2, 4, 0])).is_ok()); // batch 1024
+        assert!(NetExp::deserialize(&with(&[17, 0, 2, 0, 0])).is_err()); // batch 0
+        assert!(NetExp::deserialize(&with(&[17, 0, 2, 4, 1])).is_err()); // batch 1025
+        assert!(NetExp::deserialize(&with(&[2, 0, 4, 0, 16, 0, 0])).is_ok()); // length 1 MiB
+        assert!(NetExp::deserialize(&with(&[2, 0, 4, 0, 16, 0, 1])).is_err());
+        let mut parallel = header;
+        parallel[21..23].copy_from_slice(&0u16.to_be_bytes());
+        assert!(NetExp::deserialize(&parallel).is_err());
+        parallel[21..23].copy_from_slice(&(MAX_PARALLEL + 1).to_be_bytes());
+        assert!(NetExp::deserialize(&parallel).is_err());
+    }
+
+    #[test]
+    fn test_deserialize_truncated() {
+        assert!(NetExp::deserialize(&[]).is_err());
+        assert!(NetExp::deserialize(&[0, 0]).is_err());
+        assert!(NetExp::deserialize(&[0, 1, 4]).is_err());
+        assert!(NetExp::deserialize(&[3, 0, 0]).is_err());
+        let tcp = NetExp::Tcp(NetExpParams::default()).serialize();
+        for len in 0..tcp.len() {
+            assert!(NetExp::deserialize(&tcp[..len]).is_err());
+        }
+        let unix = NetExp::Unix(
+            NetExpParams::default(),
+            UnixParams {
+                path: "/tmp/perfy.sock".to_string(),
+                mode: UnixMode::Stream,
+            },
+        )
+        .serialize();
+        for len in 0..unix.len() {
+            assert!(NetExp::deserialize(&unix[..len]).is_err());
+        }
+    }
+
+    #[test]
+    fn test_read_from_truncated() {
+        for frame in [&[0, 0][..], &[0, 3, 0, 0, 0], &[0, 1, 4]] {
+            assert!(NetExp::read_from(&mut &frame[..]).is_err());
+        }
+    }
+
     #[test]
     #[should_panic]
     fn test_bad_deserialize_variant() {
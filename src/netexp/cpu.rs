@@ -0,0 +1,171 @@
+use std::fs;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::stats::{Cpu, Stats};
+use crate::error;
+
+/// CPU time used by this process and the host up to one moment
+pub struct CpuSample {
+    wall: Instant,
+    user: Duration,
+    system: Duration,
+    /// Busy and total clock ticks of every core, Linux only
+    host: Option<(u64, u64)>,
+}
+
+impl CpuSample {
+    pub fn now() -> CpuSample {
+        let (user, system) = process_times();
+        CpuSample {
+            wall: Instant::now(),
+            user,
+            system,
+            host: fs::read_to_string("/proc/stat")
+                .ok()
+                .and_then(|stat| host_ticks(&stat)),
+        }
+    }
+
+    /// Utilization between `start` and this sample
+    pub fn since(&self, start: &CpuSample) -> Cpu {
+        let wall = (self.wall - start.wall).as_secs_f64();
+        let percent = |used: Duration| {
+            if wall > 0f64 {
+                used.as_secs_f64() * 100f64 / wall
+            } else {
+                0f64
+            }
+        };
+        let host = match (start.host, self.host) {
+            (Some((busy0, total0)), Some((busy1, total1))) if total1 > total0 => {
+                Some(busy1.saturating_sub(busy0) as f64 * 100f64 / (total1 - total0) as f64)
+            }
+            _ => None,
+        };
+        Cpu {
+            user: percent(self.user.saturating_sub(start.user)),
+            system: percent(self.system.saturating_sub(start.system)),
+            host,
+        }
+    }
+}
+
+/// Run the data phase of a test, sampling CPU from the end of its first
+/// `omit` to its end the same way the intervals of the omitted seconds are
+/// dropped
+pub fn measure<F>(omit: Duration, run: F) -> error::Result<Stats>
+where
+    F: FnOnce() -> error::Result<Stats>,
+{
+    let start = CpuSample::now();
+    let (tx, rx) = mpsc::channel();
+    if !omit.is_zero() {
+        thread::spawn(move || {
+            thread::sleep(omit);
+            let _ = tx.send(CpuSample::now());
+        });
+    }
+    let stats = run()?;
+    let end = CpuSample::now();
+    // a phase shorter than the omitted seconds is sampled whole
+    let start = rx.try_recv().unwrap_or(start);
+    Ok(stats.with_cpu(end.since(&start)))
+}
+
+/// User and system time of this process with getrusage
+fn process_times() -> (Duration, Duration) {
+    // SAFETY: rusage is plain data, all zeroes is a valid value
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    // SAFETY: usage is a valid, writable rusage
+    if unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut usage) } != 0 {
+        return (Duration::ZERO, Duration::ZERO);
+    }
+    let duration = |tv: libc::timeval| {
+        Duration::from_secs(tv.tv_sec as u64) + Duration::from_micros(tv.tv_usec as u64)
+    };
+    (duration(usage.ru_utime), duration(usage.ru_stime))
+}
+
+/// Busy and total ticks from the aggregate "cpu" line of /proc/stat, idle
+/// and iowait count as not busy
+fn host_ticks(stat: &str) -> Option<(u64, u64)> {
+    let line = stat.lines().find(|line| line.starts_with("cpu "))?;
+    let ticks: Vec<u64> = line
+        .split_whitespace()
+        .skip(1)
+        .map(|n| n.parse().ok())
+        .collect::<Option<_>>()?;
+    // guest time is already counted in user time
+    let total: u64 = ticks.iter().take(8).sum();
+    let idle = ticks.get(3)? + ticks.get(4).unwrap_or(&0);
+    Some((total - idle, total))
+}
+
+/// Pin the calling thread to `core`, threads it spawns from then on such
+/// as those of each stream inherit it
+#[cfg(target_os = "linux")]
+pub fn pin(core: u16) -> error::Result<()> {
+    // SAFETY: cpu_set_t is plain data, all zeroes is an empty set
+    let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    if core as usize >= std::mem::size_of::<libc::cpu_set_t>() * 8 {
+        return Err(error::Error::new(&format!("Invalid CPU {}", core)));
+    }
+    // SAFETY: core is within the set
+    unsafe { libc::CPU_SET(core as usize, &mut set) };
+    // SAFETY: set is a valid cpu_set_t of the given size, 0 is this thread
+    if unsafe { libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) } != 0 {
+        return Err(error::Error::new(&format!(
+            "Failed pinning to CPU {}: {}",
+            core,
+            std::io::Error::last_os_error()
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn pin(_core: u16) -> error::Result<()> {
+    Err(error::Error::new("CPU affinity is only supported on Linux"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_host_ticks() {
+        let stat = "cpu  100 5 50 800 20 3 2 0 0 0\ncpu0 50 2 25 400 10 1 1 0 0 0\n";
+        assert_eq!(host_ticks(stat), Some((160, 980)));
+        assert_eq!(host_ticks("intr 1 2 3\n"), None);
+    }
+
+    #[test]
+    fn test_since() {
+        let start = CpuSample {
+            wall: Instant::now(),
+            user: Duration::from_millis(100),
+            system: Duration::from_millis(50),
+            host: Some((1_000, 10_000)),
+        };
+        let end = CpuSample {
+            wall: start.wall + Duration::from_secs(2),
+            user: Duration::from_millis(1_100),
+            system: Duration::from_millis(550),
+            host: Some((1_500, 11_000)),
+        };
+        let cpu = end.since(&start);
+        assert_eq!(cpu.user, 50f64);
+        assert_eq!(cpu.system, 25f64);
+        assert_eq!(cpu.host, Some(50f64));
+    }
+
+    #[test]
+    fn test_measure() {
+        // a phase shorter than the omitted seconds is still sampled
+        let stats = measure(Duration::from_secs(60), || Ok(Stats::new())).unwrap();
+        assert!(stats.cpu.is_some());
+        assert!(measure(Duration::ZERO, || Err(error::Error::new("failed"))).is_err());
+    }
+}
@@ -0,0 +1,197 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::{self, Read};
+use std::os::fd::AsRawFd;
+
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+use super::uring;
+
+/// How the TCP and UDP data loops do their I/O
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Engine {
+    /// Blocking calls on std sockets
+    #[default]
+    Std,
+    /// io_uring with registered send buffers and multishot receives, on
+    /// Linux builds with the io-uring feature
+    IoUring,
+}
+
+impl Engine {
+    /// The engine this host runs when asked for this one, falling back to
+    /// std when io_uring is not built in or the kernel refuses it
+    pub fn available(self) -> Engine {
+        match self {
+            Engine::Std => Engine::Std,
+            Engine::IoUring => match probe() {
+                Ok(()) => Engine::IoUring,
+                Err(e) => {
+                    eprintln!("io_uring is unavailable, falling back to std: {}", e);
+                    Engine::Std
+                }
+            },
+        }
+    }
+}
+
+impl fmt::Display for Engine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Engine::Std => write!(f, "std"),
+            Engine::IoUring => write!(f, "io_uring"),
+        }
+    }
+}
+
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+fn probe() -> io::Result<()> {
+    uring::probe()
+}
+
+#[cfg(not(all(feature = "io-uring", target_os = "linux")))]
+fn probe() -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "perfy was built without the io-uring feature",
+    ))
+}
+
+/// A send buffer, written with std calls or registered with an io_uring
+pub enum Sink {
+    Std(Vec<u8>),
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    IoUring(Box<uring::FixedWriter>),
+}
+
+impl Sink {
+    /// Buffer of `len` bytes for sending on `socket`, with up to `depth`
+    /// sends in flight
+    pub fn new(
+        engine: Engine,
+        socket: &impl AsRawFd,
+        len: usize,
+        depth: usize,
+    ) -> io::Result<Sink> {
+        match engine {
+            #[cfg(all(feature = "io-uring", target_os = "linux"))]
+            Engine::IoUring => Ok(Sink::IoUring(Box::new(uring::FixedWriter::new(
+                socket.as_raw_fd(),
+                len,
+                depth,
+            )?))),
+            _ => {
+                let _ = (socket, depth);
+                Ok(Sink::Std(vec![0; len]))
+            }
+        }
+    }
+
+    pub fn buf_mut(&mut self) -> &mut [u8] {
+        match self {
+            Sink::Std(buf) => buf,
+            #[cfg(all(feature = "io-uring", target_os = "linux"))]
+            Sink::IoUring(writer) => writer.buf_mut(),
+        }
+    }
+
+    /// Write the whole buffer to a stream
+    pub fn write_all(&mut self, stream: &mut impl io::Write) -> io::Result<()> {
+        match self {
+            Sink::Std(buf) => stream.write_all(buf),
+            #[cfg(all(feature = "io-uring", target_os = "linux"))]
+            Sink::IoUring(writer) => writer.write_all(),
+        }
+    }
+}
+
+/// Where received data lands, read with std calls or by a multishot
+/// receive into buffers provided to an io_uring
+pub enum Source {
+    Std(Vec<u8>),
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    IoUring(Box<uring::MultishotReader>),
+}
+
+impl Source {
+    /// `count` buffers of `len` bytes for receiving from `socket`, one for
+    /// std calls
+    pub fn new(
+        engine: Engine,
+        socket: &impl AsRawFd,
+        len: usize,
+        count: u16,
+    ) -> io::Result<Source> {
+        match engine {
+            #[cfg(all(feature = "io-uring", target_os = "linux"))]
+            Engine::IoUring => Ok(Source::IoUring(Box::new(uring::MultishotReader::new(
+                socket.as_raw_fd(),
+                len,
+                count,
+            )?))),
+            _ => {
+                let _ = (socket, count);
+                Ok(Source::Std(vec![0; len]))
+            }
+        }
+    }
+
+    /// Wait for data from a stream and hand each chunk of it to `f`,
+    /// returning false once the peer has closed it
+    pub fn read(&mut self, stream: &mut impl Read, mut f: impl FnMut(&[u8])) -> io::Result<bool> {
+        match self {
+            Source::Std(buf) => {
+                let n = stream.read(buf)?;
+                if n > 0 {
+                    f(&buf[..n]);
+                }
+                Ok(n > 0)
+            }
+            #[cfg(all(feature = "io-uring", target_os = "linux"))]
+            Source::IoUring(reader) => {
+                reader.recv(None)?;
+                reader.chunks().for_each(f);
+                Ok(!reader.eof())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+    use std::net::{Ipv4Addr, TcpListener, TcpStream};
+    use std::thread;
+
+    use super::*;
+
+    /// Whatever the engine falls back to, a stream arrives whole and in
+    /// order
+    #[test]
+    fn test_stream_roundtrip() {
+        let engine = Engine::IoUring.available();
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let writer = thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            let mut sink = Sink::new(engine, &stream, 1000, 1).unwrap();
+            for i in 0..100u8 {
+                sink.buf_mut().fill(i);
+                sink.write_all(&mut stream).unwrap();
+            }
+            stream.flush().unwrap();
+        });
+
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut source = Source::new(engine, &stream, 300, 4).unwrap();
+        let mut received = Vec::new();
+        while source
+            .read(&mut stream, |chunk| received.extend_from_slice(chunk))
+            .unwrap()
+        {}
+        writer.join().unwrap();
+
+        let expected: Vec<u8> = (0..100u8).flat_map(|i| [i; 1000]).collect();
+        assert_eq!(received, expected);
+    }
+}
@@ -0,0 +1,528 @@
+use std::io;
+use std::net::UdpSocket;
+
+use super::engine::{Engine, Sink, Source};
+use super::sock;
+use super::stats::DatagramIo;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+use super::uring;
+use crate::error;
+
+/// Most segments Linux accepts in one UDP GSO send
+const MAX_GSO_SEGMENTS: usize = 64;
+
+/// Largest UDP payload, which a GSO send may not exceed in total
+const MAX_UDP_PAYLOAD: usize = 65_507;
+
+/// Size of each receive buffer, enough for a datagram of any size or a
+/// GRO coalesced one
+const RECV_BUF_SIZE: usize = u16::MAX as usize;
+
+/// Buffers provided to an io_uring multishot receive when `batch` is
+/// smaller
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+const RING_BUFFERS: usize = 64;
+
+/// Room for the IP_TOS or IPV6_TCLASS and UDP_GRO messages of one
+/// datagram, aligned for cmsghdr
+type Control = [u64; 8];
+
+/// Datagrams to hand the kernel per send when each is `length` bytes,
+/// `batch` at most, capped for GSO by what one send may carry
+pub fn send_batch(batch: u16, gso: bool, length: usize) -> usize {
+    let batch = (batch as usize).max(1);
+    if gso {
+        batch
+            .min(MAX_GSO_SEGMENTS)
+            .min(MAX_UDP_PAYLOAD / length)
+            .max(1)
+    } else {
+        batch
+    }
+}
+
+/// Fail unless this platform can batch as asked
+pub fn check(batch: u16, offload: bool) -> error::Result<()> {
+    if cfg!(target_os = "linux") || (batch <= 1 && !offload) {
+        Ok(())
+    } else {
+        Err(error::Error::new(
+            "Batched UDP I/O, GSO and GRO are only supported on Linux",
+        ))
+    }
+}
+
+/// Sends datagrams of one stream with send, sendmmsg, one sendmsg
+/// segmented by UDP GSO, or io_uring writes of a registered buffer,
+/// counting the system calls made
+pub struct Sender {
+    batch: usize,
+    gso: bool,
+    sink: Sink,
+    io: DatagramIo,
+}
+
+impl Sender {
+    /// Sender of up to `batch` datagrams of `length` bytes at a time
+    pub fn new(
+        socket: &UdpSocket,
+        batch: usize,
+        gso: bool,
+        engine: Engine,
+        length: usize,
+    ) -> io::Result<Sender> {
+        let sink = Sink::new(engine, socket, length * batch, batch)?;
+        let method = match (&sink, batch, gso) {
+            #[cfg(all(feature = "io-uring", target_os = "linux"))]
+            (Sink::IoUring(_), _, _) => "io_uring write_fixed",
+            (_, _, true) => "sendmsg with GSO",
+            (_, 1, false) => "send",
+            (_, _, false) => "sendmmsg",
+        };
+        Ok(Sender {
+            batch,
+            gso,
+            sink,
+            io: DatagramIo {
+                method: method.to_string(),
+                batch: batch as u16,
+                ..Default::default()
+            },
+        })
+    }
+
+    /// The `length` byte datagrams the next send takes
+    pub fn buf_mut(&mut self) -> &mut [u8] {
+        self.sink.buf_mut()
+    }
+
+    /// Send the `length` byte datagrams packed into the buffer, returning
+    /// how many the kernel took
+    pub fn send(&mut self, socket: &UdpSocket, length: usize) -> io::Result<usize> {
+        self.io.calls += 1;
+        let sent = match &mut self.sink {
+            Sink::Std(buf) => match (self.batch, self.gso) {
+                (_, true) => send_gso(socket, buf, length)?,
+                (1, false) => {
+                    socket.send(buf)?;
+                    1
+                }
+                (_, false) => send_mmsg(socket, buf, length)?,
+            },
+            #[cfg(all(feature = "io-uring", target_os = "linux"))]
+            Sink::IoUring(writer) => writer.send_datagrams(length)?,
+        };
+        self.io.datagrams += sent as u64;
+        Ok(sent)
+    }
+
+    pub fn finish(self) -> DatagramIo {
+        self.io
+    }
+}
+
+/// Receives datagrams with recvmsg, recvmmsg of up to `batch` buffers
+/// that UDP GRO may have coalesced, or an io_uring multishot receive,
+/// counting the system calls made
+pub struct Receiver {
+    batch: usize,
+    gro: bool,
+    source: Source,
+    /// How long a receive waits for the first datagram
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    timeout: Option<std::time::Duration>,
+    /// Length, TOS and GRO segment size of each buffer filled by the last
+    /// call
+    received: Vec<(usize, Option<u8>, Option<usize>)>,
+    io: DatagramIo,
+}
+
+impl Receiver {
+    /// Receiver waiting as long as the socket's read timeout
+    pub fn new(
+        socket: &UdpSocket,
+        batch: u16,
+        gro: bool,
+        engine: Engine,
+    ) -> error::Result<Receiver> {
+        let batch = (batch as usize).max(1);
+        if gro {
+            sock::set_udp_gro(socket)?;
+        }
+        let source = match engine {
+            #[cfg(all(feature = "io-uring", target_os = "linux"))]
+            Engine::IoUring => {
+                use std::os::fd::AsRawFd;
+
+                Source::IoUring(Box::new(uring::MultishotReader::datagrams(
+                    socket.as_raw_fd(),
+                    RECV_BUF_SIZE,
+                    std::mem::size_of::<Control>(),
+                    batch.max(RING_BUFFERS) as u16,
+                )?))
+            }
+            _ => Source::Std(vec![0; batch * RECV_BUF_SIZE]),
+        };
+        let method = match (&source, batch, gro) {
+            #[cfg(all(feature = "io-uring", target_os = "linux"))]
+            (Source::IoUring(_), _, _) => "io_uring multishot recvmsg",
+            (_, 1, false) => "recvmsg",
+            (_, _, false) => "recvmmsg",
+            (_, _, true) => "recvmmsg with GRO",
+        };
+        Ok(Receiver {
+            batch,
+            gro,
+            source,
+            #[cfg(all(feature = "io-uring", target_os = "linux"))]
+            timeout: socket.read_timeout()?,
+            received: Vec::with_capacity(batch),
+            io: DatagramIo {
+                method: method.to_string(),
+                batch: batch as u16,
+                ..Default::default()
+            },
+        })
+    }
+
+    /// Wait for datagrams, then take as many as are queued
+    pub fn recv(&mut self, socket: &UdpSocket) -> io::Result<()> {
+        self.received.clear();
+        match &mut self.source {
+            Source::Std(bufs) if self.batch == 1 && !self.gro => {
+                let (n, tos) = sock::recv_tos(socket, bufs)?;
+                self.received.push((n, tos, None));
+            }
+            Source::Std(bufs) => recv_mmsg(socket, bufs, &mut self.received)?,
+            #[cfg(all(feature = "io-uring", target_os = "linux"))]
+            Source::IoUring(reader) => reader.recv(self.timeout)?,
+        }
+        self.io.calls += 1;
+        Ok(())
+    }
+
+    /// Every datagram the last call received, with the TOS it arrived with,
+    /// GRO coalesced buffers split back into their segments
+    pub fn datagrams(&mut self) -> Vec<(&[u8], Option<u8>)> {
+        let mut datagrams = Vec::new();
+        match &self.source {
+            Source::Std(bufs) => {
+                for (i, (n, tos, segment)) in self.received.iter().enumerate() {
+                    let buf = &bufs[i * RECV_BUF_SIZE..i * RECV_BUF_SIZE + n];
+                    match segment {
+                        Some(segment) if *segment > 0 => {
+                            datagrams.extend(buf.chunks(*segment).map(|d| (d, *tos)))
+                        }
+                        _ => datagrams.push((buf, *tos)),
+                    }
+                }
+            }
+            #[cfg(all(feature = "io-uring", target_os = "linux"))]
+            Source::IoUring(reader) => datagrams.extend(
+                reader
+                    .messages()
+                    .map(|(payload, control)| (payload, control_tos(control))),
+            ),
+        }
+        self.io.datagrams += datagrams.len() as u64;
+        datagrams
+    }
+
+    pub fn finish(self) -> DatagramIo {
+        self.io
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn send_mmsg(socket: &UdpSocket, buf: &[u8], length: usize) -> io::Result<usize> {
+    use std::os::fd::AsRawFd;
+
+    let mut iovs: Vec<libc::iovec> = buf
+        .chunks(length)
+        .map(|datagram| libc::iovec {
+            iov_base: datagram.as_ptr() as *mut libc::c_void,
+            iov_len: datagram.len(),
+        })
+        .collect();
+    let mut msgs: Vec<libc::mmsghdr> = iovs
+        .iter_mut()
+        .map(|iov| {
+            // SAFETY: mmsghdr is plain data, all zeroes is a valid value
+            let mut msg: libc::mmsghdr = unsafe { std::mem::zeroed() };
+            msg.msg_hdr.msg_iov = iov;
+            msg.msg_hdr.msg_iovlen = 1;
+            msg
+        })
+        .collect();
+    // SAFETY: every message points to an iovec of buf, which outlive the
+    // call, and the kernel only reads them
+    let n = unsafe {
+        libc::sendmmsg(
+            socket.as_raw_fd(),
+            msgs.as_mut_ptr(),
+            msgs.len() as libc::c_uint,
+            0,
+        )
+    };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(n as usize)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn send_mmsg(_socket: &UdpSocket, _buf: &[u8], _length: usize) -> io::Result<usize> {
+    Err(io::Error::from(io::ErrorKind::Unsupported))
+}
+
+/// Send `buf` as one buffer that the kernel splits into `length` byte
+/// datagrams
+#[cfg(target_os = "linux")]
+fn send_gso(socket: &UdpSocket, buf: &[u8], length: usize) -> io::Result<usize> {
+    use std::os::fd::AsRawFd;
+
+    let mut iov = libc::iovec {
+        iov_base: buf.as_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+    let mut control: Control = [0; 8];
+    // SAFETY: msghdr is plain data, all zeroes is a valid value
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    // SAFETY: CMSG_SPACE only computes a size
+    msg.msg_controllen = unsafe { libc::CMSG_SPACE(std::mem::size_of::<u16>() as u32) } as _;
+    // SAFETY: control has room for one cmsghdr carrying a u16
+    unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_UDP;
+        (*cmsg).cmsg_type = libc::UDP_SEGMENT;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(std::mem::size_of::<u16>() as u32) as _;
+        (libc::CMSG_DATA(cmsg) as *mut u16).write_unaligned(length as u16);
+    }
+    // SAFETY: msg points to buffers that outlive the call
+    let n = unsafe { libc::sendmsg(socket.as_raw_fd(), &msg, 0) };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(buf.len().div_ceil(length))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn send_gso(_socket: &UdpSocket, _buf: &[u8], _length: usize) -> io::Result<usize> {
+    Err(io::Error::from(io::ErrorKind::Unsupported))
+}
+
+/// Wait for the first datagram, then take whatever else is queued, up to
+/// one per `RECV_BUF_SIZE` of `bufs`
+#[cfg(target_os = "linux")]
+fn recv_mmsg(
+    socket: &UdpSocket,
+    bufs: &mut [u8],
+    received: &mut Vec<(usize, Option<u8>, Option<usize>)>,
+) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let mut iovs: Vec<libc::iovec> = bufs
+        .chunks_mut(RECV_BUF_SIZE)
+        .map(|buf| libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        })
+        .collect();
+    let mut controls: Vec<Control> = vec![[0; 8]; iovs.len()];
+    let mut msgs: Vec<libc::mmsghdr> = iovs
+        .iter_mut()
+        .zip(controls.iter_mut())
+        .map(|(iov, control)| {
+            // SAFETY: mmsghdr is plain data, all zeroes is a valid value
+            let mut msg: libc::mmsghdr = unsafe { std::mem::zeroed() };
+            msg.msg_hdr.msg_iov = iov;
+            msg.msg_hdr.msg_iovlen = 1;
+            msg.msg_hdr.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+            msg.msg_hdr.msg_controllen = std::mem::size_of::<Control>() as _;
+            msg
+        })
+        .collect();
+    // SAFETY: every message points to a buffer and control block that
+    // outlive the call. The socket's read timeout bounds the wait for the
+    // first datagram, the rest are only taken if already queued.
+    let n = unsafe {
+        libc::recvmmsg(
+            socket.as_raw_fd(),
+            msgs.as_mut_ptr(),
+            msgs.len() as libc::c_uint,
+            libc::MSG_WAITFORONE,
+            std::ptr::null_mut(),
+        )
+    };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    for msg in &msgs[..n as usize] {
+        let (tos, segment) = parse_control(&msg.msg_hdr);
+        received.push((msg.msg_len as usize, tos, segment));
+    }
+    Ok(())
+}
+
+/// TOS or traffic class and GRO segment size in the control messages of a
+/// received message
+#[cfg(target_os = "linux")]
+fn parse_control(hdr: &libc::msghdr) -> (Option<u8>, Option<usize>) {
+    let (mut tos, mut segment) = (None, None);
+    // SAFETY: msg_controllen covers what the kernel filled in, the CMSG
+    // macros stay within it
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(hdr);
+        while !cmsg.is_null() {
+            let data = libc::CMSG_DATA(cmsg);
+            match ((*cmsg).cmsg_level, (*cmsg).cmsg_type) {
+                (libc::IPPROTO_IP, libc::IP_TOS) => tos = Some(*data),
+                (libc::IPPROTO_IPV6, libc::IPV6_TCLASS) => {
+                    tos = Some((data as *const libc::c_int).read_unaligned() as u8)
+                }
+                (libc::SOL_UDP, libc::UDP_GRO) => {
+                    segment = Some((data as *const libc::c_int).read_unaligned() as usize)
+                }
+                _ => {}
+            }
+            cmsg = libc::CMSG_NXTHDR(hdr, cmsg);
+        }
+    }
+    (tos, segment)
+}
+
+/// TOS or traffic class in control messages a multishot recvmsg received
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+fn control_tos(control: &[u8]) -> Option<u8> {
+    // SAFETY: msghdr is plain data, all zeroes is a valid value
+    let mut hdr: libc::msghdr = unsafe { std::mem::zeroed() };
+    hdr.msg_control = control.as_ptr() as *mut libc::c_void;
+    hdr.msg_controllen = control.len() as _;
+    parse_control(&hdr).0
+}
+
+#[cfg(not(target_os = "linux"))]
+fn recv_mmsg(
+    _socket: &UdpSocket,
+    _bufs: &mut [u8],
+    _received: &mut Vec<(usize, Option<u8>, Option<usize>)>,
+) -> io::Result<()> {
+    Err(io::Error::from(io::ErrorKind::Unsupported))
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::Ipv4Addr;
+    use std::time::Duration;
+
+    use super::*;
+
+    fn pair() -> (UdpSocket, UdpSocket) {
+        let rx = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        rx.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        let tx = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        tx.connect(rx.local_addr().unwrap()).unwrap();
+        (tx, rx)
+    }
+
+    #[test]
+    fn test_send_batch() {
+        assert_eq!(send_batch(1, false, 1400), 1);
+        assert_eq!(send_batch(128, false, 1400), 128);
+        assert_eq!(send_batch(128, true, 100), MAX_GSO_SEGMENTS);
+        assert_eq!(send_batch(64, true, 1400), 46);
+        assert_eq!(send_batch(8, true, 65_000), 1);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_mmsg_roundtrip() {
+        let (tx, rx) = pair();
+        let buf: Vec<u8> = (0..4u8).flat_map(|i| [i; 100]).collect();
+        let mut sender = Sender::new(&tx, 4, false, Engine::Std, 100).unwrap();
+        sender.buf_mut().copy_from_slice(&buf);
+        assert_eq!(sender.send(&tx, 100).unwrap(), 4);
+
+        let mut receiver = Receiver::new(&rx, 8, false, Engine::Std).unwrap();
+        let mut lengths = Vec::new();
+        while lengths.len() < 4 {
+            receiver.recv(&rx).unwrap();
+            lengths.extend(receiver.datagrams().iter().map(|(d, _)| (d.len(), d[0])));
+        }
+        assert_eq!(lengths, [(100, 0), (100, 1), (100, 2), (100, 3)]);
+        let io = sender.finish();
+        assert_eq!(
+            (io.method.as_str(), io.calls, io.datagrams),
+            ("sendmmsg", 1, 4)
+        );
+        assert_eq!(receiver.finish().datagrams, 4);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_gso_and_gro() {
+        let (tx, rx) = pair();
+        let mut receiver = Receiver::new(&rx, 4, true, Engine::Std).unwrap();
+        let buf: Vec<u8> = (0..4u8).flat_map(|i| [i; 100]).collect();
+        let mut sender = Sender::new(&tx, 4, true, Engine::Std, 100).unwrap();
+        sender.buf_mut().copy_from_slice(&buf);
+        assert_eq!(sender.send(&tx, 100).unwrap(), 4);
+
+        // however the kernel delivers them, the segments come out whole
+        let mut datagrams = Vec::new();
+        while datagrams.len() < 4 {
+            receiver.recv(&rx).unwrap();
+            datagrams.extend(receiver.datagrams().iter().map(|(d, _)| (d.len(), d[0])));
+        }
+        assert_eq!(datagrams, [(100, 0), (100, 1), (100, 2), (100, 3)]);
+        assert_eq!(sender.finish().method, "sendmsg with GSO");
+    }
+
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    #[test]
+    fn test_io_uring_roundtrip() {
+        let (tx, rx) = pair();
+        let engine = Engine::IoUring.available();
+        let mut receiver = Receiver::new(&rx, 2, false, engine).unwrap();
+        let mut sender = Sender::new(&tx, 4, false, engine, 100).unwrap();
+        let mut datagrams = Vec::new();
+        for round in 0..20u8 {
+            for (i, datagram) in sender.buf_mut().chunks_mut(100).enumerate() {
+                datagram.fill(round * 4 + i as u8);
+            }
+            assert_eq!(sender.send(&tx, 100).unwrap(), 4);
+            // more than the receiver has buffers for, unless it keeps
+            // handing them back
+            while datagrams.len() < (round as usize + 1) * 4 {
+                receiver.recv(&rx).unwrap();
+                datagrams.extend(receiver.datagrams().iter().map(|(d, _)| (d.len(), d[0])));
+            }
+        }
+        let expected: Vec<_> = (0..80u8).map(|i| (100, i)).collect();
+        assert_eq!(datagrams, expected);
+        assert_eq!(sender.finish().datagrams, 80);
+    }
+
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    #[test]
+    fn test_io_uring_keeps_tos() {
+        let (tx, rx) = pair();
+        socket2::SockRef::from(&tx).set_tos_v4(0xb8).unwrap();
+        sock::set_recv_tos(&rx).unwrap();
+        let engine = Engine::IoUring.available();
+        let mut receiver = Receiver::new(&rx, 1, false, engine).unwrap();
+        let mut sender = Sender::new(&tx, 1, false, engine, 100).unwrap();
+        assert_eq!(sender.send(&tx, 100).unwrap(), 1);
+
+        let mut received = Vec::new();
+        while received.is_empty() {
+            receiver.recv(&rx).unwrap();
+            received.extend(receiver.datagrams().iter().map(|(d, tos)| (d.len(), *tos)));
+        }
+        assert_eq!(received, [(100, Some(0xb8))]);
+    }
+}
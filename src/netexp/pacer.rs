@@ -0,0 +1,105 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Data a sender may burst when no burst size is given, enough to make up
+/// for sleeps that overshoot by a timer tick or two
+const DEFAULT_BURST_TIME: Duration = Duration::from_millis(10);
+
+/// Token bucket that holds a sender to a target bitrate, letting it send
+/// up to `burst` bytes back to back after being idle
+pub struct Pacer {
+    /// Bytes per second
+    rate: f64,
+    /// Bucket size in bytes
+    burst: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl Pacer {
+    /// Pacer for `bitrate` bits per second, None if 0 (unlimited). A
+    /// `burst` of 0 picks [`DEFAULT_BURST_TIME`] worth of data, and the
+    /// burst is raised to at least `length` so every write can go through.
+    pub fn new(bitrate: u64, burst: u32, length: usize) -> Option<Pacer> {
+        if bitrate == 0 {
+            return None;
+        }
+        let rate = bitrate as f64 / 8f64;
+        let burst = match burst {
+            0 => rate * DEFAULT_BURST_TIME.as_secs_f64(),
+            burst => burst as f64,
+        }
+        .max(length as f64);
+        Some(Pacer {
+            rate,
+            burst,
+            tokens: burst,
+            last: Instant::now(),
+        })
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.last = now;
+    }
+
+    /// How long to wait before `n` bytes may be sent, taking the tokens
+    fn reserve(&mut self, n: usize, now: Instant) -> Duration {
+        self.refill(now);
+        self.tokens -= n as f64;
+        if self.tokens >= 0f64 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+
+    /// Block until `n` bytes may be sent
+    pub fn wait(&mut self, n: usize) {
+        let delay = self.reserve(n, Instant::now());
+        if !delay.is_zero() {
+            thread::sleep(delay);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_unlimited() {
+        assert!(Pacer::new(0, 0, 1_000).is_none());
+    }
+
+    fn millis(d: Duration) -> f64 {
+        (d.as_secs_f64() * 1_000f64).round()
+    }
+
+    #[test]
+    fn test_burst_then_steady_rate() {
+        // 8 kbit/s is 1000 bytes per second, with room for two writes
+        let mut pacer = Pacer::new(8_000, 200, 100).unwrap();
+        let start = pacer.last;
+        assert_eq!(pacer.reserve(100, start), Duration::ZERO);
+        assert_eq!(pacer.reserve(100, start), Duration::ZERO);
+        assert_eq!(millis(pacer.reserve(100, start)), 100f64);
+        // the caller slept through its delay, the next write waits 100ms more
+        let later = start + Duration::from_millis(100);
+        assert_eq!(millis(pacer.reserve(100, later)), 100f64);
+    }
+
+    #[test]
+    fn test_default_burst() {
+        // 10 ms at 8 Mbit/s
+        let pacer = Pacer::new(8_000_000, 0, 1_400).unwrap();
+        assert_eq!(pacer.burst.round(), 10_000f64);
+    }
+
+    #[test]
+    fn test_burst_is_at_least_one_write() {
+        let mut pacer = Pacer::new(8_000, 0, 500).unwrap();
+        assert_eq!(pacer.reserve(500, pacer.last), Duration::ZERO);
+    }
+}
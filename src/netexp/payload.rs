@@ -0,0 +1,205 @@
+use std::sync::Arc;
+
+use super::stats::Integrity;
+use super::{NetExpParams, RunOptions};
+
+/// Period of the repeating patterns in bytes
+const BLOCK_SIZE: usize = 64 * 1024;
+
+/// What senders fill their writes and datagrams with
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Pattern {
+    #[default]
+    Zeros,
+    /// A block of pseudo-random bytes from the seed, repeated
+    Random,
+    /// Pseudo-random bytes from the seed that never repeat, so nothing on
+    /// the path can compress or deduplicate them
+    Incompressible,
+}
+
+/// The bytes of a stream as a function of their offset into it, so a
+/// receiver can regenerate what the sender wrote
+#[derive(Clone)]
+pub struct Payload {
+    source: Source,
+}
+
+#[derive(Clone)]
+enum Source {
+    /// Repeated for the whole stream
+    Block(Arc<[u8]>),
+    /// Generated from the seed
+    Stream(u64),
+}
+
+impl Payload {
+    pub fn new(pattern: Pattern, seed: u64) -> Payload {
+        let source = match pattern {
+            Pattern::Zeros => Source::Block(vec![0; BLOCK_SIZE].into()),
+            Pattern::Random => {
+                let mut block = vec![0; BLOCK_SIZE];
+                fill_stream(seed, 0, &mut block);
+                Source::Block(block.into())
+            }
+            Pattern::Incompressible => Source::Stream(seed),
+        };
+        Payload { source }
+    }
+
+    /// Payload that repeats the contents of a file
+    pub fn from_bytes(bytes: Arc<[u8]>) -> Payload {
+        Payload {
+            source: Source::Block(bytes),
+        }
+    }
+
+    /// What the sending side of `params` writes, None for zeros which
+    /// need no filling in
+    pub fn for_sender(params: &NetExpParams, options: &RunOptions) -> Option<Payload> {
+        match (&options.payload_data, params.payload) {
+            (Some(data), _) => Some(Payload::from_bytes(data.clone())),
+            (None, Pattern::Zeros) => None,
+            (None, pattern) => Some(Payload::new(pattern, params.seed)),
+        }
+    }
+
+    /// Checks the receiving side of `params` makes, if it verifies
+    pub fn verifier(params: &NetExpParams, stream: u16) -> Option<Verifier> {
+        params
+            .verify
+            .then(|| Verifier::new(stream, Payload::new(params.payload, params.seed)))
+    }
+
+    /// Fill `buf` with the bytes of the stream starting at `offset`
+    pub fn fill(&self, offset: u64, buf: &mut [u8]) {
+        match &self.source {
+            Source::Block(block) => {
+                let mut pos = (offset % block.len() as u64) as usize;
+                let mut filled = 0;
+                while filled < buf.len() {
+                    let n = (block.len() - pos).min(buf.len() - filled);
+                    buf[filled..filled + n].copy_from_slice(&block[pos..pos + n]);
+                    filled += n;
+                    pos = 0;
+                }
+            }
+            Source::Stream(seed) => fill_stream(*seed, offset, buf),
+        }
+    }
+}
+
+/// SplitMix64 output for the `i`th word of the stream seeded with `seed`
+fn word(seed: u64, i: u64) -> u64 {
+    let mut z = seed.wrapping_add(i.wrapping_add(1).wrapping_mul(0x9e37_79b9_7f4a_7c15));
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+fn fill_stream(seed: u64, offset: u64, buf: &mut [u8]) {
+    let mut i = offset / 8;
+    let mut skip = (offset % 8) as usize;
+    let mut filled = 0;
+    while filled < buf.len() {
+        let bytes = word(seed, i).to_le_bytes();
+        let n = (8 - skip).min(buf.len() - filled);
+        buf[filled..filled + n].copy_from_slice(&bytes[skip..skip + n]);
+        filled += n;
+        skip = 0;
+        i += 1;
+    }
+}
+
+/// Compares what a receiver reads with what the sender should have written
+pub struct Verifier {
+    stream: u16,
+    payload: Payload,
+    expected: Vec<u8>,
+    integrity: Integrity,
+}
+
+impl Verifier {
+    pub fn new(stream: u16, payload: Payload) -> Verifier {
+        Verifier {
+            stream,
+            payload,
+            expected: Vec::new(),
+            integrity: Integrity::default(),
+        }
+    }
+
+    /// Check `data` read at `offset` into the stream
+    pub fn check(&mut self, offset: u64, data: &[u8]) {
+        self.expected.resize(data.len(), 0);
+        self.payload.fill(offset, &mut self.expected);
+        self.integrity.checked += data.len() as u64;
+        if data == self.expected.as_slice() {
+            return;
+        }
+        let mut corrupted = data
+            .iter()
+            .zip(&self.expected)
+            .enumerate()
+            .filter(|(_, (a, b))| a != b)
+            .map(|(i, _)| i);
+        if let Some(first) = corrupted.next() {
+            self.integrity.corrupted += 1 + corrupted.count() as u64;
+            self.integrity
+                .first_corrupted
+                .get_or_insert((self.stream, offset + first as u64));
+        }
+    }
+
+    pub fn finish(self) -> Integrity {
+        self.integrity
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_fill_is_the_same_at_any_offset() {
+        for pattern in [Pattern::Zeros, Pattern::Random, Pattern::Incompressible] {
+            let payload = Payload::new(pattern, 42);
+            let mut whole = vec![0; BLOCK_SIZE * 2 + 100];
+            payload.fill(0, &mut whole);
+            let mut part = vec![0; BLOCK_SIZE + 7];
+            payload.fill(BLOCK_SIZE as u64 - 3, &mut part);
+            assert_eq!(part, whole[BLOCK_SIZE - 3..BLOCK_SIZE * 2 + 4]);
+        }
+    }
+
+    #[test]
+    fn test_patterns() {
+        let mut buf = vec![0; BLOCK_SIZE * 2];
+        Payload::new(Pattern::Random, 1).fill(0, &mut buf);
+        assert_eq!(buf[..BLOCK_SIZE], buf[BLOCK_SIZE..]);
+        assert!(buf.iter().any(|b| *b != 0));
+        Payload::new(Pattern::Incompressible, 1).fill(0, &mut buf);
+        assert_ne!(buf[..BLOCK_SIZE], buf[BLOCK_SIZE..]);
+
+        let payload = Payload::from_bytes(b"abc".as_slice().into());
+        let mut buf = [0; 5];
+        payload.fill(2, &mut buf);
+        assert_eq!(&buf, b"cabca");
+    }
+
+    #[test]
+    fn test_verifier_counts_corrupted_bytes() {
+        let payload = Payload::new(Pattern::Incompressible, 7);
+        let mut data = vec![0; 100];
+        payload.fill(1_000, &mut data);
+        let mut verifier = Verifier::new(2, payload);
+        verifier.check(1_000, &data);
+        data[10] ^= 1;
+        data[20] ^= 1;
+        verifier.check(1_000, &data);
+        let integrity = verifier.finish();
+        assert_eq!(integrity.checked, 200);
+        assert_eq!(integrity.corrupted, 2);
+        assert_eq!(integrity.first_corrupted, Some((2, 1_010)));
+    }
+}
@@ -0,0 +1,347 @@
+use bytes::Bytes;
+use quinn::crypto::rustls::{QuicClientConfig, QuicServerConfig};
+use std::fmt::Display;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time;
+use tokio::runtime::Runtime;
+use tokio::task::JoinSet;
+
+use super::stats::Recorder;
+use super::tcp::{self, StreamResult};
+use super::tls;
+use super::{Marking, NetExpParams, RunOptions, Stats};
+use crate::error;
+
+/// Application protocol negotiated during the QUIC handshake
+const ALPN: &[u8] = b"perfy";
+
+/// Size of the buffer each receiving stream reads into
+const RECV_BUF_SIZE: usize = 64 * 1024;
+
+fn quic_error(e: impl Display) -> error::Error {
+    error::Error::new(&format!("QUIC: {}", e))
+}
+
+fn runtime() -> error::Result<Runtime> {
+    Ok(tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?)
+}
+
+/// Uninitialized
+pub struct Uninit {}
+/// Bound to port
+pub struct Bound {
+    runtime: Runtime,
+    endpoint: quinn::Endpoint,
+}
+/// Handshake complete
+pub struct Ready {
+    runtime: Runtime,
+    endpoint: quinn::Endpoint,
+    connection: quinn::Connection,
+    handshake: time::Duration,
+}
+
+/// Lets the peer open as many streams as the NetExp runs
+fn transport(params: &NetExpParams) -> Arc<quinn::TransportConfig> {
+    let mut transport = quinn::TransportConfig::default();
+    transport.max_concurrent_uni_streams(params.parallel.into());
+    Arc::new(transport)
+}
+
+/// Listen for a QUIC connection on the NetExp's port
+fn listen(name: &str, params: &NetExpParams, options: &RunOptions) -> error::Result<Bound> {
+    let mut crypto = tls::server_config(options.tls_identity.as_deref())?;
+    crypto.alpn_protocols = vec![ALPN.to_vec()];
+    let crypto = QuicServerConfig::try_from(crypto).map_err(quic_error)?;
+    let mut config = quinn::ServerConfig::with_crypto(Arc::new(crypto));
+    config.transport_config(transport(params));
+
+    let runtime = runtime()?;
+    let socket = options
+        .local
+        .udp_bind(params.port, params.host, &Marking::default())?;
+    let local_addr = socket.local_addr()?;
+    let endpoint = {
+        let _guard = runtime.enter();
+        quinn::Endpoint::new(
+            quinn::EndpointConfig::default(),
+            Some(config),
+            socket,
+            Arc::new(quinn::TokioRuntime),
+        )?
+    };
+    println!("Started {} listener on {}", name, local_addr);
+    Ok(Bound { runtime, endpoint })
+}
+
+/// Accept one connection and complete its handshake
+fn accept(bound: Bound) -> error::Result<Ready> {
+    let Bound { runtime, endpoint } = bound;
+    let (connection, handshake) = runtime.block_on(async {
+        let incoming = endpoint
+            .accept()
+            .await
+            .ok_or_else(|| error::Error::new("QUIC endpoint closed"))?;
+        let start = time::Instant::now();
+        let connection = incoming.await.map_err(quic_error)?;
+        Ok::<_, error::Error>((connection, start.elapsed()))
+    })?;
+    Ok(Ready {
+        runtime,
+        endpoint,
+        connection,
+        handshake,
+    })
+}
+
+/// Connect to the NetExp's host and complete the handshake
+fn connect(name: &str, params: &NetExpParams, options: &RunOptions) -> error::Result<Ready> {
+    let mut crypto = tls::client_config()?;
+    crypto.alpn_protocols = vec![ALPN.to_vec()];
+    let crypto = QuicClientConfig::try_from(crypto).map_err(quic_error)?;
+    let mut config = quinn::ClientConfig::new(Arc::new(crypto));
+    config.transport_config(transport(params));
+
+    let runtime = runtime()?;
+    let socket = options
+        .local
+        .udp_bind(0, params.host, &Marking::default())?;
+    let addr = SocketAddr::new(params.host, params.port);
+    println!("{} connecting to {}", name, addr);
+    let (endpoint, connection, handshake) = runtime.block_on(async {
+        let endpoint = quinn::Endpoint::new(
+            quinn::EndpointConfig::default(),
+            None,
+            socket,
+            Arc::new(quinn::TokioRuntime),
+        )?;
+        let start = time::Instant::now();
+        let connection = endpoint
+            .connect_with(config, addr, "perfy")
+            .map_err(quic_error)?
+            .await
+            .map_err(quic_error)?;
+        Ok::<_, error::Error>((endpoint, connection, start.elapsed()))
+    })?;
+    Ok(Ready {
+        runtime,
+        endpoint,
+        connection,
+        handshake,
+    })
+}
+
+pub struct QuicRx<State = Uninit> {
+    params: NetExpParams,
+    state: State,
+}
+
+impl QuicRx<Uninit> {
+    pub fn new(params: NetExpParams) -> QuicRx<Uninit> {
+        Self {
+            params,
+            state: Uninit {},
+        }
+    }
+
+    pub fn bind(self, options: &RunOptions) -> error::Result<QuicRx<Bound>> {
+        let state = listen("QuicRx", &self.params, options)?;
+        Ok(QuicRx {
+            params: self.params,
+            state,
+        })
+    }
+
+    /// Connect to a sender that is listening for the connection
+    pub fn connect(self, options: &RunOptions) -> error::Result<QuicRx<Ready>> {
+        let state = connect("QuicRx", &self.params, options)?;
+        Ok(QuicRx {
+            params: self.params,
+            state,
+        })
+    }
+}
+
+impl QuicRx<Bound> {
+    pub fn accept(self) -> error::Result<QuicRx<Ready>> {
+        Ok(QuicRx {
+            params: self.params,
+            state: accept(self.state)?,
+        })
+    }
+}
+
+impl QuicRx<Ready> {
+    pub fn run(self, options: &RunOptions) -> error::Result<Stats> {
+        let Ready {
+            runtime,
+            endpoint,
+            connection,
+            handshake,
+        } = self.state;
+        let peer_addr = connection.remote_address();
+        println!(
+            "Running QUIC recv {}:{} for {} seconds with {} streams...",
+            peer_addr.ip(),
+            peer_addr.port(),
+            self.params.duration,
+            self.params.parallel,
+        );
+
+        let started = time::SystemTime::now();
+        let format = options.format;
+        let (start, results) = runtime.block_on(async {
+            let start = time::Instant::now();
+            let mut streams = JoinSet::new();
+            for id in 0..self.params.parallel {
+                let mut stream = connection.accept_uni().await.map_err(quic_error)?;
+                streams.spawn(async move {
+                    let mut buf = vec![0; RECV_BUF_SIZE];
+                    let mut recorder = Recorder::new(id, start, format);
+                    let mut bytes = 0;
+                    while let Some(n) = stream.read(&mut buf).await.map_err(quic_error)? {
+                        bytes += n as u64;
+                        if recorder.add(n as u64) {
+                            recorder.flush(|_| {});
+                        }
+                    }
+                    Ok::<_, error::Error>(StreamResult {
+                        bytes,
+                        end: time::Instant::now(),
+                        intervals: recorder.finish(|_| {}),
+                        retransmits: None,
+                        integrity: None,
+                    })
+                });
+            }
+            let mut results = Vec::new();
+            for result in streams.join_all().await {
+                results.push(result?);
+            }
+            // let the sender close so its final acknowledgements arrive
+            connection.closed().await;
+            endpoint.wait_idle().await;
+            Ok::<_, error::Error>((start, results))
+        })?;
+
+        Ok(tcp::combine(started, start, results).with_handshake(handshake))
+    }
+}
+
+pub struct QuicTx<State = Uninit> {
+    params: NetExpParams,
+    state: State,
+}
+
+impl QuicTx<Uninit> {
+    pub fn new(params: NetExpParams) -> QuicTx<Uninit> {
+        Self {
+            params,
+            state: Uninit {},
+        }
+    }
+
+    pub fn init(self, options: &RunOptions) -> error::Result<QuicTx<Ready>> {
+        let state = connect("QuicTx", &self.params, options)?;
+        Ok(QuicTx {
+            params: self.params,
+            state,
+        })
+    }
+
+    /// Listen for a receiver to open the connection
+    pub fn bind(self, options: &RunOptions) -> error::Result<QuicTx<Bound>> {
+        let state = listen("QuicTx", &self.params, options)?;
+        Ok(QuicTx {
+            params: self.params,
+            state,
+        })
+    }
+}
+
+impl QuicTx<Bound> {
+    pub fn accept(self) -> error::Result<QuicTx<Ready>> {
+        Ok(QuicTx {
+            params: self.params,
+            state: accept(self.state)?,
+        })
+    }
+}
+
+impl QuicTx<Ready> {
+    pub fn run(self, options: &RunOptions) -> error::Result<Stats> {
+        let Ready {
+            runtime,
+            endpoint,
+            connection,
+            handshake,
+        } = self.state;
+        let peer_addr = connection.remote_address();
+        println!(
+            "Running QUIC send {}:{} for {} seconds with {} streams...",
+            peer_addr.ip(),
+            peer_addr.port(),
+            self.params.duration,
+            self.params.parallel,
+        );
+
+        let started = time::SystemTime::now();
+        let duration = time::Duration::from_secs(self.params.duration.into());
+        let length = tcp::length(&self.params);
+        let buf = Bytes::from(vec![0; length]);
+        let format = options.format;
+        let (start, results) = runtime.block_on(async {
+            let start = time::Instant::now();
+            let mut streams = JoinSet::new();
+            for id in 0..self.params.parallel {
+                let mut stream = connection.open_uni().await.map_err(quic_error)?;
+                let buf = buf.clone();
+                streams.spawn(async move {
+                    let mut recorder = Recorder::new(id, start, format);
+                    let mut bytes = 0;
+                    while start.elapsed() < duration {
+                        stream.write_chunk(buf.clone()).await.map_err(quic_error)?;
+                        bytes += length as u64;
+                        if recorder.add(length as u64) {
+                            recorder.flush(|_| {});
+                        }
+                    }
+                    stream.finish().map_err(quic_error)?;
+                    // everything written counts once the receiver has
+                    // acknowledged it
+                    stream.stopped().await.map_err(quic_error)?;
+                    Ok::<_, error::Error>(StreamResult {
+                        bytes,
+                        end: time::Instant::now(),
+                        intervals: recorder.finish(|_| {}),
+                        retransmits: None,
+                        integrity: None,
+                    })
+                });
+            }
+            let mut results = Vec::new();
+            for result in streams.join_all().await {
+                results.push(result?);
+            }
+            Ok::<_, error::Error>((start, results))
+        })?;
+
+        let stats = connection.stats();
+        connection.close(0u32.into(), b"done");
+        runtime.block_on(endpoint.wait_idle());
+
+        let packet_loss = match stats.path.sent_packets {
+            0 => 0f64,
+            sent => stats.path.lost_packets as f64 * 100f64 / sent as f64,
+        };
+
+        Ok(tcp::combine(started, start, results)
+            .with_handshake(handshake)
+            .with_packet_loss(packet_loss)
+            .with_rtt(stats.path.rtt)
+            .with_congestion_events(stats.path.congestion_events))
+    }
+}
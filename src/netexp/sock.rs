@@ -0,0 +1,559 @@
+use socket2::{Domain, Protocol, SockAddr, SockRef, Socket, Type};
+use std::io;
+use std::net::{
+    IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs, UdpSocket,
+};
+
+use crate::error;
+
+/// Which address family to prefer when resolving a hostname
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Family {
+    #[default]
+    Any,
+    V4,
+    V6,
+}
+
+impl Family {
+    fn matches(&self, addr: &IpAddr) -> bool {
+        match self {
+            Family::Any => true,
+            Family::V4 => addr.is_ipv4(),
+            Family::V6 => addr.is_ipv6(),
+        }
+    }
+}
+
+/// Resolve a hostname or IP address literal to an address of `family`
+pub fn resolve(host: &str, family: Family) -> error::Result<IpAddr> {
+    let addrs = (host, 0)
+        .to_socket_addrs()
+        .map_err(|e| error::Error::new(&format!("Failed resolving {}: {}", host, e)))?;
+    addrs
+        .map(|addr| addr.ip())
+        .find(|addr| family.matches(addr))
+        .ok_or_else(|| error::Error::new(&format!("No {:?} address found for {}", family, host)))
+}
+
+/// QoS markings and buffer sizes set on data sockets, zero leaves each at
+/// the system default
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Marking {
+    /// IPv4 TOS or IPv6 traffic class, the DSCP is the upper six bits
+    pub tos: u8,
+    /// IPv6 flow label of connected sockets, 20 bits (Linux only)
+    pub flow_label: u32,
+    /// SO_PRIORITY, which picks the queue on the sending host (Linux only)
+    pub priority: u32,
+    /// SO_MARK, used by policy routing and packet filters, needs
+    /// CAP_NET_ADMIN (Linux only)
+    pub mark: u32,
+    /// SO_SNDBUF and SO_RCVBUF in bytes, set before connecting so the TCP
+    /// window scale allows for it
+    pub buffer: u32,
+}
+
+impl Marking {
+    fn apply(&self, socket: &Socket, addr: &SocketAddr) -> error::Result<()> {
+        let failed = |name: &str, e: io::Error| {
+            error::Error::new(&format!("Failed setting {}: {}", name, e))
+        };
+        if self.tos != 0 {
+            match addr {
+                SocketAddr::V4(_) => socket.set_tos_v4(self.tos.into()),
+                SocketAddr::V6(_) => socket.set_tclass_v6(self.tos.into()),
+            }
+            .map_err(|e| failed("TOS", e))?;
+        }
+        if self.priority != 0 {
+            #[cfg(target_os = "linux")]
+            socket
+                .set_priority(self.priority)
+                .map_err(|e| failed("SO_PRIORITY", e))?;
+            #[cfg(not(target_os = "linux"))]
+            return Err(error::Error::new("SO_PRIORITY is only supported on Linux"));
+        }
+        if self.mark != 0 {
+            #[cfg(target_os = "linux")]
+            socket
+                .set_mark(self.mark)
+                .map_err(|e| failed("SO_MARK", e))?;
+            #[cfg(not(target_os = "linux"))]
+            return Err(error::Error::new("SO_MARK is only supported on Linux"));
+        }
+        if self.buffer != 0 {
+            socket
+                .set_send_buffer_size(self.buffer as usize)
+                .map_err(|e| failed("SO_SNDBUF", e))?;
+            socket
+                .set_recv_buffer_size(self.buffer as usize)
+                .map_err(|e| failed("SO_RCVBUF", e))?;
+        }
+        Ok(())
+    }
+
+    /// Set up `socket` to send the flow label, returning the address to
+    /// connect to in its place
+    fn flow(&self, socket: &Socket, peer: SocketAddr) -> error::Result<SocketAddr> {
+        if self.flow_label == 0 {
+            return Ok(peer);
+        }
+        let SocketAddr::V6(mut peer) = peer else {
+            return Err(error::Error::new("Flow labels need an IPv6 peer"));
+        };
+        lease_flow_label(socket, &peer, self.flow_label)
+            .map_err(|e| error::Error::new(&format!("Failed setting flow label: {}", e)))?;
+        // sin6_flowinfo is in network byte order
+        peer.set_flowinfo(self.flow_label.to_be());
+        Ok(peer.into())
+    }
+
+    /// Connect a bound UDP socket to `peer`, with the flow label
+    pub fn udp_connect(&self, socket: &UdpSocket, peer: SocketAddr) -> error::Result<()> {
+        let socket = SockRef::from(socket);
+        let peer = self.flow(&socket, peer)?;
+        socket.connect(&SockAddr::from(peer))?;
+        Ok(())
+    }
+}
+
+/// Take out a lease on `label` for traffic to `peer`, which Linux requires
+/// before a socket may send it
+#[cfg(target_os = "linux")]
+fn lease_flow_label(socket: &Socket, peer: &std::net::SocketAddrV6, label: u32) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    /// struct in6_flowlabel_req from linux/in6.h
+    #[repr(C)]
+    struct FlowLabelReq {
+        dst: libc::in6_addr,
+        label: u32,
+        action: u8,
+        share: u8,
+        flags: u16,
+        expires: u16,
+        linger: u16,
+        pad: u32,
+    }
+    const IPV6_FL_A_GET: u8 = 0;
+    const IPV6_FL_F_CREATE: u16 = 1;
+    const IPV6_FL_S_ANY: u8 = 255;
+
+    let req = FlowLabelReq {
+        dst: libc::in6_addr {
+            s6_addr: peer.ip().octets(),
+        },
+        label: label.to_be(),
+        action: IPV6_FL_A_GET,
+        share: IPV6_FL_S_ANY,
+        flags: IPV6_FL_F_CREATE,
+        expires: 0,
+        linger: 0,
+        pad: 0,
+    };
+    let fd = socket.as_raw_fd();
+    setsockopt(fd, libc::IPPROTO_IPV6, libc::IPV6_FLOWLABEL_MGR, &req)?;
+    setsockopt(
+        fd,
+        libc::IPPROTO_IPV6,
+        libc::IPV6_FLOWINFO_SEND,
+        &1 as &libc::c_int,
+    )
+}
+
+#[cfg(not(target_os = "linux"))]
+fn lease_flow_label(
+    _socket: &Socket,
+    _peer: &std::net::SocketAddrV6,
+    _label: u32,
+) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "flow labels are only supported on Linux",
+    ))
+}
+
+/// Send IPv4 multicast on the interface with `index`
+#[cfg(target_os = "linux")]
+fn set_multicast_if_v4(socket: &Socket, index: u32) -> error::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let mreqn = libc::ip_mreqn {
+        imr_multiaddr: libc::in_addr { s_addr: 0 },
+        imr_address: libc::in_addr { s_addr: 0 },
+        imr_ifindex: index as libc::c_int,
+    };
+    setsockopt(
+        socket.as_raw_fd(),
+        libc::IPPROTO_IP,
+        libc::IP_MULTICAST_IF,
+        &mreqn,
+    )
+    .map_err(|e| error::Error::new(&format!("Failed setting multicast interface: {}", e)))
+}
+
+/// Send IPv4 multicast on the interface with `index`
+#[cfg(not(target_os = "linux"))]
+fn set_multicast_if_v4(_socket: &Socket, _index: u32) -> error::Result<()> {
+    Err(error::Error::new(
+        "Choosing the IPv4 multicast interface is only supported on Linux",
+    ))
+}
+
+#[cfg(target_os = "linux")]
+fn setsockopt<T>(
+    fd: libc::c_int,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: &T,
+) -> io::Result<()> {
+    // SAFETY: value is a valid T of the length given
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            value as *const T as *const libc::c_void,
+            std::mem::size_of::<T>() as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Local address and interface that this side's sockets are bound to
+#[derive(Clone, Debug, Default)]
+pub struct LocalBind {
+    /// Source address, defaults to the unspecified address
+    pub addr: Option<IpAddr>,
+    /// Interface to pin sockets to with SO_BINDTODEVICE
+    pub device: Option<String>,
+    /// Interface multicast datagrams are sent and received on, defaults to
+    /// the one the routing table picks
+    pub multicast_if: Option<String>,
+}
+
+impl LocalBind {
+    /// Local address to use when talking to `peer`
+    fn addr_for(&self, peer: IpAddr) -> IpAddr {
+        match (self.addr, peer) {
+            (Some(addr), _) if !addr.is_unspecified() => addr,
+            (_, IpAddr::V4(_)) => Ipv4Addr::UNSPECIFIED.into(),
+            (_, IpAddr::V6(_)) => Ipv6Addr::UNSPECIFIED.into(),
+        }
+    }
+
+    fn socket(
+        &self,
+        addr: &SocketAddr,
+        ty: Type,
+        protocol: Protocol,
+        marking: &Marking,
+    ) -> error::Result<Socket> {
+        let socket = Socket::new(Domain::for_address(*addr), ty, Some(protocol))?;
+        marking.apply(&socket, addr)?;
+        if let Some(device) = &self.device {
+            #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+            socket.bind_device(Some(device.as_bytes())).map_err(|e| {
+                error::Error::new(&format!("Failed binding to device {}: {}", device, e))
+            })?;
+            #[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+            return Err(error::Error::new(&format!(
+                "Binding to device {} is only supported on Linux",
+                device
+            )));
+        }
+        Ok(socket)
+    }
+
+    /// Connect to `peer` from the local address
+    pub fn tcp_connect(&self, peer: SocketAddr, marking: &Marking) -> error::Result<TcpStream> {
+        let local = SocketAddr::new(self.addr_for(peer.ip()), 0);
+        let socket = self.socket(&local, Type::STREAM, Protocol::TCP, marking)?;
+        if self.addr.is_some() {
+            socket.bind(&SockAddr::from(local))?;
+        }
+        let peer = marking.flow(&socket, peer)?;
+        socket.connect(&SockAddr::from(peer))?;
+        Ok(socket.into())
+    }
+
+    /// Listen on `port` for connections from `peer`, accepted streams
+    /// inherit the marking
+    pub fn tcp_listen(
+        &self,
+        port: u16,
+        peer: IpAddr,
+        marking: &Marking,
+    ) -> error::Result<TcpListener> {
+        let local = SocketAddr::new(self.addr_for(peer), port);
+        let socket = self.socket(&local, Type::STREAM, Protocol::TCP, marking)?;
+        socket.set_reuse_address(true)?;
+        socket.bind(&SockAddr::from(local))?;
+        socket.listen(128)?;
+        Ok(socket.into())
+    }
+
+    /// UDP socket bound to `port` for talking to `peer`, 0 picks any port
+    pub fn udp_bind(&self, port: u16, peer: IpAddr, marking: &Marking) -> error::Result<UdpSocket> {
+        let local = SocketAddr::new(self.addr_for(peer), port);
+        let socket = self.socket(&local, Type::DGRAM, Protocol::UDP, marking)?;
+        socket.bind(&SockAddr::from(local))?;
+        Ok(socket.into())
+    }
+
+    /// UDP socket bound to `port` that has joined `group`, other sockets
+    /// may join the same group and port
+    pub fn udp_join(
+        &self,
+        group: IpAddr,
+        port: u16,
+        marking: &Marking,
+    ) -> error::Result<UdpSocket> {
+        let local = match group {
+            IpAddr::V4(_) => SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), port),
+            IpAddr::V6(_) => SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), port),
+        };
+        let socket = self.socket(&local, Type::DGRAM, Protocol::UDP, marking)?;
+        socket.set_reuse_address(true)?;
+        socket.bind(&SockAddr::from(local))?;
+        let interface = self.multicast_interface()?;
+        match group {
+            IpAddr::V4(group) => socket
+                .join_multicast_v4_n(&group, &socket2::InterfaceIndexOrAddress::Index(interface)),
+            IpAddr::V6(group) => socket.join_multicast_v6(&group, interface),
+        }
+        .map_err(|e| error::Error::new(&format!("Failed joining {}: {}", group, e)))?;
+        Ok(socket.into())
+    }
+
+    /// UDP socket that sends to multicast `group`, with datagrams allowed
+    /// `ttl` hops
+    pub fn udp_multicast(
+        &self,
+        group: SocketAddr,
+        ttl: u8,
+        marking: &Marking,
+    ) -> error::Result<UdpSocket> {
+        let local = SocketAddr::new(self.addr_for(group.ip()), 0);
+        let socket = self.socket(&local, Type::DGRAM, Protocol::UDP, marking)?;
+        socket.bind(&SockAddr::from(local))?;
+        let interface = self.multicast_interface()?;
+        match group {
+            SocketAddr::V4(_) => {
+                socket.set_multicast_ttl_v4(ttl.into())?;
+                if interface != 0 {
+                    set_multicast_if_v4(&socket, interface)?;
+                }
+            }
+            SocketAddr::V6(_) => {
+                socket.set_multicast_hops_v6(ttl.into())?;
+                socket.set_multicast_if_v6(interface)?;
+            }
+        }
+        let group = marking.flow(&socket, group)?;
+        socket.connect(&SockAddr::from(group))?;
+        Ok(socket.into())
+    }
+
+    /// Index of the multicast interface, 0 for the default
+    fn multicast_interface(&self) -> error::Result<u32> {
+        let Some(name) = &self.multicast_if else {
+            return Ok(0);
+        };
+        let invalid = || error::Error::new(&format!("No such interface {}", name));
+        let c_name = std::ffi::CString::new(name.as_str()).map_err(|_| invalid())?;
+        // SAFETY: c_name is a valid NUL terminated string
+        match unsafe { libc::if_nametoindex(c_name.as_ptr()) } {
+            0 => Err(invalid()),
+            index => Ok(index),
+        }
+    }
+
+    /// UDP socket bound to any port and connected to `peer`
+    pub fn udp_connect(&self, peer: SocketAddr, marking: &Marking) -> error::Result<UdpSocket> {
+        let local = SocketAddr::new(self.addr_for(peer.ip()), 0);
+        let socket = self.socket(&local, Type::DGRAM, Protocol::UDP, marking)?;
+        socket.bind(&SockAddr::from(local))?;
+        let peer = marking.flow(&socket, peer)?;
+        socket.connect(&SockAddr::from(peer))?;
+        Ok(socket.into())
+    }
+}
+
+/// Total segments the kernel has retransmitted on `stream`
+#[cfg(target_os = "linux")]
+pub fn tcp_retransmits(stream: &TcpStream) -> Option<u64> {
+    use std::os::fd::AsRawFd;
+
+    // SAFETY: tcp_info is plain data, all zeroes is a valid value
+    let mut info: libc::tcp_info = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::tcp_info>() as libc::socklen_t;
+    // SAFETY: info and len describe a valid, writable tcp_info
+    let ret = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_INFO,
+            &mut info as *mut libc::tcp_info as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    (ret == 0).then_some(info.tcpi_total_retrans.into())
+}
+
+/// Total segments the kernel has retransmitted on `stream`
+#[cfg(not(target_os = "linux"))]
+pub fn tcp_retransmits(_stream: &TcpStream) -> Option<u64> {
+    None
+}
+
+/// Have the kernel pace `stream` to `bytes_per_sec` with SO_MAX_PACING_RATE
+#[cfg(target_os = "linux")]
+pub fn set_max_pacing_rate(stream: &TcpStream, bytes_per_sec: u64) -> error::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    setsockopt(
+        stream.as_raw_fd(),
+        libc::SOL_SOCKET,
+        libc::SO_MAX_PACING_RATE,
+        &bytes_per_sec,
+    )
+    .map_err(|e| error::Error::new(&format!("Failed setting SO_MAX_PACING_RATE: {}", e)))
+}
+
+/// Have the kernel pace `stream` to `bytes_per_sec` with SO_MAX_PACING_RATE
+#[cfg(not(target_os = "linux"))]
+pub fn set_max_pacing_rate(_stream: &TcpStream, _bytes_per_sec: u64) -> error::Result<()> {
+    Err(error::Error::new(
+        "Kernel pacing is only supported on Linux",
+    ))
+}
+
+/// Have the kernel report the TOS or traffic class of each datagram
+/// received on `socket`, for [`recv_tos`]
+#[cfg(target_os = "linux")]
+pub fn set_recv_tos(socket: &UdpSocket) -> error::Result<()> {
+    let socket = socket2::SockRef::from(socket);
+    if let Some(SocketAddr::V6(_)) = socket.local_addr()?.as_socket() {
+        socket.set_recv_tclass_v6(true)?;
+    }
+    // also covers IPv4 datagrams arriving on a dual-stack IPv6 socket
+    socket.set_recv_tos_v4(true)?;
+    Ok(())
+}
+
+/// Have the kernel report the TOS or traffic class of each datagram
+/// received on `socket`, for [`recv_tos`]
+#[cfg(not(target_os = "linux"))]
+pub fn set_recv_tos(_socket: &UdpSocket) -> error::Result<()> {
+    Ok(())
+}
+
+/// Let the kernel coalesce datagrams arriving on `socket` with UDP GRO
+#[cfg(target_os = "linux")]
+pub fn set_udp_gro(socket: &UdpSocket) -> error::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    setsockopt(
+        socket.as_raw_fd(),
+        libc::SOL_UDP,
+        libc::UDP_GRO,
+        &(1 as libc::c_int),
+    )
+    .map_err(|e| error::Error::new(&format!("Failed setting UDP_GRO: {}", e)))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_udp_gro(_socket: &UdpSocket) -> error::Result<()> {
+    Err(error::Error::new("UDP GRO is only supported on Linux"))
+}
+
+/// Receive a datagram along with the TOS or traffic class it arrived with,
+/// if the kernel reported one
+#[cfg(target_os = "linux")]
+pub fn recv_tos(socket: &UdpSocket, buf: &mut [u8]) -> io::Result<(usize, Option<u8>)> {
+    use std::os::fd::AsRawFd;
+
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+    // room for an IP_TOS or IPV6_TCLASS message, aligned for cmsghdr
+    let mut control = [0u64; 8];
+    // SAFETY: msghdr is plain data, all zeroes is a valid value
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = std::mem::size_of_val(&control) as _;
+    // SAFETY: msg points to buffers that outlive the call
+    let n = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut msg, 0) };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut tos = None;
+    // SAFETY: the kernel filled in msg_controllen, the CMSG macros stay
+    // within it
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            let data = libc::CMSG_DATA(cmsg);
+            match ((*cmsg).cmsg_level, (*cmsg).cmsg_type) {
+                (libc::IPPROTO_IP, libc::IP_TOS) => tos = Some(*data),
+                (libc::IPPROTO_IPV6, libc::IPV6_TCLASS) => {
+                    tos = Some((data as *const libc::c_int).read_unaligned() as u8)
+                }
+                _ => {}
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+    Ok((n as usize, tos))
+}
+
+/// Receive a datagram along with the TOS or traffic class it arrived with,
+/// if the kernel reported one
+#[cfg(not(target_os = "linux"))]
+pub fn recv_tos(socket: &UdpSocket, buf: &mut [u8]) -> io::Result<(usize, Option<u8>)> {
+    Ok((socket.recv(buf)?, None))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_resolve_literal() {
+        assert_eq!(
+            resolve("127.0.0.1", Family::Any).unwrap(),
+            IpAddr::V4(Ipv4Addr::LOCALHOST)
+        );
+        assert_eq!(
+            resolve("::1", Family::V6).unwrap(),
+            IpAddr::V6(Ipv6Addr::LOCALHOST)
+        );
+        assert!(resolve("127.0.0.1", Family::V6).is_err());
+    }
+
+    #[test]
+    fn test_local_addr_for_peer() {
+        let local = LocalBind::default();
+        assert_eq!(
+            local.addr_for(Ipv6Addr::LOCALHOST.into()),
+            IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+        );
+        let local = LocalBind {
+            addr: Some(Ipv4Addr::new(10, 0, 0, 1).into()),
+            ..Default::default()
+        };
+        assert_eq!(
+            local.addr_for(Ipv4Addr::new(10, 0, 0, 2).into()),
+            IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))
+        );
+    }
+}
@@ -0,0 +1,729 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+use std::time::{Duration, Instant, SystemTime};
+
+use super::engine::Engine;
+use crate::error;
+use crate::units::{self, Format};
+
+/// How often each stream samples its counters
+pub const INTERVAL: Duration = Duration::from_secs(1);
+
+/// Largest Stats a peer may send, far more than the intervals of any test
+const MAX_STATS_SIZE: usize = 16 * 1024 * 1024;
+
+/// Counters for one stream over one sampling interval
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Interval {
+    pub stream: u16,
+    /// Offset of the start of the interval from the start of the test
+    pub start: Duration,
+    /// Offset of the end of the interval from the start of the test
+    pub end: Duration,
+    pub bytes: u64,
+    /// Datagrams sent or received, for datagram tests
+    pub packets: Option<u64>,
+    /// Datagrams that never arrived, for datagram tests
+    pub lost: Option<u64>,
+    pub jitter: Option<Duration>,
+    /// TCP segments retransmitted by the sender
+    pub retransmits: Option<u64>,
+}
+
+impl Interval {
+    /// Bytes per second over the interval
+    pub fn rate(&self) -> Option<f64> {
+        units::rate(self.bytes, self.end - self.start)
+    }
+
+    /// Lost datagrams as a percentage of those sent
+    pub fn loss(&self) -> Option<f64> {
+        let lost = self.lost?;
+        let sent = lost + self.packets?;
+        Some(if sent == 0 {
+            0f64
+        } else {
+            lost as f64 * 100f64 / sent as f64
+        })
+    }
+
+    pub fn format(&self, format: &Format) -> String {
+        let mut line = format!(
+            "[{:>3}] {:>6.2}-{:<6.2} s  {:>12}  {:>14}",
+            self.stream,
+            self.start.as_secs_f64(),
+            self.end.as_secs_f64(),
+            format.bytes(self.bytes),
+            self.rate()
+                .map(|rate| format.rate(rate))
+                .unwrap_or("n/a".to_string()),
+        );
+        if let (Some(lost), Some(packets)) = (self.lost, self.packets) {
+            line += &format!("  {}/{} lost", lost, lost + packets);
+        }
+        if let Some(jitter) = self.jitter {
+            line += &format!("  {:.3} ms jitter", jitter.as_secs_f64() * 1_000f64);
+        }
+        if let Some(retransmits) = self.retransmits {
+            line += &format!("  {} retransmits", retransmits);
+        }
+        line
+    }
+}
+
+/// What a receiver found when comparing the data with what the sender
+/// should have sent
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Integrity {
+    /// Payload bytes compared
+    pub checked: u64,
+    /// Bytes that differed from the expected payload
+    pub corrupted: u64,
+    /// Stream and offset into it of the first corrupted byte
+    pub first_corrupted: Option<(u16, u64)>,
+    /// Bytes the sender wrote that never arrived, for stream tests
+    pub missing_bytes: Option<u64>,
+    /// Datagrams that never arrived, for datagram tests
+    pub missing_datagrams: Option<u64>,
+    /// Datagrams that arrived after a later one, for datagram tests
+    pub misordered_datagrams: Option<u64>,
+}
+
+impl Integrity {
+    /// Add the counts of another stream
+    pub(crate) fn merge(&mut self, other: &Integrity) {
+        let add = |a: Option<u64>, b: Option<u64>| match (a, b) {
+            (Some(a), Some(b)) => Some(a + b),
+            (a, b) => a.or(b),
+        };
+        self.checked += other.checked;
+        self.corrupted += other.corrupted;
+        self.first_corrupted = self.first_corrupted.or(other.first_corrupted);
+        self.missing_bytes = add(self.missing_bytes, other.missing_bytes);
+        self.missing_datagrams = add(self.missing_datagrams, other.missing_datagrams);
+        self.misordered_datagrams = add(self.misordered_datagrams, other.misordered_datagrams);
+    }
+
+    pub fn format(&self, format: &Format) -> String {
+        let mut line = format!(
+            "Integrity: {} checked, {} bytes corrupted",
+            format.bytes(self.checked),
+            self.corrupted
+        );
+        if let Some((stream, offset)) = self.first_corrupted {
+            line += &format!(" (first in stream {} at byte {})", stream, offset);
+        }
+        if let Some(missing) = self.missing_bytes {
+            line += &format!(", {} bytes missing", missing);
+        }
+        if let Some(missing) = self.missing_datagrams {
+            line += &format!(", {} datagrams missing", missing);
+        }
+        if let Some(misordered) = self.misordered_datagrams {
+            line += &format!(", {} misordered", misordered);
+        }
+        line
+    }
+}
+
+/// CPU a side used over the test, so a low bandwidth can be told apart
+/// from a sender or receiver that ran out of CPU
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Cpu {
+    /// Process time in user space, as a percentage of one core
+    pub user: f64,
+    /// Process time in the kernel, as a percentage of one core
+    pub system: f64,
+    /// How busy all cores of the host were, on Linux
+    pub host: Option<f64>,
+}
+
+impl Cpu {
+    pub fn format(&self) -> String {
+        let mut line = format!(
+            "CPU: {:.1}% ({:.1}% user, {:.1}% system)",
+            self.user + self.system,
+            self.user,
+            self.system
+        );
+        if let Some(host) = self.host {
+            line += &format!(", host {:.1}%", host);
+        }
+        line
+    }
+}
+
+/// How a UDP side passed datagrams to or from the kernel, to tell the
+/// network apart from the cost of the system calls
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct DatagramIo {
+    /// System call used, such as "sendmmsg" or "recvmmsg with GRO"
+    pub method: String,
+    /// Most datagrams or buffers per call
+    pub batch: u16,
+    pub calls: u64,
+    pub datagrams: u64,
+}
+
+impl DatagramIo {
+    /// Add the counts of another stream
+    pub(crate) fn merge(&mut self, other: &DatagramIo) {
+        if self.method.is_empty() {
+            self.method = other.method.clone();
+            self.batch = other.batch;
+        }
+        self.calls += other.calls;
+        self.datagrams += other.datagrams;
+    }
+
+    pub fn format(&self) -> String {
+        let per_call = match self.calls {
+            0 => 0f64,
+            calls => self.datagrams as f64 / calls as f64,
+        };
+        format!(
+            "Datagram I/O: {} (batch {}), {} datagrams in {} calls, {:.1} per call",
+            self.method, self.batch, self.datagrams, self.calls, per_call
+        )
+    }
+}
+
+/// Splits a stream's byte count into intervals of [`INTERVAL`]
+pub struct Recorder {
+    stream: u16,
+    start: Instant,
+    last: Instant,
+    bytes: u64,
+    format: Format,
+    intervals: Vec<Interval>,
+}
+
+impl Recorder {
+    pub fn new(stream: u16, start: Instant, format: Format) -> Recorder {
+        Recorder {
+            stream,
+            start,
+            last: start,
+            bytes: 0,
+            format,
+            intervals: Vec::new(),
+        }
+    }
+
+    /// Count `n` bytes, returns true once the current interval has elapsed
+    pub fn add(&mut self, n: u64) -> bool {
+        self.bytes += n;
+        self.due()
+    }
+
+    pub fn due(&self) -> bool {
+        self.last.elapsed() >= INTERVAL
+    }
+
+    /// Close the current interval, letting `annotate` fill in
+    /// protocol-specific counters before it is printed
+    pub fn flush<F>(&mut self, annotate: F)
+    where
+        F: FnOnce(&mut Interval),
+    {
+        let now = Instant::now();
+        let mut interval = Interval {
+            stream: self.stream,
+            start: self.last - self.start,
+            end: now - self.start,
+            bytes: self.bytes,
+            ..Default::default()
+        };
+        annotate(&mut interval);
+        println!("{}", interval.format(&self.format));
+        self.intervals.push(interval);
+        self.last = now;
+        self.bytes = 0;
+    }
+
+    /// Close the final, possibly partial, interval
+    pub fn finish<F>(mut self, annotate: F) -> Vec<Interval>
+    where
+        F: FnOnce(&mut Interval),
+    {
+        if self.bytes > 0 || self.intervals.is_empty() {
+            self.flush(annotate);
+        }
+        self.intervals
+    }
+}
+
+/// Distribution of a set of samples
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Summary {
+    pub count: usize,
+    pub min: f64,
+    pub max: f64,
+    pub mean: f64,
+    /// Sample standard deviation, 0 for a single sample
+    pub stddev: f64,
+    pub p5: f64,
+    pub p50: f64,
+    pub p95: f64,
+}
+
+impl Summary {
+    /// Summarize `samples`, None if there are none
+    pub fn of(samples: &[f64]) -> Option<Summary> {
+        if samples.is_empty() {
+            return None;
+        }
+        let mut sorted = samples.to_vec();
+        sorted.sort_by(f64::total_cmp);
+        let n = sorted.len() as f64;
+        let mean = sorted.iter().sum::<f64>() / n;
+        let variance = if sorted.len() > 1 {
+            sorted.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / (n - 1f64)
+        } else {
+            0f64
+        };
+        Some(Summary {
+            count: sorted.len(),
+            min: sorted[0],
+            max: sorted[sorted.len() - 1],
+            mean,
+            stddev: variance.sqrt(),
+            p5: percentile(&sorted, 5f64),
+            p50: percentile(&sorted, 50f64),
+            p95: percentile(&sorted, 95f64),
+        })
+    }
+
+    /// Half width of the 95% confidence interval of the mean, using
+    /// Student's t distribution. None with fewer than two samples.
+    pub fn ci95(&self) -> Option<f64> {
+        if self.count < 2 {
+            return None;
+        }
+        // two-sided critical values for 1 to 30 degrees of freedom, the
+        // normal approximation is close enough beyond that
+        const T: [f64; 30] = [
+            12.706, 4.303, 3.182, 2.776, 2.571, 2.447, 2.365, 2.306, 2.262, 2.228, 2.201, 2.179,
+            2.160, 2.145, 2.131, 2.120, 2.110, 2.101, 2.093, 2.086, 2.080, 2.074, 2.069, 2.064,
+            2.060, 2.056, 2.052, 2.048, 2.045, 2.042,
+        ];
+        let t = T.get(self.count - 2).copied().unwrap_or(1.960);
+        Some(t * self.stddev / (self.count as f64).sqrt())
+    }
+}
+
+/// Percentile of sorted samples, interpolating between the closest ranks
+fn percentile(sorted: &[f64], p: f64) -> f64 {
+    let rank = p / 100f64 * (sorted.len() - 1) as f64;
+    let (lower, upper) = (rank.floor() as usize, rank.ceil() as usize);
+    sorted[lower] + (sorted[upper] - sorted[lower]) * (rank - lower as f64)
+}
+
+/// Results of one side of a NetExp
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Stats {
+    /// When the test started
+    pub started: Option<SystemTime>,
+    /// Bytes moved and how long it took
+    pub transfer: Option<(u64, Duration)>,
+    // Packet loss as percentage
+    pub packet_loss: Option<f64>,
+    pub jitter: Option<Duration>,
+    pub retransmits: Option<u64>,
+    /// Time taken to set up an encrypted session
+    pub handshake: Option<Duration>,
+    /// Smoothed round trip time at the end of the test
+    pub rtt: Option<Duration>,
+    /// Number of times the congestion controller backed off
+    pub congestion_events: Option<u64>,
+    /// Datagrams received with each IPv4 TOS or IPv6 traffic class
+    pub received_tos: Option<BTreeMap<u8, u64>>,
+    /// Result of checking the data received, with --verify
+    pub integrity: Option<Integrity>,
+    /// CPU used by the process and the host while the test ran
+    pub cpu: Option<Cpu>,
+    /// System calls a UDP side made to move its datagrams
+    pub datagram_io: Option<DatagramIo>,
+    /// Engine the TCP or UDP data loop ran on, when io_uring was asked for
+    pub engine: Option<Engine>,
+    /// Samples of every stream, ordered by stream then time
+    pub intervals: Vec<Interval>,
+}
+
+impl Stats {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    pub(crate) fn with_started(self, started: SystemTime) -> Self {
+        Self {
+            started: Some(started),
+            ..self
+        }
+    }
+
+    pub(crate) fn with_transfer(self, bytes: u64, duration: Duration) -> Self {
+        Self {
+            transfer: Some((bytes, duration)),
+            ..self
+        }
+    }
+
+    pub(crate) fn with_packet_loss(self, packet_loss: f64) -> Self {
+        Self {
+            packet_loss: Some(packet_loss),
+            ..self
+        }
+    }
+
+    pub(crate) fn with_jitter(self, jitter: Duration) -> Self {
+        Self {
+            jitter: Some(jitter),
+            ..self
+        }
+    }
+
+    pub(crate) fn with_retransmits(self, retransmits: u64) -> Self {
+        Self {
+            retransmits: Some(retransmits),
+            ..self
+        }
+    }
+
+    pub(crate) fn with_handshake(self, handshake: Duration) -> Self {
+        Self {
+            handshake: Some(handshake),
+            ..self
+        }
+    }
+
+    pub(crate) fn with_rtt(self, rtt: Duration) -> Self {
+        Self {
+            rtt: Some(rtt),
+            ..self
+        }
+    }
+
+    pub(crate) fn with_congestion_events(self, congestion_events: u64) -> Self {
+        Self {
+            congestion_events: Some(congestion_events),
+            ..self
+        }
+    }
+
+    pub(crate) fn with_received_tos(self, received_tos: BTreeMap<u8, u64>) -> Self {
+        Self {
+            received_tos: Some(received_tos),
+            ..self
+        }
+    }
+
+    pub(crate) fn with_integrity(self, integrity: Integrity) -> Self {
+        Self {
+            integrity: Some(integrity),
+            ..self
+        }
+    }
+
+    pub(crate) fn with_cpu(self, cpu: Cpu) -> Self {
+        Self {
+            cpu: Some(cpu),
+            ..self
+        }
+    }
+
+    pub(crate) fn with_datagram_io(self, datagram_io: DatagramIo) -> Self {
+        Self {
+            datagram_io: Some(datagram_io),
+            ..self
+        }
+    }
+
+    pub(crate) fn with_engine(self, engine: Engine) -> Self {
+        Self {
+            engine: Some(engine),
+            ..self
+        }
+    }
+
+    pub(crate) fn with_intervals(self, intervals: Vec<Interval>) -> Self {
+        Self { intervals, ..self }
+    }
+
+    /// Leave out intervals that started before `omit`, recomputing the
+    /// totals from the remaining intervals
+    pub(crate) fn omit(self, omit: Duration) -> Self {
+        let intervals: Vec<Interval> = self
+            .intervals
+            .into_iter()
+            .filter(|i| i.start >= omit)
+            .collect();
+        let start = intervals.iter().map(|i| i.start).min().unwrap_or(omit);
+        let end = intervals.iter().map(|i| i.end).max().unwrap_or(omit);
+        let bytes = intervals.iter().map(|i| i.bytes).sum();
+        let sum =
+            |f: fn(&Interval) -> Option<u64>| intervals.iter().filter_map(f).reduce(|a, b| a + b);
+        let retransmits = self.retransmits.and(sum(|i| i.retransmits));
+        let packet_loss = match (self.packet_loss, sum(|i| i.packets), sum(|i| i.lost)) {
+            (Some(_), Some(packets), Some(lost)) if packets + lost > 0 => {
+                Some(lost as f64 * 100f64 / (packets + lost) as f64)
+            }
+            (Some(_), Some(_), Some(_)) => Some(0f64),
+            // such as QUIC's, counted over the whole connection
+            (packet_loss, ..) => packet_loss,
+        };
+        Self {
+            transfer: self.transfer.map(|_| (bytes, end - start)),
+            packet_loss,
+            retransmits,
+            intervals,
+            ..self
+        }
+    }
+
+    /// Bytes per second over the whole test
+    pub fn rate(&self) -> Option<f64> {
+        let (bytes, duration) = self.transfer?;
+        units::rate(bytes, duration)
+    }
+
+    /// Distribution of the throughput of the whole test over each
+    /// [`INTERVAL`], the streams' intervals summed, in bytes per second.
+    /// Intervals shorter than half of [`INTERVAL`], such as the tail end of
+    /// a test, are left out as their rate is mostly noise.
+    pub fn throughput_summary(&self) -> Option<Summary> {
+        // every stream samples on the same boundaries from the start of the
+        // test, so intervals with the same index cover the same time
+        let mut totals: BTreeMap<u128, (u64, Duration, Duration)> = BTreeMap::new();
+        for interval in &self.intervals {
+            let index = interval.start.as_nanos() / INTERVAL.as_nanos();
+            let total = totals
+                .entry(index)
+                .or_insert((0, interval.start, interval.end));
+            total.0 += interval.bytes;
+            total.1 = total.1.min(interval.start);
+            total.2 = total.2.max(interval.end);
+        }
+        let rates: Vec<f64> = totals
+            .into_values()
+            .filter(|(_, start, end)| *end - *start >= INTERVAL / 2)
+            .filter_map(|(bytes, start, end)| units::rate(bytes, end - start))
+            .collect();
+        Summary::of(&rates)
+    }
+
+    pub fn format(&self, format: &Format) -> String {
+        let mut lines = Vec::new();
+        if let Some(hs) = self.handshake {
+            lines.push(format!(
+                "Handshake time: {:.3} ms",
+                hs.as_secs_f64() * 1_000f64
+            ));
+        }
+        if let Some((bytes, duration)) = self.transfer {
+            lines.push(format!(
+                "Transfer: {} in {:.3} s",
+                format.bytes(bytes),
+                duration.as_secs_f64()
+            ));
+            lines.push(match self.rate() {
+                Some(rate) => format!("Bandwidth: {}", format.rate(rate)),
+                None => "Bandwidth: n/a".to_string(),
+            });
+        }
+        if let Some(summary) = self.throughput_summary() {
+            lines.push(format!(
+                "Interval bandwidth: min {} / mean {} / max {} / stddev {}",
+                format.rate(summary.min),
+                format.rate(summary.mean),
+                format.rate(summary.max),
+                format.rate(summary.stddev),
+            ));
+            lines.push(format!(
+                "Interval percentiles: p5 {} / p50 {} / p95 {}",
+                format.rate(summary.p5),
+                format.rate(summary.p50),
+                format.rate(summary.p95),
+            ));
+        }
+        if let Some(pl) = self.packet_loss {
+            lines.push(format!("Packet loss: {:.3}%", pl));
+        }
+        if let Some(jitter) = self.jitter {
+            lines.push(format!("Jitter: {:.3} ms", jitter.as_secs_f64() * 1_000f64));
+        }
+        if let Some(retransmits) = self.retransmits {
+            lines.push(format!("Retransmits: {}", retransmits));
+        }
+        if let Some(rtt) = self.rtt {
+            lines.push(format!("RTT: {:.3} ms", rtt.as_secs_f64() * 1_000f64));
+        }
+        if let Some(ce) = self.congestion_events {
+            lines.push(format!("Congestion events: {}", ce));
+        }
+        if let Some(received_tos) = &self.received_tos {
+            let counts: Vec<String> = received_tos
+                .iter()
+                .map(|(tos, n)| format!("{:#04x} (DSCP {}) x {}", tos, tos >> 2, n))
+                .collect();
+            lines.push(format!("Received TOS: {}", counts.join(", ")));
+        }
+        if let Some(integrity) = &self.integrity {
+            lines.push(integrity.format(format));
+        }
+        if let Some(datagram_io) = &self.datagram_io {
+            lines.push(datagram_io.format());
+        }
+        if let Some(engine) = self.engine {
+            lines.push(format!("Engine: {}", engine));
+        }
+        if let Some(cpu) = &self.cpu {
+            lines.push(cpu.format());
+        }
+
+        lines.join("\n")
+    }
+
+    /// Send the Stats to the peer as length-prefixed JSON
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> error::Result<()> {
+        let json = serde_json::to_vec(self)?;
+        writer.write_all(&(json.len() as u32).to_be_bytes())?;
+        writer.write_all(&json)?;
+        Ok(())
+    }
+
+    /// Read Stats written by [`Stats::write_to`]
+    pub fn read_from<R: Read>(reader: &mut R) -> error::Result<Stats> {
+        let mut len = [0; 4];
+        reader.read_exact(&mut len)?;
+        let len = u32::from_be_bytes(len) as usize;
+        if len > MAX_STATS_SIZE {
+            return Err(error::Error::new("Stats from the peer are too large"));
+        }
+        let mut json = vec![0; len];
+        reader.read_exact(&mut json)?;
+        Ok(serde_json::from_slice(&json)?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_interval_loss() {
+        let interval = Interval {
+            packets: Some(90),
+            lost: Some(10),
+            ..Default::default()
+        };
+        assert_eq!(interval.loss(), Some(10f64));
+        let interval = Interval {
+            packets: Some(0),
+            lost: Some(0),
+            ..Default::default()
+        };
+        assert_eq!(interval.loss(), Some(0f64));
+        assert_eq!(Interval::default().loss(), None);
+    }
+
+    #[test]
+    fn test_write_to_and_read_from() {
+        let stats = Stats::new()
+            .with_transfer(1_000, Duration::from_millis(1_500))
+            .with_packet_loss(0.5)
+            .with_intervals(vec![Interval {
+                stream: 1,
+                end: Duration::from_secs(1),
+                bytes: 1_000,
+                ..Default::default()
+            }]);
+        let mut buf = Vec::new();
+        stats.write_to(&mut buf).unwrap();
+        let read = Stats::read_from(&mut buf.as_slice()).unwrap();
+        assert_eq!(read.transfer, stats.transfer);
+        assert_eq!(read.packet_loss, stats.packet_loss);
+        assert_eq!(read.intervals, stats.intervals);
+
+        let huge = u32::MAX.to_be_bytes();
+        assert!(Stats::read_from(&mut huge.as_slice()).is_err());
+    }
+
+    #[test]
+    fn test_summary() {
+        assert_eq!(Summary::of(&[]), None);
+
+        let single = Summary::of(&[4f64]).unwrap();
+        assert_eq!((single.min, single.max, single.stddev), (4f64, 4f64, 0f64));
+        assert_eq!((single.p5, single.p50, single.p95), (4f64, 4f64, 4f64));
+
+        let samples: Vec<f64> = (1..=21).rev().map(f64::from).collect();
+        let summary = Summary::of(&samples).unwrap();
+        assert_eq!(
+            (summary.min, summary.max, summary.mean),
+            (1f64, 21f64, 11f64)
+        );
+        assert_eq!((summary.p5, summary.p50, summary.p95), (2f64, 11f64, 20f64));
+        assert!((summary.stddev - 38.5f64.sqrt()).abs() < 1e-9);
+        assert_eq!(single.ci95(), None);
+        let ci = summary.ci95().unwrap();
+        assert!((ci - 2.086 * 38.5f64.sqrt() / 21f64.sqrt()).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_omit() {
+        let interval = |start, bytes, lost| Interval {
+            start: Duration::from_secs(start),
+            end: Duration::from_secs(start + 1),
+            bytes,
+            packets: Some(bytes / 100 - lost),
+            lost: Some(lost),
+            retransmits: Some(lost),
+            ..Default::default()
+        };
+        let started = SystemTime::UNIX_EPOCH;
+        let stats = Stats::new()
+            .with_started(started)
+            .with_transfer(6_000, Duration::from_secs(3))
+            .with_packet_loss(50f64)
+            .with_retransmits(3)
+            .with_intervals(vec![
+                interval(0, 1_000, 3),
+                interval(1, 2_000, 0),
+                interval(2, 3_000, 0),
+            ])
+            .omit(Duration::from_secs(1));
+        assert_eq!(stats.intervals.len(), 2);
+        assert_eq!(stats.transfer, Some((5_000, Duration::from_secs(2))));
+        assert_eq!(stats.started, Some(started));
+        assert_eq!(stats.packet_loss, Some(0f64));
+        assert_eq!(stats.retransmits, Some(0));
+    }
+
+    #[test]
+    fn test_throughput_summary_sums_streams_and_skips_short_intervals() {
+        let interval = |stream, start, end, bytes| Interval {
+            stream,
+            start: Duration::from_millis(start),
+            end: Duration::from_millis(end),
+            bytes,
+            ..Default::default()
+        };
+        let stats = Stats::new().with_intervals(vec![
+            interval(0, 0, 1_000, 200),
+            interval(0, 1_000, 2_000, 1_000),
+            interval(0, 2_000, 2_010, 1),
+            interval(1, 0, 1_000, 600),
+            interval(1, 1_000, 2_000, 400),
+            interval(1, 2_000, 2_005, 1),
+            interval(2, 1_000, 2_000, 600),
+        ]);
+        let summary = stats.throughput_summary().unwrap();
+        assert_eq!(
+            (summary.count, summary.min, summary.max),
+            (2, 800f64, 2_000f64)
+        );
+        assert_eq!(Stats::new().throughput_summary(), None);
+    }
+}
@@ -1,10 +1,22 @@
-use std::io::{Read, Write};
+use std::io::Read;
 use std::net;
-use std::time;
+use std::thread;
+use std::time::{self, Instant, SystemTime};
 
-use super::NetExpParams;
-use super::{BUF_SIZE, MB, Stats};
+use super::engine::{Engine, Sink, Source};
+use super::pacer::Pacer;
+use super::payload::{Payload, Verifier};
+use super::sock;
+use super::stats::{Integrity, Interval, Recorder};
+use super::{NetExpParams, RunOptions, Stats};
 use crate::error;
+use crate::units::Format;
+
+/// Size of each write when the NetExp does not set one
+const DEFAULT_LENGTH: usize = 128 * 1024;
+
+/// Buffers of `length` bytes each stream provides to an io_uring receive
+const RING_BUFFERS: u16 = 16;
 
 /// Uninitialized
 pub struct Uninit {}
@@ -14,7 +26,119 @@ pub struct Bound {
 }
 /// Ready
 pub struct Ready {
-    stream: net::TcpStream,
+    streams: Vec<net::TcpStream>,
+}
+
+/// What one stream moved, and when it finished
+pub(super) struct StreamResult {
+    pub(super) bytes: u64,
+    pub(super) end: Instant,
+    pub(super) intervals: Vec<Interval>,
+    pub(super) retransmits: Option<u64>,
+    pub(super) integrity: Option<Integrity>,
+}
+
+pub(super) fn length(params: &NetExpParams) -> usize {
+    match params.length {
+        0 => DEFAULT_LENGTH,
+        length => length as usize,
+    }
+}
+
+fn listen(
+    name: &str,
+    params: &NetExpParams,
+    options: &RunOptions,
+) -> error::Result<net::TcpListener> {
+    let listener = options
+        .local
+        .tcp_listen(params.port, params.host, &params.marking)?;
+    println!("Started {} listener on {}", name, listener.local_addr()?);
+    Ok(listener)
+}
+
+fn accept(
+    listener: &net::TcpListener,
+    params: &NetExpParams,
+) -> error::Result<Vec<net::TcpStream>> {
+    let mut streams = Vec::new();
+    for _ in 0..params.parallel {
+        let (stream, _) = listener.accept()?;
+        streams.push(stream);
+    }
+    Ok(streams)
+}
+
+fn connect(
+    name: &str,
+    params: &NetExpParams,
+    options: &RunOptions,
+) -> error::Result<Vec<net::TcpStream>> {
+    let addr = net::SocketAddr::new(params.host, params.port);
+    println!("{} connecting to {}", name, addr);
+    let mut streams = Vec::new();
+    for _ in 0..params.parallel {
+        streams.push(options.local.tcp_connect(addr, &params.marking)?);
+    }
+    Ok(streams)
+}
+
+/// Run `f` on every stream in its own thread and combine the results
+pub(super) fn run_streams<S, F>(streams: Vec<S>, start: Instant, f: F) -> error::Result<Stats>
+where
+    S: Send + 'static,
+    F: Fn(u16, S) -> error::Result<StreamResult> + Clone + Send + 'static,
+{
+    let started = SystemTime::now();
+    let handles: Vec<_> = streams
+        .into_iter()
+        .enumerate()
+        .map(|(i, stream)| {
+            let f = f.clone();
+            thread::spawn(move || f(i as u16, stream))
+        })
+        .collect();
+
+    let mut results = Vec::new();
+    for handle in handles {
+        let Ok(result) = handle.join() else {
+            return Err(error::Error::new("Failed joining thread"));
+        };
+        results.push(result?);
+    }
+    Ok(combine(started, start, results))
+}
+
+/// Stats of the streams of a test that started at `start`
+pub(super) fn combine(started: SystemTime, start: Instant, results: Vec<StreamResult>) -> Stats {
+    let mut total_bytes = 0;
+    let mut end = start;
+    let mut intervals = Vec::new();
+    let mut retransmits = None;
+    let mut integrity: Option<Integrity> = None;
+    for result in results {
+        total_bytes += result.bytes;
+        end = end.max(result.end);
+        intervals.extend(result.intervals);
+        if let Some(r) = result.retransmits {
+            retransmits = Some(retransmits.unwrap_or(0) + r);
+        }
+        if let Some(i) = result.integrity {
+            integrity.get_or_insert_default().merge(&i);
+        }
+    }
+
+    let mut stats = Stats::new()
+        .with_started(started)
+        .with_transfer(total_bytes, end - start)
+        .with_intervals(intervals);
+    if let Some(retransmits) = retransmits {
+        stats = stats.with_retransmits(retransmits);
+    }
+    if let Some(integrity) = integrity {
+        stats = stats.with_integrity(integrity);
+    }
+    stats
 }
 
 pub struct TcpRx<State = Uninit> {
@@ -30,52 +154,92 @@ impl TcpRx<Uninit> {
         }
     }
 
-    pub fn bind(self) -> error::Result<TcpRx<Bound>> {
-        let addr = format!("{}:{}", self.params.host, self.params.port);
-        let listener = net::TcpListener::bind(addr.clone())?;
-        println!("Started TcpRx listener on {}", addr);
+    pub fn bind(self, options: &RunOptions) -> error::Result<TcpRx<Bound>> {
+        let listener = listen("TcpRx", &self.params, options)?;
         Ok(TcpRx {
             params: self.params,
             state: Bound { listener },
         })
     }
+
+    /// Open the streams to a sender that is listening for them
+    pub fn connect(self, options: &RunOptions) -> error::Result<TcpRx<Ready>> {
+        let streams = connect("TcpRx", &self.params, options)?;
+        Ok(TcpRx {
+            params: self.params,
+            state: Ready { streams },
+        })
+    }
 }
 
 impl TcpRx<Bound> {
     pub fn accept(self) -> error::Result<TcpRx<Ready>> {
-        let (stream, _) = self.state.listener.accept()?;
+        let streams = accept(&self.state.listener, &self.params)?;
         Ok(TcpRx {
             params: self.params,
-            state: Ready { stream },
+            state: Ready { streams },
         })
     }
 }
 
 impl TcpRx<Ready> {
-    pub fn run(mut self) -> error::Result<Stats> {
-        let mut buf: Vec<u8> = vec![0; BUF_SIZE];
-        let peer_addr = self.state.stream.peer_addr()?;
+    pub fn run(self, options: &RunOptions) -> error::Result<Stats> {
+        let Some(stream) = self.state.streams.first() else {
+            return Err(error::Error::new("No streams to receive on"));
+        };
+        let peer_addr = stream.peer_addr()?;
         println!(
-            "Running TCP recv {}:{} for {} seconds with {} threads...",
+            "Running TCP recv {}:{} for {} seconds with {} streams...",
             peer_addr.ip(),
             peer_addr.port(),
             self.params.duration,
             self.params.parallel,
         );
 
-        let start = time::Instant::now();
-
-        for i in 0..self.params.duration {
-            self.state.stream.read_exact(&mut buf)?;
-            println!("{i} Received {BUF_SIZE} bytes");
-        }
-
-        let total_bytes = BUF_SIZE * self.params.duration as usize;
-        let duration = time::Instant::now().duration_since(start).as_millis();
-        let bandwidth = total_bytes as u128 / duration;
+        let start = Instant::now();
+        let length = length(&self.params);
+        let format = options.format;
+        let params = self.params.clone();
+        run_streams(self.state.streams, start, move |id, stream| {
+            let verifier = Payload::verifier(&params, id);
+            recv_stream(id, stream, start, length, params.engine, verifier, format)
+        })
+    }
+}
 
-        Ok(Stats::new().with_bandwidth(bandwidth))
+/// Read until the sender closes the stream, checking the data with
+/// `verifier` if there is one
+fn recv_stream(
+    id: u16,
+    mut stream: net::TcpStream,
+    start: Instant,
+    length: usize,
+    engine: Engine,
+    mut verifier: Option<Verifier>,
+    format: Format,
+) -> error::Result<StreamResult> {
+    let mut source = Source::new(engine, &stream, length, RING_BUFFERS)?;
+    let mut recorder = Recorder::new(id, start, format);
+    let mut bytes = 0;
+    let mut open = true;
+    while open {
+        open = source.read(&mut stream, |chunk| {
+            if let Some(verifier) = &mut verifier {
+                verifier.check(bytes, chunk);
+            }
+            bytes += chunk.len() as u64;
+            if recorder.add(chunk.len() as u64) {
+                recorder.flush(|_| {});
+            }
+        })?;
     }
+    Ok(StreamResult {
+        bytes,
+        end: Instant::now(),
+        intervals: recorder.finish(|_| {}),
+        retransmits: None,
+        integrity: verifier.map(Verifier::finish),
+    })
 }
 
 pub struct TcpTx<State = Uninit> {
@@ -91,40 +255,142 @@ impl TcpTx<Uninit> {
         }
     }
 
-    pub fn init(self) -> error::Result<TcpTx<Ready>> {
-        let addr = format!("{}:{}", self.params.host, self.params.port);
-        println!("TcpTx connecting to {}", addr);
-        let stream = net::TcpStream::connect(addr)?;
+    pub fn init(self, options: &RunOptions) -> error::Result<TcpTx<Ready>> {
+        let streams = connect("TcpTx", &self.params, options)?;
         Ok(TcpTx {
             params: self.params,
-            state: Ready { stream },
+            state: Ready { streams },
+        })
+    }
+
+    /// Listen for a receiver to open the streams
+    pub fn bind(self, options: &RunOptions) -> error::Result<TcpTx<Bound>> {
+        let listener = listen("TcpTx", &self.params, options)?;
+        Ok(TcpTx {
+            params: self.params,
+            state: Bound { listener },
+        })
+    }
+}
+
+impl TcpTx<Bound> {
+    pub fn accept(self) -> error::Result<TcpTx<Ready>> {
+        let streams = accept(&self.state.listener, &self.params)?;
+        Ok(TcpTx {
+            params: self.params,
+            state: Ready { streams },
         })
     }
 }
 
 impl TcpTx<Ready> {
-    pub fn run(mut self) -> error::Result<Stats> {
-        let mut buf: Vec<u8> = vec![0; BUF_SIZE];
-        let peer_addr = self.state.stream.peer_addr()?;
+    pub fn run(self, options: &RunOptions) -> error::Result<Stats> {
+        let Some(stream) = self.state.streams.first() else {
+            return Err(error::Error::new("No streams to send on"));
+        };
+        let peer_addr = stream.peer_addr()?;
         println!(
-            "Running TCP send {}:{} for {} seconds with {} threads...",
+            "Running TCP send {}:{} for {} seconds with {} streams...",
             peer_addr.ip(),
             peer_addr.port(),
             self.params.duration,
             self.params.parallel,
         );
 
-        let start = time::Instant::now();
-
-        for i in 0..self.params.duration {
-            buf[0] = i as u8;
-            self.state.stream.write_all(&buf)?;
+        let length = length(&self.params);
+        let bitrate = self.params.bitrate;
+        let burst = self.params.burst;
+        if self.params.kernel_pacing && bitrate > 0 {
+            for stream in &self.state.streams {
+                sock::set_max_pacing_rate(stream, bitrate / 8)?;
+            }
         }
+        let kernel_pacing = self.params.kernel_pacing;
+        let engine = self.params.engine;
 
-        let total_bytes = BUF_SIZE * self.params.duration as usize;
-        let duration_ms = time::Instant::now().duration_since(start).as_millis();
-        let bandwidth = total_bytes as u128 / duration_ms;
+        let start = Instant::now();
+        let duration = time::Duration::from_secs(self.params.duration.into());
+        let format = options.format;
+        let payload = Payload::for_sender(&self.params, options);
+        run_streams(self.state.streams, start, move |id, stream| {
+            let pacer = if kernel_pacing {
+                None
+            } else {
+                Pacer::new(bitrate, burst, length)
+            };
+            let writer = Writer {
+                length,
+                pacer,
+                payload: payload.clone(),
+                engine,
+            };
+            send_stream(id, stream, start, duration, writer, format)
+        })
+    }
+}
 
-        Ok(Stats::new().with_bandwidth(bandwidth))
+/// What each stream writes and how fast
+struct Writer {
+    length: usize,
+    pacer: Option<Pacer>,
+    /// None for zeros
+    payload: Option<Payload>,
+    engine: Engine,
+}
+
+/// Write as fast as the pacer allows until `duration` has passed, then
+/// wait for the receiver to read everything and close the stream
+fn send_stream(
+    id: u16,
+    mut stream: net::TcpStream,
+    start: Instant,
+    duration: time::Duration,
+    writer: Writer,
+    format: Format,
+) -> error::Result<StreamResult> {
+    let Writer {
+        length,
+        mut pacer,
+        payload,
+        engine,
+    } = writer;
+    let mut sink = Sink::new(engine, &stream, length, 1)?;
+    let mut recorder = Recorder::new(id, start, format);
+    let mut bytes = 0;
+    let mut last_retransmits = 0;
+    let mut retransmits_since = |stream: &net::TcpStream| {
+        let total = sock::tcp_retransmits(stream)?;
+        let since = total.saturating_sub(last_retransmits);
+        last_retransmits = total;
+        Some(since)
+    };
+
+    while start.elapsed() < duration {
+        if let Some(pacer) = &mut pacer {
+            pacer.wait(length);
+        }
+        if let Some(payload) = &payload {
+            payload.fill(bytes, sink.buf_mut());
+        }
+        sink.write_all(&mut stream)?;
+        bytes += length as u64;
+        if recorder.add(length as u64) {
+            let retransmits = retransmits_since(&stream);
+            recorder.flush(|interval| interval.retransmits = retransmits);
+        }
     }
+    stream.shutdown(net::Shutdown::Write)?;
+    let end = Instant::now();
+    // the receiver closes once it has read everything
+    let _ = stream.read(&mut [0; 1]);
+
+    let retransmits = retransmits_since(&stream);
+    let intervals = recorder.finish(|interval| interval.retransmits = retransmits);
+    Ok(StreamResult {
+        bytes,
+        end,
+        intervals,
+        retransmits: sock::tcp_retransmits(&stream),
+        integrity: None,
+    })
 }
@@ -0,0 +1,418 @@
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{CryptoProvider, ring};
+use rustls::{
+    ClientConnection, ConnectionCommon, DigitallySignedStruct, ServerConnection, SideData,
+    SignatureScheme, StreamOwned,
+};
+use rustls_pki_types::pem::PemObject;
+use rustls_pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use std::io::{Read, Write};
+use std::net;
+use std::ops::{Deref, DerefMut};
+use std::path::Path;
+use std::sync::Arc;
+use std::time;
+
+use super::stats::Recorder;
+use super::tcp::{self, StreamResult};
+use super::{NetExpParams, RunOptions, Stats};
+use crate::error;
+use crate::units::Format;
+
+/// Certificate chain and private key presented by the accepting side
+pub struct Identity {
+    cert_chain: Vec<CertificateDer<'static>>,
+    key: PrivateKeyDer<'static>,
+}
+
+impl Identity {
+    /// Generate a self-signed certificate for this run
+    pub fn generate() -> error::Result<Identity> {
+        let cert = rcgen::generate_simple_self_signed(vec!["perfy".to_string()])?;
+        Ok(Identity {
+            cert_chain: vec![cert.cert.der().clone()],
+            key: PrivateKeyDer::Pkcs8(cert.signing_key.serialize_der().into()),
+        })
+    }
+
+    /// Load a PEM certificate chain and private key
+    pub fn from_pem_files(cert: &Path, key: &Path) -> error::Result<Identity> {
+        let pem_error = |path: &Path, e: rustls_pki_types::pem::Error| {
+            error::Error::new(&format!("Failed reading {}: {}", path.display(), e))
+        };
+        let cert_chain = CertificateDer::pem_file_iter(cert)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| pem_error(cert, e))?;
+        let key = PrivateKeyDer::from_pem_file(key).map_err(|e| pem_error(key, e))?;
+        Ok(Identity { cert_chain, key })
+    }
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+/// Config for the accepting side, generating a certificate if none is given
+pub(super) fn server_config(identity: Option<&Identity>) -> error::Result<rustls::ServerConfig> {
+    let generated;
+    let identity = match identity {
+        Some(identity) => identity,
+        None => {
+            generated = Identity::generate()?;
+            &generated
+        }
+    };
+    let mut config = rustls::ServerConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(identity.cert_chain.clone(), identity.key.clone_key())?;
+    // Tickets the sender never reads would make it reset the connection
+    // when it closes, discarding data still in flight
+    config.send_tls13_tickets = 0;
+    Ok(config)
+}
+
+/// Config for the connecting side
+pub(super) fn client_config() -> error::Result<rustls::ClientConfig> {
+    let provider = provider();
+    let config = rustls::ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(AcceptAnyCert(provider)))
+        .with_no_client_auth();
+    Ok(config)
+}
+
+/// The connecting side does not authenticate the accepting side: the test
+/// measures encryption overhead and certificates are usually self-signed.
+#[derive(Debug)]
+struct AcceptAnyCert(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for AcceptAnyCert {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+/// Uninitialized
+pub struct Uninit {}
+/// Bound to port
+pub struct Bound {
+    listener: net::TcpListener,
+    config: Arc<rustls::ServerConfig>,
+}
+/// Handshakes complete
+pub struct Ready<C> {
+    streams: Vec<StreamOwned<C, net::TcpStream>>,
+    /// Longest of the streams' handshakes
+    handshake: time::Duration,
+}
+
+/// Listen for TLS connections on the NetExp's port
+fn listen(name: &str, params: &NetExpParams, options: &RunOptions) -> error::Result<Bound> {
+    let config = server_config(options.tls_identity.as_deref())?;
+    let listener = options
+        .local
+        .tcp_listen(params.port, params.host, &params.marking)?;
+    println!("Started {} listener on {}", name, listener.local_addr()?);
+    Ok(Bound {
+        listener,
+        config: Arc::new(config),
+    })
+}
+
+/// Accept one connection per stream and complete each handshake as the
+/// server
+fn accept(bound: Bound, params: &NetExpParams) -> error::Result<Ready<ServerConnection>> {
+    let mut streams = Vec::new();
+    let mut handshake = time::Duration::ZERO;
+    for _ in 0..params.parallel {
+        let (mut sock, _) = bound.listener.accept()?;
+        let start = time::Instant::now();
+        let mut conn = ServerConnection::new(bound.config.clone())?;
+        while conn.is_handshaking() {
+            conn.complete_io(&mut sock)?;
+        }
+        handshake = handshake.max(start.elapsed());
+        streams.push(StreamOwned::new(conn, sock));
+    }
+    Ok(Ready { streams, handshake })
+}
+
+/// Open one connection per stream to the NetExp's host and complete each
+/// handshake as the client
+fn connect(
+    name: &str,
+    params: &NetExpParams,
+    options: &RunOptions,
+) -> error::Result<Ready<ClientConnection>> {
+    let config = Arc::new(client_config()?);
+    let addr = net::SocketAddr::new(params.host, params.port);
+    println!("{} connecting to {}", name, addr);
+    let mut streams = Vec::new();
+    let mut handshake = time::Duration::ZERO;
+    for _ in 0..params.parallel {
+        let mut sock = options.local.tcp_connect(addr, &params.marking)?;
+        let start = time::Instant::now();
+        let server_name = ServerName::IpAddress(params.host.into());
+        let mut conn = ClientConnection::new(config.clone(), server_name)?;
+        while conn.is_handshaking() {
+            conn.complete_io(&mut sock)?;
+        }
+        handshake = handshake.max(start.elapsed());
+        streams.push(StreamOwned::new(conn, sock));
+    }
+    Ok(Ready { streams, handshake })
+}
+
+pub struct TlsRx<State = Uninit> {
+    params: NetExpParams,
+    state: State,
+}
+
+impl TlsRx<Uninit> {
+    pub fn new(params: NetExpParams) -> TlsRx<Uninit> {
+        Self {
+            params,
+            state: Uninit {},
+        }
+    }
+
+    pub fn bind(self, options: &RunOptions) -> error::Result<TlsRx<Bound>> {
+        let state = listen("TlsRx", &self.params, options)?;
+        Ok(TlsRx {
+            params: self.params,
+            state,
+        })
+    }
+
+    /// Connect to a sender that is listening for the connections
+    pub fn connect(self, options: &RunOptions) -> error::Result<TlsRx<Ready<ClientConnection>>> {
+        let state = connect("TlsRx", &self.params, options)?;
+        Ok(TlsRx {
+            params: self.params,
+            state,
+        })
+    }
+}
+
+impl TlsRx<Bound> {
+    pub fn accept(self) -> error::Result<TlsRx<Ready<ServerConnection>>> {
+        let state = accept(self.state, &self.params)?;
+        Ok(TlsRx {
+            params: self.params,
+            state,
+        })
+    }
+}
+
+impl<C, S> TlsRx<Ready<C>>
+where
+    C: DerefMut + Deref<Target = ConnectionCommon<S>> + Send + 'static,
+    S: SideData,
+{
+    pub fn run(self, options: &RunOptions) -> error::Result<Stats> {
+        let Some(stream) = self.state.streams.first() else {
+            return Err(error::Error::new("No streams to receive on"));
+        };
+        let peer_addr = stream.sock.peer_addr()?;
+        println!(
+            "Running TLS recv {}:{} for {} seconds with {} streams...",
+            peer_addr.ip(),
+            peer_addr.port(),
+            self.params.duration,
+            self.params.parallel,
+        );
+
+        let start = time::Instant::now();
+        let length = tcp::length(&self.params);
+        let format = options.format;
+        let stats = tcp::run_streams(self.state.streams, start, move |id, stream| {
+            recv_stream(id, stream, start, length, format)
+        })?;
+        Ok(stats.with_handshake(self.state.handshake))
+    }
+}
+
+/// Read until the sender ends the session
+fn recv_stream<C, S>(
+    id: u16,
+    mut stream: StreamOwned<C, net::TcpStream>,
+    start: time::Instant,
+    length: usize,
+    format: Format,
+) -> error::Result<StreamResult>
+where
+    C: DerefMut + Deref<Target = ConnectionCommon<S>>,
+    S: SideData,
+{
+    let mut buf = vec![0; length];
+    let mut recorder = Recorder::new(id, start, format);
+    let mut bytes = 0;
+    loop {
+        let n = stream.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        bytes += n as u64;
+        if recorder.add(n as u64) {
+            recorder.flush(|_| {});
+        }
+    }
+    Ok(StreamResult {
+        bytes,
+        end: time::Instant::now(),
+        intervals: recorder.finish(|_| {}),
+        retransmits: None,
+        integrity: None,
+    })
+}
+
+pub struct TlsTx<State = Uninit> {
+    params: NetExpParams,
+    state: State,
+}
+
+impl TlsTx<Uninit> {
+    pub fn new(params: NetExpParams) -> TlsTx<Uninit> {
+        Self {
+            params,
+            state: Uninit {},
+        }
+    }
+
+    pub fn init(self, options: &RunOptions) -> error::Result<TlsTx<Ready<ClientConnection>>> {
+        let state = connect("TlsTx", &self.params, options)?;
+        Ok(TlsTx {
+            params: self.params,
+            state,
+        })
+    }
+
+    /// Listen for a receiver to open the connections
+    pub fn bind(self, options: &RunOptions) -> error::Result<TlsTx<Bound>> {
+        let state = listen("TlsTx", &self.params, options)?;
+        Ok(TlsTx {
+            params: self.params,
+            state,
+        })
+    }
+}
+
+impl TlsTx<Bound> {
+    pub fn accept(self) -> error::Result<TlsTx<Ready<ServerConnection>>> {
+        let state = accept(self.state, &self.params)?;
+        Ok(TlsTx {
+            params: self.params,
+            state,
+        })
+    }
+}
+
+impl<C, S> TlsTx<Ready<C>>
+where
+    C: DerefMut + Deref<Target = ConnectionCommon<S>> + Send + 'static,
+    S: SideData,
+{
+    pub fn run(self, options: &RunOptions) -> error::Result<Stats> {
+        let Some(stream) = self.state.streams.first() else {
+            return Err(error::Error::new("No streams to send on"));
+        };
+        let peer_addr = stream.sock.peer_addr()?;
+        println!(
+            "Running TLS send {}:{} for {} seconds with {} streams...",
+            peer_addr.ip(),
+            peer_addr.port(),
+            self.params.duration,
+            self.params.parallel,
+        );
+
+        let start = time::Instant::now();
+        let duration = time::Duration::from_secs(self.params.duration.into());
+        let length = tcp::length(&self.params);
+        let format = options.format;
+        let stats = tcp::run_streams(self.state.streams, start, move |id, stream| {
+            send_stream(id, stream, start, duration, length, format)
+        })?;
+        Ok(stats.with_handshake(self.state.handshake))
+    }
+}
+
+/// Write until `duration` has passed, then end the session and wait for
+/// the receiver to read everything and close the connection
+fn send_stream<C, S>(
+    id: u16,
+    mut stream: StreamOwned<C, net::TcpStream>,
+    start: time::Instant,
+    duration: time::Duration,
+    length: usize,
+    format: Format,
+) -> error::Result<StreamResult>
+where
+    C: DerefMut + Deref<Target = ConnectionCommon<S>>,
+    S: SideData,
+{
+    let buf = vec![0; length];
+    let mut recorder = Recorder::new(id, start, format);
+    let mut bytes = 0;
+    while start.elapsed() < duration {
+        stream.write_all(&buf)?;
+        bytes += length as u64;
+        if recorder.add(length as u64) {
+            recorder.flush(|_| {});
+        }
+    }
+    stream.conn.send_close_notify();
+    stream.flush()?;
+    stream.sock.shutdown(net::Shutdown::Write)?;
+    let end = time::Instant::now();
+    // the receiver closes once it has read everything
+    let _ = stream.sock.read(&mut [0; 1]);
+
+    Ok(StreamResult {
+        bytes,
+        end,
+        intervals: recorder.finish(|_| {}),
+        retransmits: None,
+        integrity: None,
+    })
+}
@@ -1,13 +1,87 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io;
 use std::net;
+use std::thread;
+use std::time::{self, Instant, SystemTime, UNIX_EPOCH};
 
-use super::NetExpParams;
+use super::engine::Engine;
+use super::mmsg;
+use super::pacer::Pacer;
+use super::payload::{Payload, Verifier};
+use super::sock;
+use super::stats::{DatagramIo, Integrity, Interval, Recorder};
+use super::{NetExpParams, RunOptions, Stats};
 use crate::error;
+use crate::units::Format;
+
+/// Size of each datagram when the NetExp does not set one
+const DEFAULT_LENGTH: usize = 1400;
+
+/// Stream id, sequence number and send time in nanoseconds
+const HEADER_SIZE: usize = 18;
+
+/// Sequence number marking the end of a stream
+const FIN_SEQ: u64 = u64::MAX;
+
+/// Copies of the end-of-stream datagram sent, in case some are lost
+const FIN_COPIES: usize = 3;
+
+/// Sequence number of the datagrams a connected receiver sends until data
+/// arrives, telling a listening sender where to send
+const HELLO_SEQ: u64 = u64::MAX - 1;
+
+/// How long a listening sender waits for the receiver's first hello
+const HELLO_TIMEOUT: time::Duration = time::Duration::from_secs(10);
+
+/// How long the receiver waits for stragglers once the sender should be done
+const GRACE: time::Duration = time::Duration::from_secs(2);
+
+/// How often the receiver wakes up to close intervals when idle
+const POLL_INTERVAL: time::Duration = time::Duration::from_millis(100);
+
+fn length(params: &NetExpParams) -> usize {
+    match params.length as usize {
+        0 => DEFAULT_LENGTH,
+        length => length.max(HEADER_SIZE),
+    }
+}
+
+fn now_nanos() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0)
+}
+
+fn write_header(buf: &mut [u8], stream: u16, seq: u64) {
+    buf[0..2].copy_from_slice(&stream.to_be_bytes());
+    buf[2..10].copy_from_slice(&seq.to_be_bytes());
+    buf[10..18].copy_from_slice(&now_nanos().to_be_bytes());
+}
+
+/// Returns the stream id, sequence number and send time of a datagram
+fn read_header(buf: &[u8]) -> Option<(u16, u64, u64)> {
+    if buf.len() < HEADER_SIZE {
+        return None;
+    }
+    Some((
+        u16::from_be_bytes(buf[0..2].try_into().ok()?),
+        u64::from_be_bytes(buf[2..10].try_into().ok()?),
+        u64::from_be_bytes(buf[10..18].try_into().ok()?),
+    ))
+}
 
 /// Uninitialized
 pub struct Uninit {}
+/// Bound to port
+pub struct Bound {
+    socket: net::UdpSocket,
+}
 /// Ready
 pub struct Ready {
-    socket: net::UdpSocket,
+    sockets: Vec<net::UdpSocket>,
+    /// Send hellos until the first datagram arrives
+    hello: bool,
 }
 
 pub struct UdpRx<State = Uninit> {
@@ -23,29 +97,299 @@ impl UdpRx<Uninit> {
         }
     }
 
-    pub fn bind(self) -> error::Result<UdpRx<Ready>> {
-        let addr = format!("{}:{}", self.params.host, self.params.port);
-        let socket = net::UdpSocket::bind(addr.clone())?;
-        println!("Started UdpRx listener on {}", addr);
+    pub fn bind(self, options: &RunOptions) -> error::Result<UdpRx<Ready>> {
+        let marking = &self.params.marking;
+        let socket = match self.params.multicast {
+            Some(group) => {
+                let socket = options.local.udp_join(group, self.params.port, marking)?;
+                println!("UdpRx joined {} on port {}", group, self.params.port);
+                socket
+            }
+            None => {
+                let socket = options
+                    .local
+                    .udp_bind(self.params.port, self.params.host, marking)?;
+                println!("Started UdpRx listener on {}", socket.local_addr()?);
+                socket
+            }
+        };
+        sock::set_recv_tos(&socket)?;
         Ok(UdpRx {
             params: self.params,
-            state: Ready { socket },
+            state: Ready {
+                sockets: vec![socket],
+                hello: false,
+            },
         })
     }
+
+    /// Receive from a sender that is listening on the NetExp's port, every
+    /// stream arrives over the one socket that says hello to it
+    pub fn connect(self, options: &RunOptions) -> error::Result<UdpRx<Ready>> {
+        let addr = net::SocketAddr::new(self.params.host, self.params.port);
+        println!("UdpRx connecting to {}", addr);
+        let socket = options.local.udp_connect(addr, &self.params.marking)?;
+        sock::set_recv_tos(&socket)?;
+        Ok(UdpRx {
+            params: self.params,
+            state: Ready {
+                sockets: vec![socket],
+                hello: true,
+            },
+        })
+    }
+}
+
+/// What the receiver has seen of one stream
+struct RxStream {
+    recorder: Recorder,
+    bytes: u64,
+    packets: u64,
+    lost: u64,
+    next_seq: u64,
+    /// Datagrams that arrived after a later one
+    misordered: u64,
+    verifier: Option<Verifier>,
+    /// Counters of the current interval
+    interval_packets: u64,
+    interval_lost: u64,
+    /// Last transit time in nanoseconds, and the RFC 3550 jitter estimate
+    transit: Option<i128>,
+    jitter: f64,
+    done: bool,
+}
+
+impl RxStream {
+    fn new(id: u16, start: Instant, verifier: Option<Verifier>, format: Format) -> RxStream {
+        RxStream {
+            recorder: Recorder::new(id, start, format),
+            bytes: 0,
+            packets: 0,
+            lost: 0,
+            next_seq: 0,
+            misordered: 0,
+            verifier,
+            interval_packets: 0,
+            interval_lost: 0,
+            transit: None,
+            jitter: 0f64,
+            done: false,
+        }
+    }
+
+    fn receive(&mut self, datagram: &[u8], seq: u64, sent: u64) {
+        let n = datagram.len();
+        if let Some(verifier) = &mut self.verifier {
+            let payload = &datagram[HEADER_SIZE..];
+            verifier.check(seq * payload.len() as u64, payload);
+        }
+        if seq >= self.next_seq {
+            self.interval_lost += seq - self.next_seq;
+            self.next_seq = seq + 1;
+        } else {
+            self.misordered += 1;
+            // a datagram counted as lost arrived late
+            if self.interval_lost > 0 {
+                self.interval_lost -= 1;
+            } else {
+                self.lost = self.lost.saturating_sub(1);
+            }
+        }
+        self.interval_packets += 1;
+        self.bytes += n as u64;
+
+        let transit = now_nanos() as i128 - sent as i128;
+        if let Some(last) = self.transit {
+            let d = (transit - last).abs() as f64;
+            self.jitter += (d - self.jitter) / 16f64;
+        }
+        self.transit = Some(transit);
+
+        self.recorder.add(n as u64);
+    }
+
+    fn jitter(&self) -> time::Duration {
+        time::Duration::from_nanos(self.jitter as u64)
+    }
+
+    fn flush(&mut self) {
+        let annotation = self.annotation();
+        self.recorder.flush(annotation);
+    }
+
+    fn finish(mut self) -> RxResult {
+        let annotation = self.annotation();
+        let integrity = self.verifier.take().map(|verifier| Integrity {
+            missing_datagrams: Some(self.lost),
+            misordered_datagrams: Some(self.misordered),
+            ..verifier.finish()
+        });
+        RxResult {
+            bytes: self.bytes,
+            packets: self.packets,
+            lost: self.lost,
+            jitter: self.jitter(),
+            intervals: self.recorder.finish(annotation),
+            integrity,
+        }
+    }
+
+    /// Moves the interval counters into the totals and returns a closure
+    /// that writes them into the closed interval
+    fn annotation(&mut self) -> impl FnOnce(&mut Interval) + use<> {
+        let packets = self.interval_packets;
+        let lost = self.interval_lost;
+        let jitter = self.jitter();
+        self.packets += packets;
+        self.lost += lost;
+        self.interval_packets = 0;
+        self.interval_lost = 0;
+        move |interval| {
+            interval.packets = Some(packets);
+            interval.lost = Some(lost);
+            interval.jitter = Some(jitter);
+        }
+    }
+}
+
+/// What the receiver saw of one stream once it finished
+struct RxResult {
+    bytes: u64,
+    packets: u64,
+    lost: u64,
+    jitter: time::Duration,
+    intervals: Vec<Interval>,
+    integrity: Option<Integrity>,
 }
 
 impl UdpRx<Ready> {
-    pub fn run(&self) -> error::Result<()> {
-        let mut buf: Vec<u8> = vec![0; 1024];
+    pub fn run(&self, options: &RunOptions) -> error::Result<Stats> {
+        let socket = &self.state.sockets[0];
         println!(
-            "Running UDP recv {}:{} for {} seconds with {} threads...",
-            self.params.host, self.params.port, self.params.duration, self.params.parallel,
+            "Running UDP recv {}:{} for {} seconds with {} streams...",
+            self.params.multicast.unwrap_or(self.params.host),
+            self.params.port,
+            self.params.duration,
+            self.params.parallel,
         );
 
-        let n_bytes = self.state.socket.recv(&mut buf)?;
-        println!("Received {n_bytes} bytes");
+        let duration = time::Duration::from_secs(self.params.duration.into());
+        mmsg::check(self.params.batch, self.params.gro)?;
+        socket.set_read_timeout(Some(POLL_INTERVAL))?;
+        let mut receiver = mmsg::Receiver::new(
+            socket,
+            self.params.batch,
+            self.params.gro,
+            self.params.engine,
+        )?;
+        let mut streams: HashMap<u16, RxStream> = HashMap::new();
+        // datagrams received with each TOS or traffic class
+        let mut received_tos: BTreeMap<u8, u64> = BTreeMap::new();
+        let waiting = Instant::now();
+        let mut started = None;
+        let mut last = waiting;
+        let mut next_hello = waiting;
+
+        loop {
+            if self.state.hello && started.is_none() && next_hello <= Instant::now() {
+                let mut hello = [0; HEADER_SIZE];
+                write_header(&mut hello, 0, HELLO_SEQ);
+                // the sender may not be reachable yet, try again shortly
+                let _ = socket.send(&hello);
+                next_hello += POLL_INTERVAL;
+            }
+            match receiver.recv(socket) {
+                Ok(()) => {
+                    for (datagram, tos) in receiver.datagrams() {
+                        let Some((id, seq, sent)) = read_header(datagram) else {
+                            continue;
+                        };
+                        let (start, _) =
+                            *started.get_or_insert((Instant::now(), SystemTime::now()));
+                        let stream = streams.entry(id).or_insert_with(|| {
+                            let verifier = Payload::verifier(&self.params, id);
+                            RxStream::new(id, start, verifier, options.format)
+                        });
+                        if seq == FIN_SEQ {
+                            stream.done = true;
+                        } else if !stream.done {
+                            stream.receive(datagram, seq, sent);
+                            if let Some(tos) = tos {
+                                *received_tos.entry(tos).or_default() += 1;
+                            }
+                            last = Instant::now();
+                        }
+                    }
+                }
+                Err(e)
+                    if e.kind() == io::ErrorKind::WouldBlock
+                        || e.kind() == io::ErrorKind::TimedOut => {}
+                Err(e) => return Err(e.into()),
+            }
+
+            for stream in streams.values_mut() {
+                if stream.recorder.due() && !stream.done {
+                    stream.flush();
+                }
+            }
+
+            let finished = streams.values().filter(|s| s.done).count();
+            let timed_out = match started {
+                Some((start, _)) => {
+                    last.elapsed() > GRACE && start.elapsed() > duration
+                        || start.elapsed() > duration + GRACE * 2
+                }
+                None => waiting.elapsed() > duration + GRACE,
+            };
+            if finished == self.params.parallel as usize || timed_out {
+                break;
+            }
+        }
+
+        let Some((start, started)) = started else {
+            return Err(error::Error::new("No datagrams received"));
+        };
 
-        Ok(())
+        let mut ids: Vec<u16> = streams.keys().copied().collect();
+        ids.sort();
+        let (mut bytes, mut packets, mut lost) = (0, 0, 0);
+        let mut jitter = time::Duration::ZERO;
+        let mut intervals = Vec::new();
+        let mut integrity: Option<Integrity> = None;
+        for id in &ids {
+            let Some(stream) = streams.remove(id) else {
+                continue;
+            };
+            let result = stream.finish();
+            bytes += result.bytes;
+            packets += result.packets;
+            lost += result.lost;
+            jitter += result.jitter;
+            intervals.extend(result.intervals);
+            if let Some(i) = result.integrity {
+                integrity.get_or_insert_default().merge(&i);
+            }
+        }
+        let jitter = jitter / ids.len().max(1) as u32;
+        let packet_loss = match packets + lost {
+            0 => 0f64,
+            sent => lost as f64 * 100f64 / sent as f64,
+        };
+
+        let mut stats = Stats::new()
+            .with_started(started)
+            .with_transfer(bytes, last.duration_since(start))
+            .with_packet_loss(packet_loss)
+            .with_jitter(jitter)
+            .with_datagram_io(receiver.finish())
+            .with_intervals(intervals);
+        if !received_tos.is_empty() {
+            stats = stats.with_received_tos(received_tos);
+        }
+        if let Some(integrity) = integrity {
+            stats = stats.with_integrity(integrity);
+        }
+        Ok(stats)
     }
 }
 
@@ -62,26 +406,290 @@ impl UdpTx<Uninit> {
         }
     }
 
-    pub fn init(self) -> error::Result<UdpTx<Ready>> {
-        println!("UdpTx creating UDP socket");
-        let socket = net::UdpSocket::bind(format!("{}:0", self.params.host))?;
-        socket.connect(format!("{}:{}", self.params.host, self.params.port))?;
+    pub fn init(self, options: &RunOptions) -> error::Result<UdpTx<Ready>> {
+        println!("UdpTx creating UDP sockets");
+        let marking = &self.params.marking;
+        let mut sockets = Vec::new();
+        for _ in 0..self.params.parallel {
+            sockets.push(match self.params.multicast {
+                Some(group) => options.local.udp_multicast(
+                    net::SocketAddr::new(group, self.params.port),
+                    self.params.multicast_ttl,
+                    marking,
+                )?,
+                None => options.local.udp_connect(
+                    net::SocketAddr::new(self.params.host, self.params.port),
+                    marking,
+                )?,
+            });
+        }
         Ok(UdpTx {
             params: self.params,
-            state: Ready { socket },
+            state: Ready {
+                sockets,
+                hello: false,
+            },
+        })
+    }
+
+    /// Listen for the receiver's hello on the NetExp's port
+    pub fn bind(self, options: &RunOptions) -> error::Result<UdpTx<Bound>> {
+        let socket =
+            options
+                .local
+                .udp_bind(self.params.port, self.params.host, &self.params.marking)?;
+        println!("Started UdpTx listener on {}", socket.local_addr()?);
+        Ok(UdpTx {
+            params: self.params,
+            state: Bound { socket },
+        })
+    }
+}
+
+impl UdpTx<Bound> {
+    /// Wait for the receiver's hello, then send every stream back over the
+    /// bound socket to wherever it came from
+    pub fn accept(self) -> error::Result<UdpTx<Ready>> {
+        let socket = self.state.socket;
+        let mut buf = [0; HEADER_SIZE];
+        let waiting = Instant::now();
+        let peer = loop {
+            let timeout = HELLO_TIMEOUT.saturating_sub(waiting.elapsed());
+            if timeout.is_zero() {
+                return Err(error::Error::new("Timed out waiting for the receiver"));
+            }
+            socket.set_read_timeout(Some(timeout))?;
+            match socket.recv_from(&mut buf) {
+                Ok((n, peer)) => {
+                    if let Some((_, HELLO_SEQ, _)) = read_header(&buf[..n]) {
+                        break peer;
+                    }
+                }
+                Err(e)
+                    if e.kind() == io::ErrorKind::WouldBlock
+                        || e.kind() == io::ErrorKind::TimedOut => {}
+                Err(e) => return Err(e.into()),
+            }
+        };
+        socket.set_read_timeout(None)?;
+        self.params.marking.udp_connect(&socket, peer)?;
+        let mut sockets = Vec::new();
+        for _ in 1..self.params.parallel {
+            sockets.push(socket.try_clone()?);
+        }
+        sockets.push(socket);
+        Ok(UdpTx {
+            params: self.params,
+            state: Ready {
+                sockets,
+                hello: false,
+            },
         })
     }
 }
 
 impl UdpTx<Ready> {
-    pub fn run(&self) -> error::Result<()> {
+    pub fn run(self, options: &RunOptions) -> error::Result<Stats> {
+        let Some(socket) = self.state.sockets.first() else {
+            return Err(error::Error::new("No streams to send on"));
+        };
+        let peer_addr = socket.peer_addr()?;
         println!(
-            "Running UDP send {}:{} for {} seconds with {} threads...",
-            self.params.host, self.params.port, self.params.duration, self.params.parallel,
+            "Running UDP send {}:{} for {} seconds with {} streams...",
+            peer_addr.ip(),
+            peer_addr.port(),
+            self.params.duration,
+            self.params.parallel,
         );
 
-        self.state.socket.send("TESTING".as_bytes())?;
+        let started = SystemTime::now();
+        let start = Instant::now();
+        let duration = time::Duration::from_secs(self.params.duration.into());
+        let length = length(&self.params);
+        mmsg::check(self.params.batch, self.params.gso)?;
+        let batch = mmsg::send_batch(self.params.batch, self.params.gso, length);
+        let format = options.format;
+        let payload = Payload::for_sender(&self.params, options);
+        let handles: Vec<_> = self
+            .state
+            .sockets
+            .into_iter()
+            .enumerate()
+            .map(|(i, socket)| {
+                let sender = Sender {
+                    length,
+                    batch,
+                    gso: self.params.gso,
+                    engine: self.params.engine,
+                    pacer: Pacer::new(self.params.bitrate, self.params.burst, length * batch),
+                    payload: payload.clone(),
+                };
+                thread::spawn(move || {
+                    send_stream(i as u16, socket, start, duration, sender, format)
+                })
+            })
+            .collect();
+
+        let mut bytes = 0;
+        let mut end = start;
+        let mut intervals = Vec::new();
+        let mut datagram_io = DatagramIo::default();
+        for handle in handles {
+            let Ok(result) = handle.join() else {
+                return Err(error::Error::new("Failed joining thread"));
+            };
+            let result = result?;
+            bytes += result.bytes;
+            end = end.max(result.end);
+            intervals.extend(result.intervals);
+            datagram_io.merge(&result.datagram_io);
+        }
+
+        Ok(Stats::new()
+            .with_started(started)
+            .with_transfer(bytes, end - start)
+            .with_datagram_io(datagram_io)
+            .with_intervals(intervals))
+    }
+}
+
+/// What each stream sends and how fast
+struct Sender {
+    length: usize,
+    /// Datagrams per system call
+    batch: usize,
+    gso: bool,
+    engine: Engine,
+    pacer: Option<Pacer>,
+    /// None for zeros
+    payload: Option<Payload>,
+}
+
+/// What one stream sent, and when it finished
+struct TxResult {
+    bytes: u64,
+    end: Instant,
+    intervals: Vec<Interval>,
+    datagram_io: DatagramIo,
+}
+
+/// Send datagrams as fast as the pacer allows, or as fast as possible
+/// without one, until `duration` has passed
+fn send_stream(
+    id: u16,
+    socket: net::UdpSocket,
+    start: Instant,
+    duration: time::Duration,
+    sender: Sender,
+    format: Format,
+) -> error::Result<TxResult> {
+    let Sender {
+        length,
+        batch,
+        gso,
+        engine,
+        mut pacer,
+        payload,
+    } = sender;
+    let mut io = mmsg::Sender::new(&socket, batch, gso, engine, length)?;
+    let mut recorder = Recorder::new(id, start, format);
+    let mut seq = 0;
+    let mut interval_packets = 0;
+
+    while start.elapsed() < duration {
+        if let Some(pacer) = &mut pacer {
+            pacer.wait(length * batch);
+        }
+        for (i, datagram) in io.buf_mut().chunks_mut(length).enumerate() {
+            let seq = seq + i as u64;
+            if let Some(payload) = &payload {
+                let n = length - HEADER_SIZE;
+                payload.fill(seq * n as u64, &mut datagram[HEADER_SIZE..]);
+            }
+            write_header(datagram, id, seq);
+        }
+        let sent = match io.send(&socket, length) {
+            Ok(sent) => sent,
+            // the receiver's kernel buffers are full, the datagrams are lost
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => batch,
+            Err(e) => return Err(e.into()),
+        } as u64;
+        seq += sent;
+        interval_packets += sent;
+        if recorder.add(sent * length as u64) {
+            let packets = interval_packets;
+            interval_packets = 0;
+            recorder.flush(|interval| interval.packets = Some(packets));
+        }
+    }
+    let end = Instant::now();
+
+    let mut fin = [0; HEADER_SIZE];
+    write_header(&mut fin, id, FIN_SEQ);
+    for _ in 0..FIN_COPIES {
+        // the receiver may already have stopped listening
+        let _ = socket.send(&fin);
+    }
+
+    let intervals = recorder.finish(|interval| interval.packets = Some(interval_packets));
+    Ok(TxResult {
+        bytes: seq * length as u64,
+        end,
+        intervals,
+        datagram_io: io.finish(),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::Pattern;
+    use super::*;
+
+    #[test]
+    fn test_header_roundtrip() {
+        let mut buf = [0; HEADER_SIZE];
+        write_header(&mut buf, 3, 42);
+        let (stream, seq, sent) = read_header(&buf).unwrap();
+        assert_eq!((stream, seq), (3, 42));
+        assert!(sent > 0);
+        assert_eq!(read_header(&buf[..HEADER_SIZE - 1]), None);
+    }
+
+    #[test]
+    fn test_rx_stream_counts_loss_and_reordering() {
+        let mut stream = RxStream::new(0, Instant::now(), None, Format::default());
+        let sent = now_nanos();
+        stream.receive(&[0; 100], 0, sent);
+        stream.receive(&[0; 100], 3, sent);
+        stream.receive(&[0; 100], 2, sent);
+        let result = stream.finish();
+        assert_eq!((result.bytes, result.packets, result.lost), (300, 3, 1));
+        assert_eq!(result.intervals[0].lost, Some(1));
+        assert_eq!(result.intervals[0].packets, Some(3));
+    }
 
-        Ok(())
+    #[test]
+    fn test_rx_stream_verifies_payload() {
+        let payload = Payload::new(Pattern::Random, 9);
+        let verifier = Verifier::new(0, payload.clone());
+        let mut stream = RxStream::new(0, Instant::now(), Some(verifier), Format::default());
+        let datagram = |seq: u64| {
+            let mut buf = vec![0; HEADER_SIZE + 50];
+            payload.fill(seq * 50, &mut buf[HEADER_SIZE..]);
+            buf
+        };
+        let sent = now_nanos();
+        stream.receive(&datagram(0), 0, sent);
+        stream.receive(&datagram(2), 2, sent);
+        stream.receive(&datagram(1), 1, sent);
+        let mut corrupted = datagram(4);
+        corrupted[HEADER_SIZE + 5] ^= 0xff;
+        stream.receive(&corrupted, 4, sent);
+        let integrity = stream.finish().integrity.unwrap();
+        assert_eq!(integrity.checked, 200);
+        assert_eq!(integrity.corrupted, 1);
+        assert_eq!(integrity.first_corrupted, Some((0, 205)));
+        assert_eq!(integrity.missing_datagrams, Some(1));
+        assert_eq!(integrity.misordered_datagrams, Some(1));
     }
 }
@@ -0,0 +1,515 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::Shutdown;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram, UnixListener, UnixStream};
+use std::time;
+
+use super::stats::Recorder;
+use super::tcp::{self, StreamResult};
+use super::{NetExpParams, RunOptions, Stats, UnixParams};
+use crate::error;
+use crate::units::Format;
+
+/// Size of each datagram sent in datagram mode
+const DGRAM_SIZE: usize = 8 * 1024;
+
+/// Stream id and sequence number at the start of each datagram
+const HEADER_SIZE: usize = 10;
+
+/// Sequence number marking the end of a stream
+const FIN_SEQ: u64 = u64::MAX;
+
+/// How long the datagram receiver waits for the first datagram
+const FIRST_DATAGRAM_TIMEOUT: time::Duration = time::Duration::from_secs(10);
+
+/// How long the datagram receiver waits for more data before giving up
+const DGRAM_IDLE_TIMEOUT: time::Duration = time::Duration::from_secs(1);
+
+/// Resolve the socket path, a leading '@' selects the Linux abstract namespace
+fn socket_addr(path: &str) -> error::Result<SocketAddr> {
+    match path.strip_prefix('@') {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            Ok(SocketAddr::from_abstract_name(name)?)
+        }
+        #[cfg(not(target_os = "linux"))]
+        Some(_) => Err(error::Error::new(
+            "Abstract sockets are only supported on Linux",
+        )),
+        None => Ok(SocketAddr::from_pathname(path)?),
+    }
+}
+
+fn write_header(buf: &mut [u8], stream: u16, seq: u64) {
+    buf[0..2].copy_from_slice(&stream.to_be_bytes());
+    buf[2..10].copy_from_slice(&seq.to_be_bytes());
+}
+
+/// Returns the stream id and sequence number of a datagram
+fn read_header(buf: &[u8]) -> Option<(u16, u64)> {
+    Some((
+        u16::from_be_bytes(buf.get(0..2)?.try_into().ok()?),
+        u64::from_be_bytes(buf.get(2..10)?.try_into().ok()?),
+    ))
+}
+
+/// Remove a socket file left behind by an earlier run so bind succeeds,
+/// leaving anything else at the path, which the peer chose, alone
+fn remove_stale_socket(path: &str) -> error::Result<()> {
+    if path.starts_with('@') {
+        return Ok(());
+    }
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if !metadata.file_type().is_socket() => {
+            return Err(error::Error::new(&format!(
+                "Failed removing stale socket {}: path exists and is not a socket",
+                path
+            )));
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        _ => {}
+    }
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(error::Error::new(&format!(
+            "Failed removing stale socket {}: {}",
+            path, e
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// Uninitialized
+pub struct Uninit {}
+/// Bound to path
+pub struct Bound {
+    listener: UnixListener,
+}
+/// Ready
+pub struct Ready<S> {
+    sockets: Vec<S>,
+}
+
+pub struct UnixStreamRx<State = Uninit> {
+    params: NetExpParams,
+    unix: UnixParams,
+    state: State,
+}
+
+impl UnixStreamRx<Uninit> {
+    pub fn new(params: NetExpParams, unix: UnixParams) -> UnixStreamRx<Uninit> {
+        Self {
+            params,
+            unix,
+            state: Uninit {},
+        }
+    }
+
+    pub fn bind(self) -> error::Result<UnixStreamRx<Bound>> {
+        remove_stale_socket(&self.unix.path)?;
+        let listener = UnixListener::bind_addr(&socket_addr(&self.unix.path)?)?;
+        println!("Started UnixStreamRx listener on {}", self.unix.path);
+        Ok(UnixStreamRx {
+            params: self.params,
+            unix: self.unix,
+            state: Bound { listener },
+        })
+    }
+}
+
+impl UnixStreamRx<Bound> {
+    pub fn accept(self) -> error::Result<UnixStreamRx<Ready<UnixStream>>> {
+        let mut sockets = Vec::new();
+        for _ in 0..self.params.parallel {
+            let (socket, _) = self.state.listener.accept()?;
+            sockets.push(socket);
+        }
+        Ok(UnixStreamRx {
+            params: self.params,
+            unix: self.unix,
+            state: Ready { sockets },
+        })
+    }
+}
+
+impl UnixStreamRx<Ready<UnixStream>> {
+    pub fn run(self, options: &RunOptions) -> error::Result<Stats> {
+        println!(
+            "Running Unix stream recv {} for {} seconds with {} streams...",
+            self.unix.path, self.params.duration, self.params.parallel,
+        );
+
+        let start = time::Instant::now();
+        let length = tcp::length(&self.params);
+        let format = options.format;
+        let stats = tcp::run_streams(self.state.sockets, start, move |id, socket| {
+            recv_stream(id, socket, start, length, format)
+        });
+        remove_stale_socket(&self.unix.path)?;
+        stats
+    }
+}
+
+/// Read until the sender closes the stream
+fn recv_stream(
+    id: u16,
+    mut socket: UnixStream,
+    start: time::Instant,
+    length: usize,
+    format: Format,
+) -> error::Result<StreamResult> {
+    let mut buf = vec![0; length];
+    let mut recorder = Recorder::new(id, start, format);
+    let mut bytes = 0;
+    loop {
+        let n = socket.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        bytes += n as u64;
+        if recorder.add(n as u64) {
+            recorder.flush(|_| {});
+        }
+    }
+    Ok(StreamResult {
+        bytes,
+        end: time::Instant::now(),
+        intervals: recorder.finish(|_| {}),
+        retransmits: None,
+        integrity: None,
+    })
+}
+
+pub struct UnixStreamTx<State = Uninit> {
+    params: NetExpParams,
+    unix: UnixParams,
+    state: State,
+}
+
+impl UnixStreamTx<Uninit> {
+    pub fn new(params: NetExpParams, unix: UnixParams) -> UnixStreamTx<Uninit> {
+        Self {
+            params,
+            unix,
+            state: Uninit {},
+        }
+    }
+
+    pub fn init(self) -> error::Result<UnixStreamTx<Ready<UnixStream>>> {
+        println!("UnixStreamTx connecting to {}", self.unix.path);
+        let addr = socket_addr(&self.unix.path)?;
+        let mut sockets = Vec::new();
+        for _ in 0..self.params.parallel {
+            sockets.push(UnixStream::connect_addr(&addr)?);
+        }
+        Ok(UnixStreamTx {
+            params: self.params,
+            unix: self.unix,
+            state: Ready { sockets },
+        })
+    }
+}
+
+impl UnixStreamTx<Ready<UnixStream>> {
+    pub fn run(self, options: &RunOptions) -> error::Result<Stats> {
+        println!(
+            "Running Unix stream send {} for {} seconds with {} streams...",
+            self.unix.path, self.params.duration, self.params.parallel,
+        );
+
+        let start = time::Instant::now();
+        let duration = time::Duration::from_secs(self.params.duration.into());
+        let length = tcp::length(&self.params);
+        let format = options.format;
+        tcp::run_streams(self.state.sockets, start, move |id, socket| {
+            send_stream(id, socket, start, duration, length, format)
+        })
+    }
+}
+
+/// Write until `duration` has passed, then wait for the receiver to read
+/// everything and close the stream
+fn send_stream(
+    id: u16,
+    mut socket: UnixStream,
+    start: time::Instant,
+    duration: time::Duration,
+    length: usize,
+    format: Format,
+) -> error::Result<StreamResult> {
+    let buf = vec![0; length];
+    let mut recorder = Recorder::new(id, start, format);
+    let mut bytes = 0;
+    while start.elapsed() < duration {
+        socket.write_all(&buf)?;
+        bytes += length as u64;
+        if recorder.add(length as u64) {
+            recorder.flush(|_| {});
+        }
+    }
+    socket.shutdown(Shutdown::Write)?;
+    let end = time::Instant::now();
+    // the receiver closes once it has read everything
+    let _ = socket.read(&mut [0; 1]);
+
+    Ok(StreamResult {
+        bytes,
+        end,
+        intervals: recorder.finish(|_| {}),
+        retransmits: None,
+        integrity: None,
+    })
+}
+
+pub struct UnixDatagramRx<State = Uninit> {
+    params: NetExpParams,
+    unix: UnixParams,
+    state: State,
+}
+
+impl UnixDatagramRx<Uninit> {
+    pub fn new(params: NetExpParams, unix: UnixParams) -> UnixDatagramRx<Uninit> {
+        Self {
+            params,
+            unix,
+            state: Uninit {},
+        }
+    }
+
+    pub fn bind(self) -> error::Result<UnixDatagramRx<Ready<UnixDatagram>>> {
+        remove_stale_socket(&self.unix.path)?;
+        let socket = UnixDatagram::bind_addr(&socket_addr(&self.unix.path)?)?;
+        println!("Started UnixDatagramRx listener on {}", self.unix.path);
+        Ok(UnixDatagramRx {
+            params: self.params,
+            unix: self.unix,
+            state: Ready {
+                sockets: vec![socket],
+            },
+        })
+    }
+}
+
+/// What arrived from one sending stream
+struct RxStream {
+    recorder: Recorder,
+    bytes: u64,
+    datagrams: u64,
+    /// Highest sequence number seen, plus one
+    sent: u64,
+    end: time::Instant,
+}
+
+impl UnixDatagramRx<Ready<UnixDatagram>> {
+    pub fn run(self, options: &RunOptions) -> error::Result<Stats> {
+        println!(
+            "Running Unix datagram recv {} for {} seconds with {} streams...",
+            self.unix.path, self.params.duration, self.params.parallel,
+        );
+        let stats = self.receive(options);
+        remove_stale_socket(&self.unix.path)?;
+        stats
+    }
+
+    /// Receive until every stream has ended, or nothing arrives for a while
+    fn receive(&self, options: &RunOptions) -> error::Result<Stats> {
+        let Some(socket) = self.state.sockets.first() else {
+            return Err(error::Error::new("No socket to receive on"));
+        };
+        let started = time::SystemTime::now();
+        let start = time::Instant::now();
+        let mut buf = vec![0; DGRAM_SIZE];
+        let mut streams: BTreeMap<u16, RxStream> = BTreeMap::new();
+        let mut finished = 0;
+
+        // a sender that never sends must not hold the receiver forever
+        socket.set_read_timeout(Some(FIRST_DATAGRAM_TIMEOUT))?;
+        while finished < self.params.parallel {
+            let n = match socket.recv(&mut buf) {
+                Ok(n) => n,
+                Err(e)
+                    if e.kind() == io::ErrorKind::WouldBlock
+                        || e.kind() == io::ErrorKind::TimedOut =>
+                {
+                    if streams.is_empty() {
+                        return Err(error::Error::new("No Unix datagrams arrived"));
+                    }
+                    break;
+                }
+                Err(e) => return Err(e.into()),
+            };
+            socket.set_read_timeout(Some(DGRAM_IDLE_TIMEOUT))?;
+            let Some((id, seq)) = read_header(&buf[..n]) else {
+                continue;
+            };
+            let stream = streams.entry(id).or_insert_with(|| RxStream {
+                recorder: Recorder::new(id, start, options.format),
+                bytes: 0,
+                datagrams: 0,
+                sent: 0,
+                end: start,
+            });
+            if seq == FIN_SEQ {
+                finished += 1;
+                continue;
+            }
+            stream.bytes += n as u64;
+            stream.datagrams += 1;
+            stream.sent = stream.sent.max(seq + 1);
+            stream.end = time::Instant::now();
+            if stream.recorder.add(n as u64) {
+                stream.recorder.flush(|_| {});
+            }
+        }
+
+        let sent: u64 = streams.values().map(|stream| stream.sent).sum();
+        let datagrams: u64 = streams.values().map(|stream| stream.datagrams).sum();
+        let packet_loss = match sent {
+            0 => 0f64,
+            sent => sent.saturating_sub(datagrams) as f64 * 100f64 / sent as f64,
+        };
+        let results = streams
+            .into_values()
+            .map(|stream| StreamResult {
+                bytes: stream.bytes,
+                end: stream.end,
+                intervals: stream.recorder.finish(|_| {}),
+                retransmits: None,
+                integrity: None,
+            })
+            .collect();
+        Ok(tcp::combine(started, start, results).with_packet_loss(packet_loss))
+    }
+}
+
+pub struct UnixDatagramTx<State = Uninit> {
+    params: NetExpParams,
+    unix: UnixParams,
+    state: State,
+}
+
+impl UnixDatagramTx<Uninit> {
+    pub fn new(params: NetExpParams, unix: UnixParams) -> UnixDatagramTx<Uninit> {
+        Self {
+            params,
+            unix,
+            state: Uninit {},
+        }
+    }
+
+    pub fn init(self) -> error::Result<UnixDatagramTx<Ready<UnixDatagram>>> {
+        println!("UnixDatagramTx connecting to {}", self.unix.path);
+        let addr = socket_addr(&self.unix.path)?;
+        let mut sockets = Vec::new();
+        for _ in 0..self.params.parallel {
+            let socket = UnixDatagram::unbound()?;
+            socket.connect_addr(&addr)?;
+            sockets.push(socket);
+        }
+        Ok(UnixDatagramTx {
+            params: self.params,
+            unix: self.unix,
+            state: Ready { sockets },
+        })
+    }
+}
+
+impl UnixDatagramTx<Ready<UnixDatagram>> {
+    pub fn run(self, options: &RunOptions) -> error::Result<Stats> {
+        println!(
+            "Running Unix datagram send {} for {} seconds with {} streams...",
+            self.unix.path, self.params.duration, self.params.parallel,
+        );
+
+        let start = time::Instant::now();
+        let duration = time::Duration::from_secs(self.params.duration.into());
+        let format = options.format;
+        tcp::run_streams(self.state.sockets, start, move |id, socket| {
+            send_datagrams(id, socket, start, duration, format)
+        })
+    }
+}
+
+/// Send datagrams until `duration` has passed, then mark the end of the
+/// stream
+fn send_datagrams(
+    id: u16,
+    socket: UnixDatagram,
+    start: time::Instant,
+    duration: time::Duration,
+    format: Format,
+) -> error::Result<StreamResult> {
+    let mut buf = vec![0; DGRAM_SIZE];
+    let mut recorder = Recorder::new(id, start, format);
+    let mut bytes = 0;
+    let mut seq = 0;
+    while start.elapsed() < duration {
+        write_header(&mut buf, id, seq);
+        let n = socket.send(&buf)?;
+        bytes += n as u64;
+        seq += 1;
+        if recorder.add(n as u64) {
+            recorder.flush(|_| {});
+        }
+    }
+    let end = time::Instant::now();
+    write_header(&mut buf, id, FIN_SEQ);
+    socket.send(&buf[..HEADER_SIZE])?;
+
+    Ok(StreamResult {
+        bytes,
+        end,
+        intervals: recorder.finish(|_| {}),
+        retransmits: None,
+        integrity: None,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use super::*;
+
+    #[test]
+    fn test_pathname_socket_addr() {
+        let addr = socket_addr("/tmp/perfy.sock").unwrap();
+        assert_eq!(addr.as_pathname(), Some(Path::new("/tmp/perfy.sock")));
+    }
+
+    #[test]
+    fn test_header_roundtrip() {
+        let mut buf = [0; HEADER_SIZE];
+        write_header(&mut buf, 3, 42);
+        assert_eq!(read_header(&buf), Some((3, 42)));
+        assert_eq!(read_header(&buf[..HEADER_SIZE - 1]), None);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_abstract_socket_addr() {
+        use std::os::linux::net::SocketAddrExt;
+
+        let addr = socket_addr("@perfy").unwrap();
+        assert_eq!(addr.as_abstract_name(), Some("perfy".as_bytes()));
+    }
+
+    #[test]
+    fn test_remove_stale_socket() {
+        let dir = std::env::temp_dir().join(format!("perfy-unix-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let socket = dir.join("perfy.sock");
+        let file = dir.join("victim.txt");
+        UnixListener::bind(&socket).unwrap();
+        fs::write(&file, "keep me").unwrap();
+
+        remove_stale_socket(socket.to_str().unwrap()).unwrap();
+        assert!(!socket.exists());
+        remove_stale_socket(socket.to_str().unwrap()).unwrap();
+        assert!(remove_stale_socket(file.to_str().unwrap()).is_err());
+        assert_eq!(fs::read_to_string(&file).unwrap(), "keep me");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,343 @@
+use io_uring::{IoUring, Probe, cqueue, opcode, squeue, types};
+use std::io;
+use std::os::fd::RawFd;
+use std::time::Duration;
+
+/// Buffer group of the buffers provided to multishot receives
+const BUFFER_GROUP: u16 = 0;
+
+/// user_data of a multishot receive
+const RECV: u64 = 1;
+/// user_data of a buffer given back to the kernel
+const PROVIDE: u64 = 2;
+/// user_data of cancelling the multishot receive
+const CANCEL: u64 = 3;
+
+/// Size of the io_uring_recvmsg_out header a multishot recvmsg puts at
+/// the start of each buffer
+const RECVMSG_OUT_SIZE: usize = 16;
+
+/// Fail unless the kernel lets this process set up an io_uring that
+/// supports everything the readers and writers use
+pub fn probe() -> io::Result<()> {
+    let ring = IoUring::new(2)?;
+    let mut probe = Probe::new();
+    ring.submitter().register_probe(&mut probe)?;
+    let missing = |what: &str| {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("the kernel's io_uring lacks {}", what),
+        ))
+    };
+    for (code, name) in [
+        (opcode::WriteFixed::CODE, "WRITE_FIXED"),
+        (opcode::ProvideBuffers::CODE, "PROVIDE_BUFFERS"),
+        (opcode::RecvMulti::CODE, "RECV"),
+        (opcode::RecvMsgMulti::CODE, "RECVMSG"),
+        (opcode::AsyncCancel::CODE, "ASYNC_CANCEL"),
+        // multishot receives arrived in 6.0 along with SEND_ZC, which the
+        // probe can tell apart where it cannot tell a multishot RECV from
+        // a plain one
+        (opcode::SendZc::CODE, "multishot receives"),
+    ] {
+        if !probe.is_supported(code) {
+            return missing(name);
+        }
+    }
+    if !ring.params().is_feature_ext_arg() {
+        return missing("EXT_ARG");
+    }
+    if !ring.params().is_feature_skip_cqe_on_success() {
+        return missing("CQE_SKIP_SUCCESS");
+    }
+    Ok(())
+}
+
+/// Writes a socket from one buffer registered with an io_uring
+pub struct FixedWriter {
+    ring: IoUring,
+    fd: RawFd,
+    buf: Vec<u8>,
+}
+
+impl FixedWriter {
+    /// Writer of `len` bytes at a time, with up to `depth` writes in flight
+    pub fn new(fd: RawFd, len: usize, depth: usize) -> io::Result<FixedWriter> {
+        let ring = IoUring::new(depth.max(1).next_power_of_two() as u32)?;
+        let buf = vec![0; len];
+        let iov = libc::iovec {
+            iov_base: buf.as_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        };
+        // SAFETY: buf is never resized, so stays valid until the ring is
+        // dropped along with it
+        unsafe { ring.submitter().register_buffers(&[iov])? };
+        Ok(FixedWriter { ring, fd, buf })
+    }
+
+    pub fn buf_mut(&mut self) -> &mut [u8] {
+        &mut self.buf
+    }
+
+    /// Write all of the buffer to a stream socket
+    pub fn write_all(&mut self) -> io::Result<()> {
+        let mut offset = 0;
+        while offset < self.buf.len() {
+            let n = self.write(offset, self.buf.len() - offset)?;
+            if n == 0 {
+                return Err(io::ErrorKind::WriteZero.into());
+            }
+            offset += n;
+        }
+        Ok(())
+    }
+
+    /// Write every `length` byte chunk of the buffer as its own datagram,
+    /// all in flight at once, returning how many the kernel took
+    pub fn send_datagrams(&mut self, length: usize) -> io::Result<usize> {
+        let chunks = self.buf.len().div_ceil(length);
+        for offset in (0..self.buf.len()).step_by(length) {
+            let len = length.min(self.buf.len() - offset);
+            self.push_write(offset, len)?;
+        }
+        self.ring.submit_and_wait(chunks)?;
+        let mut sent = 0;
+        let mut error = None;
+        for cqe in self.ring.completion() {
+            match cqe.result() {
+                n if n >= 0 => sent += 1,
+                e => error = Some(io::Error::from_raw_os_error(-e)),
+            }
+        }
+        match error {
+            Some(e) if sent == 0 => Err(e),
+            _ => Ok(sent),
+        }
+    }
+
+    fn write(&mut self, offset: usize, len: usize) -> io::Result<usize> {
+        self.push_write(offset, len)?;
+        self.ring.submit_and_wait(1)?;
+        let Some(cqe) = self.ring.completion().next() else {
+            return Err(io::ErrorKind::Other.into());
+        };
+        match cqe.result() {
+            n if n >= 0 => Ok(n as usize),
+            e => Err(io::Error::from_raw_os_error(-e)),
+        }
+    }
+
+    fn push_write(&mut self, offset: usize, len: usize) -> io::Result<()> {
+        let write = opcode::WriteFixed::new(
+            types::Fd(self.fd),
+            self.buf[offset..].as_ptr(),
+            len as u32,
+            0,
+        )
+        .build();
+        // SAFETY: the registered buffer outlives the write, which is reaped
+        // before the buffer is touched again
+        unsafe { self.ring.submission().push(&write) }
+            .map_err(|_| io::Error::other("io_uring submission queue is full"))
+    }
+}
+
+/// Receives from a socket with a multishot receive into buffers provided
+/// to the kernel, each holding one read or datagram
+pub struct MultishotReader {
+    ring: IoUring,
+    fd: RawFd,
+    bufs: Vec<u8>,
+    buf_len: usize,
+    /// Header of a multishot recvmsg, which also takes each datagram's
+    /// control messages, None to receive a stream
+    msg: Option<Box<libc::msghdr>>,
+    armed: bool,
+    eof: bool,
+    /// Buffer id and length of everything the last call received
+    received: Vec<(u16, usize)>,
+}
+
+impl MultishotReader {
+    /// Reader of a stream with `count` buffers of `buf_len` bytes
+    pub fn new(fd: RawFd, buf_len: usize, count: u16) -> io::Result<MultishotReader> {
+        MultishotReader::with_msg(fd, buf_len, count, None)
+    }
+
+    /// Reader of datagrams up to `payload_len` bytes with `count` buffers,
+    /// keeping up to `control_len` bytes of control messages of each
+    pub fn datagrams(
+        fd: RawFd,
+        payload_len: usize,
+        control_len: usize,
+        count: u16,
+    ) -> io::Result<MultishotReader> {
+        // SAFETY: msghdr is plain data, all zeroes is a valid value
+        let mut msg: Box<libc::msghdr> = Box::new(unsafe { std::mem::zeroed() });
+        msg.msg_controllen = control_len as _;
+        let buf_len = RECVMSG_OUT_SIZE + control_len + payload_len;
+        MultishotReader::with_msg(fd, buf_len, count, Some(msg))
+    }
+
+    fn with_msg(
+        fd: RawFd,
+        buf_len: usize,
+        count: u16,
+        msg: Option<Box<libc::msghdr>>,
+    ) -> io::Result<MultishotReader> {
+        let mut reader = MultishotReader {
+            // room to give back every buffer and re-arm the receive at once
+            ring: IoUring::new((count as u32 + 1).next_power_of_two())?,
+            fd,
+            bufs: vec![0; buf_len * count as usize],
+            buf_len,
+            msg,
+            armed: false,
+            eof: false,
+            received: Vec::new(),
+        };
+        reader.provide(0, count, squeue::Flags::empty())?;
+        reader.ring.submit_and_wait(1)?;
+        if let Some(cqe) = reader.ring.completion().next()
+            && cqe.result() < 0
+        {
+            return Err(io::Error::from_raw_os_error(-cqe.result()));
+        }
+        Ok(reader)
+    }
+
+    /// Wait up to `timeout` for data, failing with TimedOut if none
+    /// arrives. What arrived is in [`MultishotReader::chunks`] until the
+    /// next call.
+    pub fn recv(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        // the kernel may fill the buffers read last time again, only
+        // failing to take one back wakes the wait below
+        for (id, _) in std::mem::take(&mut self.received) {
+            self.provide(id, 1, squeue::Flags::SKIP_SUCCESS)?;
+        }
+        if !self.armed {
+            let fd = types::Fd(self.fd);
+            let recv = match &self.msg {
+                Some(msg) => opcode::RecvMsgMulti::new(fd, &**msg, BUFFER_GROUP).build(),
+                None => opcode::RecvMulti::new(fd, BUFFER_GROUP).build(),
+            }
+            .user_data(RECV);
+            // SAFETY: the provided buffers and msghdr outlive the ring
+            unsafe { self.ring.submission().push(&recv) }
+                .map_err(|_| io::Error::other("io_uring submission queue is full"))?;
+            self.armed = true;
+        }
+        let submitted = match timeout {
+            Some(timeout) => {
+                let timespec = types::Timespec::from(timeout);
+                let args = types::SubmitArgs::new().timespec(&timespec);
+                self.ring.submitter().submit_with_args(1, &args)
+            }
+            None => self.ring.submit_and_wait(1),
+        };
+        match submitted {
+            Ok(_) => {}
+            Err(e) if matches!(e.raw_os_error(), Some(libc::ETIME | libc::EINTR)) => {
+                return Err(io::ErrorKind::TimedOut.into());
+            }
+            Err(e) => return Err(e),
+        }
+
+        let completions: Vec<cqueue::Entry> = self.ring.completion().collect();
+        for cqe in completions {
+            let result = cqe.result();
+            if cqe.user_data() == PROVIDE {
+                if result < 0 {
+                    return Err(io::Error::from_raw_os_error(-result));
+                }
+                continue;
+            }
+            if !cqueue::more(cqe.flags()) {
+                self.armed = false;
+            }
+            match result {
+                // every buffer is in use until the next call gives them back
+                e if e == -libc::ENOBUFS => {}
+                e if e < 0 => return Err(io::Error::from_raw_os_error(-e)),
+                0 => self.eof = true,
+                n => {
+                    let Some(id) = cqueue::buffer_select(cqe.flags()) else {
+                        return Err(io::Error::other("io_uring receive without a buffer"));
+                    };
+                    self.received.push((id, n as usize));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// What the last call received, in order
+    pub fn chunks(&self) -> impl Iterator<Item = &[u8]> {
+        self.received.iter().map(|(id, len)| {
+            let start = *id as usize * self.buf_len;
+            &self.bufs[start..start + len]
+        })
+    }
+
+    /// The payload and control messages of each datagram the last call
+    /// received, in order, for a reader of datagrams
+    pub fn messages(&self) -> impl Iterator<Item = (&[u8], &[u8])> {
+        self.chunks().filter_map(|chunk| {
+            let msg = self.msg.as_deref()?;
+            let out = types::RecvMsgOut::parse(chunk, msg).ok()?;
+            // the header is followed by the name and control fields at
+            // their full lengths, and the payload fills the rest
+            let control = RECVMSG_OUT_SIZE + msg.msg_namelen as usize;
+            let payload = chunk.len() - out.payload_data().len();
+            Some((
+                &chunk[payload..],
+                &chunk[control..control + out.control_data().len()],
+            ))
+        })
+    }
+
+    /// Whether the peer closed the stream
+    pub fn eof(&self) -> bool {
+        self.eof
+    }
+
+    /// Give `count` buffers starting at `id` to the kernel
+    fn provide(&mut self, id: u16, count: u16, flags: squeue::Flags) -> io::Result<()> {
+        let start = id as usize * self.buf_len;
+        let provide = opcode::ProvideBuffers::new(
+            self.bufs[start..].as_mut_ptr(),
+            self.buf_len as i32,
+            count,
+            BUFFER_GROUP,
+            id,
+        )
+        .build()
+        .flags(flags)
+        .user_data(PROVIDE);
+        // SAFETY: the buffers are never resized, so stay valid until the
+        // ring is dropped along with them
+        unsafe { self.ring.submission().push(&provide) }
+            .map_err(|_| io::Error::other("io_uring submission queue is full"))
+    }
+}
+
+impl Drop for MultishotReader {
+    /// An armed receive holds the socket open until the ring is torn down
+    /// in the background, so cancel it to free the port along with the
+    /// socket
+    fn drop(&mut self) {
+        if !self.armed {
+            return;
+        }
+        let cancel = opcode::AsyncCancel::new(RECV).build().user_data(CANCEL);
+        // SAFETY: cancelling holds on to no buffers
+        if unsafe { self.ring.submission().push(&cancel) }.is_err() {
+            return;
+        }
+        while self.ring.submit_and_wait(1).is_ok() {
+            if self.ring.completion().any(|cqe| cqe.user_data() == CANCEL) {
+                return;
+            }
+        }
+    }
+}
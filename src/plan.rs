@@ -0,0 +1,390 @@
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+use crate::client::{self, ClientConfig, RunResult};
+use crate::error;
+use crate::netexp::{self, Family, Marking, NetExp, NetExpParams, Pattern, Side, Summary};
+use crate::units::{self, Format};
+
+/// A list of NetExps read from a TOML file, run by `perfy client run`
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Plan {
+    #[serde(default)]
+    pub mode: Mode,
+    /// Server host of steps that do not name their own
+    pub host: Option<String>,
+    /// Server port of steps that do not name their own
+    pub port: Option<u16>,
+    /// Seconds to wait between sequential steps
+    #[serde(default)]
+    pub pause: u16,
+    #[serde(rename = "step")]
+    pub steps: Vec<Step>,
+}
+
+/// How the steps of a Plan are run
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    /// One after another, in the order they are listed
+    #[default]
+    Sequential,
+    /// All at once, each step needs a Server of its own
+    Concurrent,
+}
+
+/// One NetExp of a Plan, options left out take the same defaults as on
+/// the command line
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Step {
+    /// Shown in the report, defaults to the step's number
+    pub name: Option<String>,
+    pub protocol: Protocol,
+    pub host: Option<String>,
+    pub port: Option<u16>,
+    /// Send data from the Server to the Client
+    #[serde(default)]
+    pub reverse: bool,
+    pub parallel: Option<u16>,
+    pub duration: Option<u16>,
+    /// Bit rate such as "100M", as with --bitrate
+    pub bitrate: Option<String>,
+    pub length: Option<u32>,
+    pub burst: Option<u32>,
+    #[serde(default)]
+    pub omit: u16,
+    pub repeat: Option<u16>,
+    pub tos: Option<u8>,
+    /// zeros, random or incompressible
+    pub payload: Option<String>,
+    pub seed: Option<u64>,
+    #[serde(default)]
+    pub verify: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    Tcp,
+    Udp,
+    Tls,
+    Quic,
+}
+
+/// What one step of a Plan measured, or why it failed
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StepReport {
+    pub name: String,
+    pub protocol: Protocol,
+    pub reverse: bool,
+    pub runs: Vec<RunResult>,
+    pub error: Option<String>,
+}
+
+/// Results of every step, as saved with `--json`
+#[derive(Serialize, Deserialize)]
+struct Saved {
+    steps: Vec<StepReport>,
+}
+
+impl Plan {
+    /// Read a Plan from a TOML file
+    pub fn load(path: &Path) -> error::Result<Plan> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| error::Error::new(&format!("Failed opening {}: {}", path.display(), e)))?;
+        Plan::parse(&text)
+            .map_err(|e| error::Error::new(&format!("Invalid plan {}: {}", path.display(), e)))
+    }
+
+    pub fn parse(text: &str) -> error::Result<Plan> {
+        let plan: Plan = toml::from_str(text).map_err(|e| error::Error::new(&e.to_string()))?;
+        if plan.steps.is_empty() {
+            return Err(error::Error::new("A plan needs at least one [[step]]"));
+        }
+        for (i, step) in plan.steps.iter().enumerate() {
+            if step.host.as_ref().or(plan.host.as_ref()).is_none() {
+                return Err(error::Error::new(&format!("Step {} has no host", i + 1)));
+            }
+            if step.port.or(plan.port).is_none() {
+                return Err(error::Error::new(&format!("Step {} has no port", i + 1)));
+            }
+        }
+        Ok(plan)
+    }
+
+    /// Run every step, a failed step is reported and does not stop the
+    /// others
+    pub fn run(&self, config: &ClientConfig) -> Vec<StepReport> {
+        let run_step = |i: usize, step: &Step| {
+            let name = step
+                .name
+                .clone()
+                .unwrap_or_else(|| format!("step {}", i + 1));
+            println!("Step {}: {}", i + 1, name);
+            let result = step
+                .net_exp(self)
+                .and_then(|net_exp| client::run(net_exp, config));
+            if let Err(e) = &result {
+                eprintln!("Step {} failed: {}", i + 1, e.message);
+            }
+            StepReport {
+                name,
+                protocol: step.protocol,
+                reverse: step.reverse,
+                error: result.as_ref().err().map(|e| e.message.clone()),
+                runs: result.unwrap_or_default(),
+            }
+        };
+        match self.mode {
+            Mode::Sequential => self
+                .steps
+                .iter()
+                .enumerate()
+                .map(|(i, step)| {
+                    if i > 0 {
+                        thread::sleep(Duration::from_secs(self.pause.into()));
+                    }
+                    run_step(i, step)
+                })
+                .collect(),
+            Mode::Concurrent => thread::scope(|scope| {
+                let handles: Vec<_> = self
+                    .steps
+                    .iter()
+                    .enumerate()
+                    .map(|(i, step)| scope.spawn(move || run_step(i, step)))
+                    .collect();
+                handles
+                    .into_iter()
+                    .map(|handle| handle.join().expect("plan step panicked"))
+                    .collect()
+            }),
+        }
+    }
+}
+
+impl Step {
+    /// The NetExp this step runs, the Plan fills in the host and port
+    pub fn net_exp(&self, plan: &Plan) -> error::Result<NetExp> {
+        let host = self.host.as_ref().or(plan.host.as_ref());
+        let (Some(host), Some(port)) = (host, self.port.or(plan.port)) else {
+            return Err(error::Error::new("Step needs a host and port"));
+        };
+        let bitrate = match &self.bitrate {
+            Some(bitrate) => units::parse_bitrate(bitrate).map_err(|e| error::Error::new(&e))?,
+            None if self.protocol == Protocol::Udp => netexp::DEFAULT_UDP_BITRATE,
+            None => 0,
+        };
+        let payload = match self.payload.as_deref() {
+            None | Some("zeros") => Pattern::Zeros,
+            Some("random") => Pattern::Random,
+            Some("incompressible") => Pattern::Incompressible,
+            Some(payload) => {
+                return Err(error::Error::new(&format!(
+                    "Invalid payload '{}', expected zeros, random or incompressible",
+                    payload
+                )));
+            }
+        };
+        let defaults = NetExpParams::default();
+        let params = NetExpParams {
+            host: netexp::resolve(host, Family::Any)?,
+            port,
+            side: if self.reverse { Side::Rx } else { Side::Tx },
+            parallel: self.parallel.unwrap_or(defaults.parallel),
+            duration: self.duration.unwrap_or(defaults.duration),
+            bitrate,
+            length: self.length.unwrap_or(0),
+            burst: self.burst.unwrap_or(0),
+            omit: self.omit,
+            repeat: self.repeat.unwrap_or(defaults.repeat).max(1),
+            marking: Marking {
+                tos: self.tos.unwrap_or(0),
+                ..Default::default()
+            },
+            payload,
+            seed: match payload {
+                Pattern::Zeros => 0,
+                _ => self.seed.unwrap_or_else(rand::random),
+            },
+            verify: self.verify,
+            ..defaults
+        };
+        Ok(match self.protocol {
+            Protocol::Tcp => NetExp::Tcp(params),
+            Protocol::Udp => NetExp::Udp(params),
+            Protocol::Tls => NetExp::Tls(params),
+            Protocol::Quic => NetExp::Quic(params),
+        })
+    }
+}
+
+/// Save the reports of every step to `path` as JSON
+pub fn save(path: &Path, reports: Vec<StepReport>) -> error::Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    serde_json::to_writer_pretty(&mut writer, &Saved { steps: reports })?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// One line per step with the mean sender and receiver bandwidth of its
+/// runs, its loss or retransmits, and its jitter or round trip time
+pub fn format_reports(reports: &[StepReport], format: &Format) -> String {
+    let width = reports
+        .iter()
+        .map(|r| r.name.len())
+        .max()
+        .unwrap_or(0)
+        .max(4);
+    let mut lines = vec![format!(
+        "{:<width$}  {:<8}  {:<9}  {:>15}  {:>15}  {:>15}  {}",
+        "Step", "Protocol", "Direction", "Sender", "Receiver", "Loss", "Latency"
+    )];
+    for report in reports {
+        let protocol = format!("{:?}", report.protocol).to_lowercase();
+        let direction = if report.reverse { "receive" } else { "send" };
+        let mut line = format!(
+            "{:<width$}  {:<8}  {:<9}  ",
+            report.name, protocol, direction
+        );
+        if let Some(error) = &report.error {
+            line += &format!("failed: {}", error);
+            lines.push(line);
+            continue;
+        }
+        let mean_rate = |f: fn(&RunResult) -> Option<f64>| {
+            let rates: Vec<f64> = report.runs.iter().filter_map(f).collect();
+            Summary::of(&rates)
+                .map(|summary| format.rate(summary.mean))
+                .unwrap_or("n/a".to_string())
+        };
+        line += &format!(
+            "{:>15}  {:>15}  {:>15}  {}",
+            mean_rate(|r| r.sender.rate()),
+            mean_rate(|r| r.receiver.rate()),
+            client::format_loss(&report.runs).unwrap_or_default(),
+            client::format_latency(&report.runs).unwrap_or_default()
+        );
+        lines.push(line.trim_end().to_string());
+    }
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use super::*;
+    use crate::netexp::Stats;
+
+    const PLAN: &str = r#"
+        host = "127.0.0.1"
+        port = 5201
+
+        [[step]]
+        name = "upload"
+        protocol = "tcp"
+        parallel = 4
+
+        [[step]]
+        protocol = "udp"
+        port = 5202
+        reverse = true
+        bitrate = "100M"
+        tos = 0xb8
+    "#;
+
+    #[test]
+    fn test_parse_plan() {
+        let plan = Plan::parse(PLAN).expect("Failed to parse plan");
+        assert_eq!(plan.mode, Mode::Sequential);
+        assert_eq!(plan.steps.len(), 2);
+        assert_eq!(
+            plan.steps[0].net_exp(&plan).unwrap(),
+            NetExp::Tcp(NetExpParams {
+                host: IpAddr::V4(Ipv4Addr::LOCALHOST),
+                port: 5201,
+                parallel: 4,
+                ..Default::default()
+            })
+        );
+        assert_eq!(
+            plan.steps[1].net_exp(&plan).unwrap(),
+            NetExp::Udp(NetExpParams {
+                host: IpAddr::V4(Ipv4Addr::LOCALHOST),
+                port: 5202,
+                side: Side::Rx,
+                bitrate: 100_000_000,
+                marking: Marking {
+                    tos: 0xb8,
+                    ..Default::default()
+                },
+                ..Default::default()
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_invalid_plans() {
+        assert!(Plan::parse("port = 5201\n[[step]]\nprotocol = \"tcp\"").is_err());
+        assert!(Plan::parse("host = \"a\"\nport = 1\n[[step]]\nprotocol = \"sctp\"").is_err());
+        assert!(Plan::parse("host = \"a\"\nport = 1\n[[step]]\nprotocl = \"tcp\"").is_err());
+        assert!(Plan::parse("host = \"a\"\nport = 1").is_err());
+    }
+
+    #[test]
+    fn test_format_reports() {
+        let stats = |bytes, retransmits, packet_loss, jitter| Stats {
+            transfer: Some((bytes, Duration::from_secs(1))),
+            retransmits,
+            packet_loss,
+            jitter,
+            ..Default::default()
+        };
+        let reports = [
+            StepReport {
+                name: "upload".to_string(),
+                protocol: Protocol::Tcp,
+                reverse: false,
+                runs: vec![RunResult {
+                    sender: stats(125_000_000, Some(3), None, None),
+                    receiver: stats(125_000_000, None, None, None),
+                }],
+                error: None,
+            },
+            StepReport {
+                name: "voice".to_string(),
+                protocol: Protocol::Udp,
+                reverse: true,
+                runs: vec![RunResult {
+                    sender: stats(125_000, None, None, None),
+                    receiver: stats(125_000, None, Some(0.5), Some(Duration::from_millis(2))),
+                }],
+                error: None,
+            },
+            StepReport {
+                name: "down".to_string(),
+                protocol: Protocol::Quic,
+                reverse: true,
+                runs: Vec::new(),
+                error: Some("Connection refused".to_string()),
+            },
+        ];
+        assert_eq!(
+            format_reports(&reports, &Format::default()),
+            [
+                "Step    Protocol  Direction           Sender         Receiver             Loss  Latency",
+                "upload  tcp       send           1.00 Gbit/s      1.00 Gbit/s    3 retransmits",
+                "voice   udp       receive        1.00 Mbit/s      1.00 Mbit/s           0.500%  2.000 ms jitter",
+                "down    quic      receive    failed: Connection refused",
+            ]
+            .join("\n")
+        );
+    }
+}
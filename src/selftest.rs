@@ -0,0 +1,193 @@
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use crate::auth::Psk;
+use crate::client::{self, ClientConfig, RunResult};
+use crate::error;
+use crate::netexp::{NetExp, NetExpParams, RunOptions, Side};
+use crate::plan::{Protocol, StepReport};
+use crate::server::{self, ServerConfig};
+
+/// How long to wait for the Server to listen for the next Client
+const READY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A Server on an ephemeral loopback port, run in a thread of this process
+/// for `perfy selftest` and end-to-end tests of the Client and Server
+pub struct LoopbackServer {
+    pub addr: SocketAddr,
+    ready: mpsc::Receiver<()>,
+}
+
+impl LoopbackServer {
+    pub fn start(psk: Option<Psk>) -> error::Result<LoopbackServer> {
+        let host = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        // have the system pick a free port, the Server binds it again
+        let addr = TcpListener::bind((host, 0))?.local_addr()?;
+        let (ready_tx, ready) = mpsc::channel();
+        let config = ServerConfig {
+            host,
+            port: addr.port(),
+            psk,
+            run_options: RunOptions::default(),
+            metrics: None,
+            history: None,
+            ready: Some(ready_tx),
+            reload: None,
+        };
+        thread::spawn(move || {
+            if let Err(e) = server::run(config) {
+                eprintln!("Loopback server stopped: {}", e.message);
+            }
+        });
+        Ok(LoopbackServer { addr, ready })
+    }
+
+    /// Run `net_exp` against the Server once it is listening, with the
+    /// host and port of the NetExp pointed at it
+    pub fn run(&self, net_exp: &NetExp, config: &ClientConfig) -> error::Result<Vec<RunResult>> {
+        self.ready
+            .recv_timeout(READY_TIMEOUT)
+            .map_err(|_| error::Error::new("Loopback server is not listening"))?;
+        let mut net_exp = net_exp.clone();
+        let params = net_exp.params_mut();
+        params.host = self.addr.ip();
+        params.port = self.addr.port();
+        client::run(net_exp, config)
+    }
+}
+
+/// Measure what the host's network stack can move over loopback with TCP
+/// in both directions, unpaced UDP, and QUIC for its round trip time
+pub fn run(duration: u16, config: &ClientConfig) -> error::Result<Vec<StepReport>> {
+    let server = LoopbackServer::start(None)?;
+    let params = NetExpParams {
+        duration,
+        ..Default::default()
+    };
+    let reverse = NetExpParams {
+        side: Side::Rx,
+        ..params.clone()
+    };
+    let experiments = [
+        ("TCP", Protocol::Tcp, NetExp::Tcp(params.clone())),
+        ("TCP reverse", Protocol::Tcp, NetExp::Tcp(reverse)),
+        ("UDP unpaced", Protocol::Udp, NetExp::Udp(params.clone())),
+        ("QUIC", Protocol::Quic, NetExp::Quic(params)),
+    ];
+    let reports = experiments
+        .into_iter()
+        .map(|(name, protocol, net_exp)| {
+            println!("Selftest: {}", name);
+            let result = server.run(&net_exp, config);
+            if let Err(e) = &result {
+                eprintln!("{} failed: {}", name, e.message);
+            }
+            StepReport {
+                name: name.to_string(),
+                protocol,
+                reverse: net_exp.params().side == Side::Rx,
+                error: result.as_ref().err().map(|e| e.message.clone()),
+                runs: result.unwrap_or_default(),
+            }
+        })
+        .collect();
+    Ok(reports)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::netexp::Pattern;
+
+    fn params() -> NetExpParams {
+        NetExpParams {
+            duration: 1,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_tcp_both_directions() {
+        let server = LoopbackServer::start(None).unwrap();
+        let config = ClientConfig::default();
+        for side in [Side::Tx, Side::Rx] {
+            let net_exp = NetExp::Tcp(NetExpParams {
+                side,
+                parallel: 2,
+                payload: Pattern::Incompressible,
+                seed: 1,
+                verify: true,
+                ..params()
+            });
+            let runs = server.run(&net_exp, &config).unwrap();
+            assert_eq!(runs.len(), 1);
+            let (sender, receiver) = (&runs[0].sender, &runs[0].receiver);
+            let sent = sender.transfer.unwrap().0;
+            assert!(sent > 0);
+            assert_eq!(receiver.transfer.unwrap().0, sent);
+            let integrity = receiver.integrity.unwrap();
+            assert_eq!(integrity.checked, sent);
+            assert_eq!(integrity.corrupted, 0);
+            assert_eq!(integrity.missing_bytes, Some(0));
+            assert!(sender.cpu.is_some() && receiver.cpu.is_some());
+        }
+    }
+
+    #[test]
+    fn test_udp_repeat() {
+        let server = LoopbackServer::start(None).unwrap();
+        let config = ClientConfig::default();
+        let net_exp = NetExp::Udp(NetExpParams {
+            bitrate: 10_000_000,
+            repeat: 2,
+            ..params()
+        });
+        let runs = server.run(&net_exp, &config).unwrap();
+        assert_eq!(runs.len(), 2);
+        for run in runs {
+            assert!(run.sender.transfer.unwrap().0 > 0);
+            assert!(run.receiver.packet_loss.is_some());
+            assert!(run.receiver.jitter.is_some());
+        }
+    }
+
+    #[test]
+    fn test_reverse_over_client_connections() {
+        let server = LoopbackServer::start(None).unwrap();
+        let config = ClientConfig::default();
+        let reverse = NetExpParams {
+            side: Side::Rx,
+            parallel: 2,
+            bitrate: 10_000_000,
+            ..params()
+        };
+        for net_exp in [
+            NetExp::Udp(reverse.clone()),
+            NetExp::Tls(reverse.clone()),
+            NetExp::Quic(reverse),
+        ] {
+            let runs = server.run(&net_exp, &config).unwrap();
+            let sent = runs[0].sender.transfer.unwrap().0;
+            assert!(sent > 0);
+            assert!(runs[0].receiver.transfer.unwrap().0 > 0);
+        }
+    }
+
+    #[test]
+    fn test_server_rejects_wrong_psk() {
+        let server = LoopbackServer::start(Some(Psk::new(b"server").unwrap())).unwrap();
+        let config = ClientConfig {
+            psk: Some(Psk::new(b"client").unwrap()),
+            ..Default::default()
+        };
+        assert!(server.run(&NetExp::Tcp(params()), &config).is_err());
+        // the Server carries on with the next Client
+        let config = ClientConfig {
+            psk: Some(Psk::new(b"server").unwrap()),
+            ..Default::default()
+        };
+        assert!(server.run(&NetExp::Tcp(params()), &config).is_ok());
+    }
+}
@@ -1,20 +1,66 @@
 use std::io::{Read, Write};
 use std::net::IpAddr;
-use std::net::{TcpListener, TcpStream};
-use std::sync::mpsc;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::os::fd::AsRawFd;
+use std::sync::{Arc, mpsc};
 use std::thread;
 
+use crate::auth::{self, Psk};
+use crate::daemon;
 use crate::error;
-use crate::netexp::NetExp;
+use crate::history::{self, History, SessionParams};
+use crate::metrics::Metrics;
+use crate::netexp::{NetExp, RunOptions, TlsIdentity};
+
+/// How often, in milliseconds, a Server waiting for a Client checks
+/// whether it has been signalled
+const SIGNAL_POLL_MS: libc::c_int = 200;
+
+/// Reads the settings a Server replaces on SIGHUP
+pub type Reload = Box<dyn Fn() -> error::Result<Reloaded> + Send>;
+
+/// Settings read again on SIGHUP
+pub struct Reloaded {
+    pub psk: Option<Psk>,
+    /// None keeps the current identity
+    pub tls_identity: Option<Arc<TlsIdentity>>,
+}
 
 pub struct ServerConfig {
     pub host: IpAddr,
     pub port: u16,
+    /// Clients must prove they know this key before running a test
+    pub psk: Option<Psk>,
+    pub run_options: RunOptions,
+    /// Serve Prometheus metrics over HTTP on this address
+    pub metrics: Option<SocketAddr>,
+    /// Append a record of every session here
+    pub history: Option<History>,
+    /// Told each time the Server is listening for the next Client
+    pub ready: Option<mpsc::Sender<()>>,
+    /// Called between sessions after SIGHUP
+    pub reload: Option<Reload>,
 }
 
-/// The Server receives NetExp from the Client, sets up the Rx side of the
-/// NetExp if necessary, then sends "OK" to the Client.
-pub fn run(config: ServerConfig) -> error::Result<()> {
+/// The Server receives NetExp from the Client, listens for the data
+/// connections of its side of the NetExp, then sends "OK" to the Client,
+/// which opens them whichever way the data flows. After SIGTERM it
+/// finishes the session in progress and returns.
+pub fn run(mut config: ServerConfig) -> error::Result<()> {
+    config.run_options.accept = true;
+    let metrics = Arc::new(Metrics::default());
+    if let Some(addr) = config.metrics {
+        let Ok(listener) = TcpListener::bind(addr) else {
+            return Err(error::Error::new(&format!(
+                "Failed binding metrics listener to {}",
+                addr
+            )));
+        };
+        println!("serving metrics on http://{}/metrics", addr);
+        metrics.serve(listener);
+    }
+
+    let mut notified = false;
     loop {
         let Ok(listener) = TcpListener::bind(format!("{}:{}", config.host, config.port)) else {
             return Err(error::Error::new(&format!(
@@ -24,49 +70,157 @@ pub fn run(config: ServerConfig) -> error::Result<()> {
         };
 
         println!("listening on {}:{}", config.host, config.port);
+        if let Some(ready) = &config.ready {
+            let _ = ready.send(());
+        }
+        if !notified {
+            daemon::notify("READY=1");
+            notified = true;
+        }
 
-        let Ok((stream, _)) = listener.accept() else {
-            return Err(error::Error::new("Error accepting client connection"));
+        let Some((stream, peer)) = wait_for_client(&listener, &mut config)? else {
+            println!("stopping");
+            daemon::notify("STOPPING=1");
+            return Ok(());
         };
         // stop listening in case a Tcp test needs to rebind to the port
         drop(listener);
-        handle_client(stream).unwrap_or_else(|e| eprintln!("Error handling client {}", e))
+        metrics.session_started();
+        let mut record = history::Record::new(peer.ip());
+        let result = handle_client(stream, &config, &metrics, &mut record);
+        metrics.session_finished(result.is_ok());
+        if let Err(e) = &result {
+            eprintln!("Error handling client {}", e);
+            record.error = Some(e.message.clone());
+        }
+        if let Some(history) = &config.history {
+            history
+                .append(&record)
+                .unwrap_or_else(|e| eprintln!("Error writing session history {}", e));
+        }
     }
 }
 
-/// Deserialize NetExp from Client and run NetExp
-fn handle_client(mut stream: TcpStream) -> error::Result<()> {
+/// Accept the next Client, reloading on SIGHUP while waiting, None once
+/// SIGTERM asks the Server to stop
+fn wait_for_client(
+    listener: &TcpListener,
+    config: &mut ServerConfig,
+) -> error::Result<Option<(TcpStream, SocketAddr)>> {
+    loop {
+        if daemon::stopping() {
+            return Ok(None);
+        }
+        if daemon::take_reload() {
+            reload(config);
+        }
+        let mut fd = libc::pollfd {
+            fd: listener.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        // SAFETY: fd is a valid pollfd for an open listener, signals
+        // interrupt the wait and are checked on the next pass
+        if unsafe { libc::poll(&mut fd, 1, SIGNAL_POLL_MS) } <= 0 {
+            continue;
+        }
+        let Ok(accepted) = listener.accept() else {
+            return Err(error::Error::new("Error accepting client connection"));
+        };
+        return Ok(Some(accepted));
+    }
+}
+
+/// Replace the PSK and TLS identity, keeping the old ones if they cannot be
+/// read
+fn reload(config: &mut ServerConfig) {
+    let Some(reload) = &config.reload else {
+        return;
+    };
+    daemon::notify("RELOADING=1");
+    match reload() {
+        Ok(reloaded) => {
+            config.psk = reloaded.psk;
+            if let Some(identity) = reloaded.tls_identity {
+                config.run_options.tls_identity = Some(identity);
+            }
+            println!("reloaded configuration");
+        }
+        Err(e) => eprintln!("Error reloading configuration, keeping the old one {}", e),
+    }
+    daemon::notify("READY=1");
+}
+
+/// Authenticate the Client, then deserialize NetExp from Client and run NetExp
+/// as many times as it asks for
+fn handle_client(
+    mut stream: TcpStream,
+    config: &ServerConfig,
+    metrics: &Metrics,
+    record: &mut history::Record,
+) -> error::Result<()> {
     let client_addr = stream.peer_addr()?;
     println!("Got a client! {}", client_addr);
-    let mut buf = [0; NetExp::serialized_size()];
-    stream.read_exact(&mut buf)?;
 
-    let mut experiment = NetExp::deserialize(&buf)?;
-    match &mut experiment {
-        NetExp::Tcp(params) => {
-            params.host = client_addr.ip();
-        }
-        NetExp::Udp(params) => {
-            params.host = client_addr.ip();
+    stream.set_read_timeout(Some(auth::HANDSHAKE_TIMEOUT))?;
+    auth::server_handshake(&mut stream, config.psk.as_ref())?;
+    stream.set_read_timeout(None)?;
+
+    let mut experiment = NetExp::read_from(&mut stream)?;
+    // a Unix socket test only makes sense with a Client on this host, and
+    // lets it pick a path on the Server's filesystem
+    if matches!(experiment, NetExp::Unix(..)) && !client_addr.ip().to_canonical().is_loopback() {
+        return Err(error::Error::new(
+            "Unix socket tests are only accepted from clients on this host",
+        ));
+    }
+    experiment.params_mut().host = client_addr.ip();
+    record.params = Some(SessionParams::from(&experiment));
+
+    for run in 0..experiment.params().repeat {
+        // the Client starts each run after the first with "GO"
+        if run > 0 {
+            let mut buf = [0; 2];
+            stream.read_exact(&mut buf)?;
+            if buf != "GO".as_bytes() {
+                return Err(error::Error::new("Received invalid request from client"));
+            }
         }
-    };
 
-    let (ready_tx, ready_rx) = mpsc::channel::<()>();
-    let exp_thread = thread::spawn(move || {
-        experiment.run(|| {
-            ready_tx.send(()).unwrap();
-        })
-    });
-    let Ok(_) = ready_rx.recv_timeout(std::time::Duration::new(5, 0)) else {
-        return Err(error::Error::new("Timed out initializing test"));
-    };
+        let (ready_tx, ready_rx) = mpsc::channel::<()>();
+        let run_options = config.run_options.clone();
+        let run_experiment = experiment.clone();
+        let exp_thread = thread::spawn(move || {
+            run_experiment.run(&run_options, || {
+                ready_tx.send(()).unwrap();
+            })
+        });
+        match ready_rx.recv_timeout(std::time::Duration::new(5, 0)) {
+            Ok(_) => {}
+            // the NetExp failed before it was ready
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                return match exp_thread.join() {
+                    Ok(Err(e)) => Err(e),
+                    _ => Err(error::Error::new("Failed joining thread")),
+                };
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                return Err(error::Error::new("Timed out initializing test"));
+            }
+        }
 
-    let response = "OK".as_bytes();
-    stream.write_all(response)?;
-    println!("Sent response!");
+        let response = "OK".as_bytes();
+        stream.write_all(response)?;
+        println!("Sent response!");
 
-    match exp_thread.join() {
-        Err(_) => Err(error::Error::new("Failed joining thread")),
-        Ok(_) => Ok(()),
+        let stats = match exp_thread.join() {
+            Err(_) => return Err(error::Error::new("Failed joining thread")),
+            Ok(stats) => stats?,
+        };
+        println!("{}", stats.format(&config.run_options.format));
+        metrics.record_run(client_addr.ip(), &experiment.params().side, &stats);
+        stats.write_to(&mut stream)?;
+        record.results.push(stats);
     }
+    Ok(())
 }
@@ -0,0 +1,296 @@
+use std::str::FromStr;
+use std::thread;
+
+use crate::client::{self, ClientConfig, RunResult};
+use crate::netexp::{NetExp, NetExpParams, Summary};
+use crate::units::{self, Format};
+
+/// Most points a sweep may have
+const MAX_POINTS: usize = 1_000;
+
+/// Runs a NetExp once for each value of one parameter
+#[derive(Clone, Debug, PartialEq)]
+pub struct Sweep {
+    pub param: Param,
+    pub values: Vec<u64>,
+}
+
+/// A parameter a Sweep can vary
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Param {
+    /// Size of each write or datagram
+    Length,
+    /// Number of parallel streams
+    Parallel,
+    /// Target bit rate of each stream
+    Bitrate,
+    /// Socket send and receive buffer size
+    Window,
+}
+
+/// What the NetExp measured at one value, or why it failed
+pub struct Point {
+    pub value: u64,
+    pub runs: Vec<RunResult>,
+    pub error: Option<String>,
+}
+
+impl Param {
+    fn name(&self) -> &'static str {
+        match self {
+            Param::Length => "length",
+            Param::Parallel => "parallel",
+            Param::Bitrate => "bitrate",
+            Param::Window => "window",
+        }
+    }
+
+    fn parse_value(&self, s: &str) -> Result<u64, String> {
+        match self {
+            Param::Length | Param::Window => units::parse_size(s).map(u64::from),
+            Param::Parallel => match s.parse::<u16>() {
+                Ok(0) | Err(_) => Err(format!("invalid number of streams '{}'", s)),
+                Ok(n) => Ok(n.into()),
+            },
+            Param::Bitrate => units::parse_bitrate(s),
+        }
+    }
+
+    /// The value as it is printed in the table
+    fn format_value(&self, value: u64) -> String {
+        match self {
+            Param::Bitrate => Format::default().rate(value as f64 / 8f64),
+            _ => value.to_string(),
+        }
+    }
+}
+
+impl FromStr for Sweep {
+    type Err = String;
+
+    /// Parse PARAM=VALUES, where VALUES is a comma separated list, or a
+    /// range START..END that doubles each step, multiplies by N with
+    /// START..END*N or adds N with START..END+N. END is always included.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((param, values)) = s.split_once('=') else {
+            return Err(format!("invalid sweep '{}', expected PARAM=VALUES", s));
+        };
+        let param = match param {
+            "length" => Param::Length,
+            "parallel" => Param::Parallel,
+            "bitrate" => Param::Bitrate,
+            "window" => Param::Window,
+            _ => {
+                return Err(format!(
+                    "invalid sweep parameter '{}', expected length, parallel, bitrate or window",
+                    param
+                ));
+            }
+        };
+        let values = match values.split_once("..") {
+            None => values
+                .split(',')
+                .map(|v| param.parse_value(v))
+                .collect::<Result<Vec<_>, _>>()?,
+            Some((start, rest)) => {
+                let start = param.parse_value(start)?;
+                let (end, step) = match rest.rfind(['*', '+']) {
+                    Some(i) => (&rest[..i], Some((&rest[i..i + 1], &rest[i + 1..]))),
+                    None => (rest, None),
+                };
+                let end = param.parse_value(end)?;
+                let next: Box<dyn Fn(u64) -> u64> = match step {
+                    None => Box::new(|v| v.saturating_mul(2)),
+                    Some(("*", factor)) => match factor.parse::<u64>() {
+                        Ok(factor) if factor > 1 => Box::new(move |v| v.saturating_mul(factor)),
+                        _ => {
+                            return Err(format!("invalid factor '{}', expected 2 or more", factor));
+                        }
+                    },
+                    Some((_, step)) => {
+                        let step = param.parse_value(step)?;
+                        if step == 0 {
+                            return Err("sweep step must be more than 0".to_string());
+                        }
+                        Box::new(move |v| v.saturating_add(step))
+                    }
+                };
+                range(start, end, next)?
+            }
+        };
+        if values.len() > MAX_POINTS {
+            return Err(format!("sweep has more than {} points", MAX_POINTS));
+        }
+        Ok(Sweep { param, values })
+    }
+}
+
+fn range(start: u64, end: u64, next: impl Fn(u64) -> u64) -> Result<Vec<u64>, String> {
+    if start == 0 || start > end {
+        return Err(format!("invalid sweep range {}..{}", start, end));
+    }
+    let mut values = vec![start];
+    let mut value = start;
+    while value < end && values.len() <= MAX_POINTS {
+        value = next(value).min(end);
+        values.push(value);
+    }
+    Ok(values)
+}
+
+impl Sweep {
+    /// `params` with the swept parameter set to `value`
+    pub fn apply(&self, params: &NetExpParams, value: u64) -> NetExpParams {
+        let mut params = params.clone();
+        match self.param {
+            Param::Length => params.length = value as u32,
+            Param::Parallel => params.parallel = value as u16,
+            Param::Bitrate => params.bitrate = value,
+            Param::Window => params.marking.buffer = value as u32,
+        }
+        params
+    }
+
+    /// Run `net_exp` at every value, a failed point is reported and does
+    /// not stop the sweep
+    pub fn run(&self, net_exp: &NetExp, config: &ClientConfig) -> Vec<Point> {
+        let mut points = Vec::new();
+        for (i, value) in self.values.iter().enumerate() {
+            if i > 0 {
+                thread::sleep(config.pause);
+            }
+            println!(
+                "Sweep {} = {} ({} of {})",
+                self.param.name(),
+                self.param.format_value(*value),
+                i + 1,
+                self.values.len()
+            );
+            let net_exp = net_exp.with_params(self.apply(net_exp.params(), *value));
+            let result = client::run(net_exp, config);
+            if let Err(e) = &result {
+                eprintln!("Sweep point failed: {}", e.message);
+            }
+            points.push(Point {
+                value: *value,
+                error: result.as_ref().err().map(|e| e.message.clone()),
+                runs: result.unwrap_or_default(),
+            });
+        }
+        points
+    }
+
+    /// One line per point with the mean sender and receiver bandwidth,
+    /// loss or retransmits, and jitter or round trip time
+    pub fn format_points(&self, points: &[Point], format: &Format) -> String {
+        let values: Vec<String> = points
+            .iter()
+            .map(|p| self.param.format_value(p.value))
+            .collect();
+        let width = values
+            .iter()
+            .map(String::len)
+            .chain([self.param.name().len()])
+            .max()
+            .unwrap_or(0);
+        let mut lines = vec![format!(
+            "{:>width$}  {:>15}  {:>15}  {:>15}  {}",
+            self.param.name(),
+            "Sender",
+            "Receiver",
+            "Loss",
+            "Latency"
+        )];
+        for (point, value) in points.iter().zip(values) {
+            let mut line = format!("{:>width$}  ", value);
+            if let Some(error) = &point.error {
+                line += &format!("failed: {}", error);
+                lines.push(line);
+                continue;
+            }
+            let mean = |f: fn(&RunResult) -> Option<f64>| {
+                let samples: Vec<f64> = point.runs.iter().filter_map(f).collect();
+                Summary::of(&samples).map(|summary| summary.mean)
+            };
+            let rate = |rate: Option<f64>| {
+                rate.map(|rate| format.rate(rate))
+                    .unwrap_or("n/a".to_string())
+            };
+            line += &format!(
+                "{:>15}  {:>15}  {:>15}  {}",
+                rate(mean(|r| r.sender.rate())),
+                rate(mean(|r| r.receiver.rate())),
+                client::format_loss(&point.runs).unwrap_or_default(),
+                client::format_latency(&point.runs).unwrap_or_default()
+            );
+            lines.push(line.trim_end().to_string());
+        }
+        lines.join("\n")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::netexp::Stats;
+
+    #[test]
+    fn test_parse_sweep() {
+        let sweep = |s: &str| s.parse::<Sweep>().map(|sweep| sweep.values);
+        assert_eq!(sweep("parallel=1..16"), Ok(vec![1, 2, 4, 8, 16]));
+        assert_eq!(sweep("parallel=1..10"), Ok(vec![1, 2, 4, 8, 10]));
+        assert_eq!(sweep("length=1K..16K*4"), Ok(vec![1024, 4096, 16384]));
+        assert_eq!(
+            sweep("bitrate=100M..300M+100M"),
+            Ok(vec![100_000_000, 200_000_000, 300_000_000])
+        );
+        assert_eq!(sweep("window=64K,1M"), Ok(vec![65_536, 1_048_576]));
+        assert_eq!(
+            "window=8K".parse::<Sweep>().map(|sweep| sweep.param),
+            Ok(Param::Window)
+        );
+        assert!(sweep("parallel=0..4").is_err());
+        assert!(sweep("parallel=8..4").is_err());
+        assert!(sweep("length=1..4*1").is_err());
+        assert!(sweep("bitrate=1..1G+1").is_err());
+        assert!(sweep("mtu=1..2").is_err());
+        assert!(sweep("parallel").is_err());
+    }
+
+    #[test]
+    fn test_format_points() {
+        let sweep: Sweep = "bitrate=100M,1G".parse().unwrap();
+        let stats = |bytes, packet_loss, jitter| Stats {
+            transfer: Some((bytes, Duration::from_secs(1))),
+            packet_loss,
+            jitter,
+            ..Default::default()
+        };
+        let points = [
+            Point {
+                value: 100_000_000,
+                runs: vec![RunResult {
+                    sender: stats(12_500_000, None, None),
+                    receiver: stats(12_500_000, Some(0f64), Some(Duration::from_micros(20))),
+                }],
+                error: None,
+            },
+            Point {
+                value: 1_000_000_000,
+                runs: Vec::new(),
+                error: Some("Timed out".to_string()),
+            },
+        ];
+        assert_eq!(
+            sweep.format_points(&points, &Format::default()),
+            [
+                "      bitrate           Sender         Receiver             Loss  Latency",
+                "100.00 Mbit/s    100.00 Mbit/s    100.00 Mbit/s           0.000%  0.020 ms jitter",
+                "  1.00 Gbit/s  failed: Timed out",
+            ]
+            .join("\n")
+        );
+    }
+}
@@ -0,0 +1,236 @@
+use std::fmt::Display;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Whether rates are shown in bits or bytes
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Unit {
+    #[default]
+    Bits,
+    Bytes,
+}
+
+/// Decimal (k = 1000) or binary (Ki = 1024) prefixes
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Scale {
+    #[default]
+    Si,
+    Iec,
+}
+
+impl Scale {
+    fn base(&self) -> f64 {
+        match self {
+            Scale::Si => 1_000f64,
+            Scale::Iec => 1_024f64,
+        }
+    }
+
+    fn prefixes(&self) -> [&'static str; 5] {
+        match self {
+            Scale::Si => ["", "k", "M", "G", "T"],
+            Scale::Iec => ["", "Ki", "Mi", "Gi", "Ti"],
+        }
+    }
+
+    /// Divide `value` down to the largest prefix that keeps it at least 1
+    fn auto_scale(&self, mut value: f64) -> (f64, &'static str) {
+        let prefixes = self.prefixes();
+        let mut i = 0;
+        while value.abs() >= self.base() && i < prefixes.len() - 1 {
+            value /= self.base();
+            i += 1;
+        }
+        (value, prefixes[i])
+    }
+}
+
+/// How to print transfer sizes and rates, selected with `-f`
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Format {
+    pub unit: Unit,
+    pub scale: Scale,
+}
+
+impl Format {
+    /// Format an amount of data such as "1.25 GB", always in bytes
+    pub fn bytes(&self, bytes: u64) -> String {
+        let (value, prefix) = self.scale.auto_scale(bytes as f64);
+        format!("{:.2} {}B", value, prefix)
+    }
+
+    /// Format a rate given in bytes per second such as "9.41 Gbit/s"
+    pub fn rate(&self, bytes_per_sec: f64) -> String {
+        let (value, unit) = match self.unit {
+            Unit::Bits => (bytes_per_sec * 8f64, "bit/s"),
+            Unit::Bytes => (bytes_per_sec, "B/s"),
+        };
+        let (value, prefix) = self.scale.auto_scale(value);
+        format!("{:.2} {}{}", value, prefix, unit)
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (unit, scale) = match s {
+            "bits" => (Unit::Bits, Scale::Si),
+            "bytes" => (Unit::Bytes, Scale::Si),
+            "bits-iec" => (Unit::Bits, Scale::Iec),
+            "bytes-iec" => (Unit::Bytes, Scale::Iec),
+            _ => {
+                return Err(format!(
+                    "invalid format '{}', expected one of bits, bytes, bits-iec, bytes-iec",
+                    s
+                ));
+            }
+        };
+        Ok(Format { unit, scale })
+    }
+}
+
+impl Display for Format {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match (self.unit, self.scale) {
+            (Unit::Bits, Scale::Si) => "bits",
+            (Unit::Bytes, Scale::Si) => "bytes",
+            (Unit::Bits, Scale::Iec) => "bits-iec",
+            (Unit::Bytes, Scale::Iec) => "bytes-iec",
+        };
+        f.write_str(s)
+    }
+}
+
+/// Bytes per second moved in `duration`, None if no time has passed
+pub fn rate(bytes: u64, duration: Duration) -> Option<f64> {
+    if duration.is_zero() {
+        None
+    } else {
+        Some(bytes as f64 / duration.as_secs_f64())
+    }
+}
+
+/// Parse a bit rate such as "500k", "100M" or "1.5G" into bits per second,
+/// suffixes are always decimal
+pub fn parse_bitrate(s: &str) -> Result<u64, String> {
+    let invalid = || format!("invalid bitrate '{}', expected a number such as 100M", s);
+    let (number, multiplier) = match s.char_indices().last() {
+        Some((i, 'k' | 'K')) => (&s[..i], 1e3),
+        Some((i, 'm' | 'M')) => (&s[..i], 1e6),
+        Some((i, 'g' | 'G')) => (&s[..i], 1e9),
+        Some((i, 't' | 'T')) => (&s[..i], 1e12),
+        _ => (s, 1f64),
+    };
+    let number: f64 = number.parse().map_err(|_| invalid())?;
+    if !number.is_finite() || number < 0f64 {
+        return Err(invalid());
+    }
+    Ok((number * multiplier).round() as u64)
+}
+
+/// Parse a size in bytes such as "128K" or "4M", suffixes are always
+/// binary
+pub fn parse_size(s: &str) -> Result<u32, String> {
+    let invalid = || format!("invalid size '{}', expected a number such as 128K", s);
+    let (number, multiplier) = match s.char_indices().last() {
+        Some((i, 'k' | 'K')) => (&s[..i], 1 << 10),
+        Some((i, 'm' | 'M')) => (&s[..i], 1 << 20),
+        Some((i, 'g' | 'G')) => (&s[..i], 1 << 30),
+        _ => (s, 1),
+    };
+    let number: u32 = number.parse().map_err(|_| invalid())?;
+    number.checked_mul(multiplier).ok_or_else(invalid)
+}
+
+/// Parse a decimal number, or a hexadecimal one prefixed with "0x"
+pub fn parse_u32(s: &str) -> Result<u32, String> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+    .map_err(|_| format!("invalid number '{}'", s))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_rate_auto_scaling_si_bits() {
+        let format = Format::default();
+        assert_eq!(format.rate(0f64), "0.00 bit/s");
+        assert_eq!(format.rate(100f64), "800.00 bit/s");
+        assert_eq!(format.rate(125f64), "1.00 kbit/s");
+        assert_eq!(format.rate(1_250_000f64), "10.00 Mbit/s");
+        assert_eq!(format.rate(1_175_000_000f64), "9.40 Gbit/s");
+        assert_eq!(format.rate(50_000_000_000f64), "400.00 Gbit/s");
+        assert_eq!(format.rate(125_000_000_000f64), "1.00 Tbit/s");
+        // nothing above tera
+        assert_eq!(format.rate(125_000_000_000_000f64), "1000.00 Tbit/s");
+    }
+
+    #[test]
+    fn test_rate_iec_bytes() {
+        let format: Format = "bytes-iec".parse().unwrap();
+        assert_eq!(format.rate(1_023f64), "1023.00 B/s");
+        assert_eq!(format.rate(1_024f64), "1.00 KiB/s");
+        assert_eq!(format.rate(1_536f64 * 1_024f64), "1.50 MiB/s");
+    }
+
+    #[test]
+    fn test_bytes() {
+        let si = Format::default();
+        let iec: Format = "bits-iec".parse().unwrap();
+        assert_eq!(si.bytes(100_000_000), "100.00 MB");
+        assert_eq!(iec.bytes(100_000_000), "95.37 MiB");
+    }
+
+    #[test]
+    fn test_parse_format() {
+        for s in ["bits", "bytes", "bits-iec", "bytes-iec"] {
+            assert_eq!(s.parse::<Format>().unwrap().to_string(), s);
+        }
+        assert!("kbps".parse::<Format>().is_err());
+    }
+
+    #[test]
+    fn test_rate_uses_full_precision() {
+        assert_eq!(rate(1_000, Duration::ZERO), None);
+        assert_eq!(rate(1_000, Duration::from_nanos(500)), Some(2e9));
+        assert_eq!(rate(3, Duration::from_millis(1_500)), Some(2f64));
+    }
+
+    #[test]
+    fn test_parse_bitrate() {
+        assert_eq!(parse_bitrate("0"), Ok(0));
+        assert_eq!(parse_bitrate("64000"), Ok(64_000));
+        assert_eq!(parse_bitrate("500k"), Ok(500_000));
+        assert_eq!(parse_bitrate("100M"), Ok(100_000_000));
+        assert_eq!(parse_bitrate("1.5G"), Ok(1_500_000_000));
+        assert!(parse_bitrate("").is_err());
+        assert!(parse_bitrate("M").is_err());
+        assert!(parse_bitrate("-1M").is_err());
+        assert!(parse_bitrate("fast").is_err());
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("1500"), Ok(1_500));
+        assert_eq!(parse_size("128K"), Ok(131_072));
+        assert_eq!(parse_size("4m"), Ok(4 << 20));
+        assert_eq!(parse_size("1G"), Ok(1 << 30));
+        assert!(parse_size("4G").is_err());
+        assert!(parse_size("1.5M").is_err());
+        assert!(parse_size("K").is_err());
+    }
+
+    #[test]
+    fn test_parse_u32() {
+        assert_eq!(parse_u32("184"), Ok(184));
+        assert_eq!(parse_u32("0xb8"), Ok(184));
+        assert_eq!(parse_u32("0XB8"), Ok(184));
+        assert!(parse_u32("0x").is_err());
+        assert!(parse_u32("-1").is_err());
+    }
+}